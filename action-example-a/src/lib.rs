@@ -1,12 +1,23 @@
 wit_bindgen::generate!({ generate_all });
-use exports::bettyblocks::runtime_cloud::action::Guest;
+use exports::bettyblocks::runtime_cloud::action::{Guest, StepResult};
 
 struct Action;
 
 impl Guest for Action {
-    fn execute() -> String {
-        let str = format!("action a");
-        str
+    fn execute(input: String, _token: Option<String>) -> Result<String, String> {
+        let input: serde_json::Value =
+            serde_json::from_str(&input).map_err(|err| format!("Invalid input: {}", err))?;
+        let name = input["name"].as_str().unwrap_or("world");
+        Ok(serde_json::Value::from(format!("action a greets {}", name)).to_string())
+    }
+
+    fn step(
+        input: String,
+        _state: Option<String>,
+        token: Option<String>,
+    ) -> Result<StepResult, String> {
+        // greeting is quick, it is done in a single step
+        Self::execute(input, token).map(StepResult::Done)
     }
 }
 
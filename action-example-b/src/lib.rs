@@ -1,14 +1,75 @@
 wit_bindgen::generate!({ generate_all });
-use crate::bettyblocks::runtime_cloud::system_info::Kind;
-use exports::bettyblocks::runtime_cloud::action::Guest;
+use crate::bettyblocks::runtime_cloud::data_api::{self, QueryError, QueryRequest};
+use crate::bettyblocks::runtime_cloud::provider_status;
+use exports::bettyblocks::runtime_cloud::action::{Guest, Progress, StepResult};
 
 struct Action;
 
+/// Run `query` against the Data API as the caller, returning the JSON encoded response
+fn query_data_api(query: &str, token: Option<String>) -> Result<String, String> {
+    data_api::query(&QueryRequest {
+        query: query.to_string(),
+        variables: None,
+        operation_name: None,
+        token,
+    })
+    .map_err(|err| match err {
+        QueryError::InvalidRequest(message) => format!("Invalid query: {}", message),
+        QueryError::Unauthorized(message) => format!("Not authorized: {}", message),
+        QueryError::Upstream(message) | QueryError::InvalidResponse(message) => {
+            format!("Data API failed: {}", message)
+        }
+        QueryError::CircuitOpen(retry_after_ms) => {
+            format!("Data API is down, retry in {}ms", retry_after_ms)
+        }
+    })
+}
+
 impl Guest for Action {
-    fn execute() -> String {
-        let s = crate::bettyblocks::runtime_cloud::system_info::request_info(Kind::Os);
-        let str = format!("action b {}", s);
-        str
+    /// Report whether the upstreams of the provider are reachable, and run the `query` of the
+    /// input against the Data API on behalf of the caller when there is one
+    fn execute(input: String, token: Option<String>) -> Result<String, String> {
+        let input: serde_json::Value =
+            serde_json::from_str(&input).map_err(|err| format!("Invalid input: {}", err))?;
+        let status = provider_status::status();
+        let reachable = status.upstreams.iter().all(|upstream| upstream.reachable);
+        let mut result = serde_json::json!({ "version": status.version, "reachable": reachable });
+        if let Some(query) = input["query"].as_str() {
+            result["response"] = query_data_api(query, token)?.into();
+        }
+        Ok(result.to_string())
+    }
+
+    /// Read the status of the provider, then report whether its upstreams are reachable
+    fn step(
+        _input: String,
+        state: Option<String>,
+        _token: Option<String>,
+    ) -> Result<StepResult, String> {
+        let Some(state) = state else {
+            let status = provider_status::status();
+            let reachable: Vec<bool> = status
+                .upstreams
+                .iter()
+                .map(|upstream| upstream.reachable)
+                .collect();
+            return Ok(StepResult::Progress(Progress {
+                message: format!("Provider {} is up", status.version),
+                percent: Some(50),
+                state: serde_json::json!({ "version": status.version, "reachable": reachable })
+                    .to_string(),
+            }));
+        };
+        let state: serde_json::Value =
+            serde_json::from_str(&state).map_err(|err| format!("Invalid state: {}", err))?;
+        let reachable = state["reachable"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .all(|reachable| reachable.as_bool() == Some(true));
+        Ok(StepResult::Done(
+            serde_json::json!({ "version": state["version"], "reachable": reachable }).to_string(),
+        ))
     }
 }
 
@@ -0,0 +1,257 @@
+//! Actions executed in the background, for actions that take longer than an HTTP request may.
+//!
+//! `enqueue` stores a queued job and publishes it on the jobs subject. The handler of that subject
+//! executes the action step by step and stores the job after every step, so `job` reads its
+//! progress while it runs and its result once it is done.
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::exports::bettyblocks::runtime_cloud::action_runner::{
+    ActionJob, ActionRequest, ActionStep, JobStatus,
+};
+use crate::wasi::keyvalue::store;
+
+/// Prefix of the keys jobs are stored under
+const KEY_PREFIX: &str = "job.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// A job as stored
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub app_uuid: String,
+    pub action_uuid: String,
+    pub status: Status,
+    pub message: Option<String>,
+    pub percent: Option<u8>,
+    pub result: Option<String>,
+    pub error: Option<String>,
+}
+
+impl Job {
+    pub fn queued(id: String, request: &ActionRequest) -> Job {
+        Job {
+            id,
+            app_uuid: request.app_uuid.clone(),
+            action_uuid: request.action_uuid.clone(),
+            status: Status::Queued,
+            message: None,
+            percent: None,
+            result: None,
+            error: None,
+        }
+    }
+
+    pub fn fail(&mut self, error: String) {
+        self.status = Status::Failed;
+        self.error = Some(error);
+    }
+}
+
+impl From<Job> for ActionJob {
+    fn from(job: Job) -> ActionJob {
+        ActionJob {
+            id: job.id,
+            app_uuid: job.app_uuid,
+            action_uuid: job.action_uuid,
+            status: match job.status {
+                Status::Queued => JobStatus::Queued,
+                Status::Running => JobStatus::Running,
+                Status::Succeeded => JobStatus::Succeeded,
+                Status::Failed => JobStatus::Failed,
+            },
+            message: job.message,
+            percent: job.percent,
+            result: job.result,
+            error: job.error,
+        }
+    }
+}
+
+/// A job as published on the jobs subject, with the input of its action
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Message {
+    pub id: String,
+    pub component: Option<String>,
+    pub input: String,
+    /// JWT of the caller, the action runs on their behalf
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl Message {
+    /// The action to execute for the job
+    pub fn request(self, job: &Job) -> ActionRequest {
+        ActionRequest {
+            app_uuid: job.app_uuid.clone(),
+            action_uuid: job.action_uuid.clone(),
+            component: self.component,
+            input: self.input,
+            token: self.token,
+        }
+    }
+}
+
+/// Where jobs are stored
+pub trait JobStore {
+    fn get(&self, id: &str) -> Result<Option<Job>, String>;
+    fn set(&self, job: &Job) -> Result<(), String>;
+}
+
+/// Jobs stored in a keyvalue bucket, shared by every instance of this component
+pub struct KeyValueStore {
+    bucket: store::Bucket,
+}
+
+impl KeyValueStore {
+    pub fn open(name: &str) -> Result<KeyValueStore, String> {
+        let bucket = store::open(name)
+            .map_err(|err| format!("Failed to open bucket {}: {:?}", name, err))?;
+        Ok(KeyValueStore { bucket })
+    }
+}
+
+impl JobStore for KeyValueStore {
+    fn get(&self, id: &str) -> Result<Option<Job>, String> {
+        let value = self
+            .bucket
+            .get(&format!("{}{}", KEY_PREFIX, id))
+            .map_err(|err| format!("Failed to read job {}: {:?}", id, err))?;
+        value
+            .map(|value| {
+                serde_json::from_slice(&value)
+                    .map_err(|err| format!("Job {} is corrupt: {}", id, err))
+            })
+            .transpose()
+    }
+
+    fn set(&self, job: &Job) -> Result<(), String> {
+        let value = serde_json::to_vec(job).map_err(|err| err.to_string())?;
+        self.bucket
+            .set(&format!("{}{}", KEY_PREFIX, job.id), &value)
+            .map_err(|err| format!("Failed to store job {}: {:?}", job.id, err))
+    }
+}
+
+/// Execute the action of a job step by step with `step`, storing the job after every step. A
+/// failed action fails the job, only failing to store it is an error.
+pub fn run(
+    job: &mut Job,
+    mut step: impl FnMut(Option<&str>) -> Result<ActionStep, String>,
+    store: &dyn JobStore,
+) -> Result<(), String> {
+    job.status = Status::Running;
+    store.set(job)?;
+    let mut state = None;
+    loop {
+        match step(state.as_deref()) {
+            Ok(ActionStep::Progress(progress)) => {
+                job.message = Some(progress.message);
+                job.percent = progress.percent;
+                state = Some(progress.state);
+                store.set(job)?;
+            }
+            Ok(ActionStep::Done(result)) => {
+                job.status = Status::Succeeded;
+                job.result = Some(result);
+                return store.set(job);
+            }
+            Err(err) => {
+                job.fail(err);
+                return store.set(job);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exports::bettyblocks::runtime_cloud::action_runner::ActionProgress;
+    use std::cell::RefCell;
+
+    /// Every version of the job as it was stored
+    #[derive(Default)]
+    struct MemoryStore(RefCell<Vec<Job>>);
+
+    impl JobStore for MemoryStore {
+        fn get(&self, id: &str) -> Result<Option<Job>, String> {
+            Ok(self
+                .0
+                .borrow()
+                .iter()
+                .rev()
+                .find(|job| job.id == id)
+                .cloned())
+        }
+
+        fn set(&self, job: &Job) -> Result<(), String> {
+            self.0.borrow_mut().push(job.clone());
+            Ok(())
+        }
+    }
+
+    fn job() -> Job {
+        Job::queued(
+            "0f4c6e3a9d1b4f7e8a2c5b6d7e8f9a0b".to_string(),
+            &ActionRequest {
+                app_uuid: "693b22e983fb46afa4eb353d82ece4bb".to_string(),
+                action_uuid: "b1d7e3a0c6f94b0e9d5f2a4c8e7b6d51".to_string(),
+                component: Some("action-example-b".to_string()),
+                input: "{}".to_string(),
+                token: None,
+            },
+        )
+    }
+
+    #[test]
+    fn test_run_stores_progress_and_result() {
+        let store = MemoryStore::default();
+        let mut job = job();
+        let step = |state: Option<&str>| match state {
+            None => Ok(ActionStep::Progress(ActionProgress {
+                message: "Checking upstreams".to_string(),
+                percent: Some(50),
+                state: "checked".to_string(),
+            })),
+            Some(_) => Ok(ActionStep::Done(r#"{"reachable":true}"#.to_string())),
+        };
+        run(&mut job, step, &store).unwrap();
+
+        let stored = store.0.borrow();
+        let statuses: Vec<Status> = stored.iter().map(|job| job.status).collect();
+        assert_eq!(
+            statuses,
+            vec![Status::Running, Status::Running, Status::Succeeded]
+        );
+        assert_eq!(stored[1].message.as_deref(), Some("Checking upstreams"));
+        assert_eq!(stored[1].percent, Some(50));
+        assert_eq!(stored[2].result.as_deref(), Some(r#"{"reachable":true}"#));
+    }
+
+    #[test]
+    fn test_failed_step_fails_job() {
+        let store = MemoryStore::default();
+        let mut job = job();
+        run(&mut job, |_| Err("unreachable".to_string()), &store).unwrap();
+        let stored = store.get(&job.id).unwrap().unwrap();
+        assert_eq!(stored.status, Status::Failed);
+        assert_eq!(stored.error.as_deref(), Some("unreachable"));
+    }
+
+    #[test]
+    fn test_job_is_stored_as_json() {
+        let job = job();
+        let json = serde_json::to_value(&job).unwrap();
+        assert_eq!(json["status"], "queued");
+        assert_eq!(serde_json::from_value::<Job>(json).unwrap(), job);
+    }
+}
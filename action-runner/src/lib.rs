@@ -1,25 +1,162 @@
 wit_bindgen::generate!({ generate_all });
-use exports::bettyblocks::runtime_cloud::action_runner::Guest;
+use bettyblocks::runtime_cloud::action::{self, StepResult};
+use exports::bettyblocks::runtime_cloud::action_runner::{
+    ActionJob, ActionProgress, ActionRequest, ActionStep, Guest,
+};
+use exports::bettyblocks::runtime_cloud::record_events::{
+    Guest as RecordEventsGuest, RecordChanged,
+};
+use exports::wasmcloud::messaging::handler::Guest as HandlerGuest;
+use jobs::JobStore;
+use wasi::config::runtime as config;
+use wasi::logging::logging::{log, Level};
+use wasmcloud::messaging::consumer;
+use wasmcloud::messaging::types::BrokerMessage;
+mod jobs;
+mod triggers;
 
 struct ActionRunner;
 
+/// Bucket jobs are stored in, when the `jobs_bucket` config isn't set
+const DEFAULT_JOBS_BUCKET: &str = "default";
+/// Subject jobs are published on, when the `jobs_subject` config isn't set
+const DEFAULT_JOBS_SUBJECT: &str = "bettyblocks.runtime-cloud.jobs";
+
+/// Route calls of the action interface to the component executing the action, every component
+/// executing actions is linked under its own name.
+fn link_component(request: &ActionRequest) -> Result<(), String> {
+    let component = request.component.as_ref().ok_or_else(|| {
+        format!(
+            "Action {} of application {} has no component to execute it",
+            request.action_uuid, request.app_uuid
+        )
+    })?;
+    let interface =
+        wasmcloud::bus::lattice::CallTargetInterface::new("bettyblocks", "runtime-cloud", "action");
+    wasmcloud::bus::lattice::set_link_name(component, vec![interface]);
+    Ok(())
+}
+
+/// A config value, `default` when it isn't set
+fn config_value(key: &str, default: &str) -> Result<String, String> {
+    config::get(key)
+        .map(|value| value.unwrap_or_else(|| default.to_string()))
+        .map_err(|err| format!("Failed to read config {}: {:?}", key, err))
+}
+
+fn job_store() -> Result<jobs::KeyValueStore, String> {
+    jobs::KeyValueStore::open(&config_value("jobs_bucket", DEFAULT_JOBS_BUCKET)?)
+}
+
+/// A random id as lowercase hex, like the uuids in the artefact
+fn new_id() -> String {
+    wasi::random::random::get_random_bytes(16)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
 impl Guest for ActionRunner {
-    fn execute() -> String {
-        let yourinterface = wasmcloud::bus::lattice::CallTargetInterface::new(
-            "bettyblocks",
-            "runtime-cloud",
-            "action",
-        );
-        // yourinterface.set_actor_id("bb_runtime_cloud-action_example_b".to_string());
-        // yourinterface.set_operation("execute".to_string());
+    fn execute(request: ActionRequest) -> Result<String, String> {
+        link_component(&request)?;
+        action::execute(&request.input, request.token.as_deref())
+    }
+
+    fn step(request: ActionRequest, state: Option<String>) -> Result<ActionStep, String> {
+        link_component(&request)?;
+        let step = action::step(&request.input, state.as_deref(), request.token.as_deref())?;
+        Ok(match step {
+            StepResult::Progress(progress) => ActionStep::Progress(ActionProgress {
+                message: progress.message,
+                percent: progress.percent,
+                state: progress.state,
+            }),
+            StepResult::Done(result) => ActionStep::Done(result),
+        })
+    }
+
+    fn enqueue(request: ActionRequest) -> Result<String, String> {
+        let store = job_store()?;
+        let mut job = jobs::Job::queued(new_id(), &request);
+        store.set(&job)?;
+        let message = jobs::Message {
+            id: job.id.clone(),
+            component: request.component,
+            input: request.input,
+            token: request.token,
+        };
+        let published = serde_json::to_vec(&message)
+            .map_err(|err| err.to_string())
+            .and_then(|body| {
+                consumer::publish(&BrokerMessage {
+                    subject: config_value("jobs_subject", DEFAULT_JOBS_SUBJECT)?,
+                    body,
+                    reply_to: None,
+                })
+            });
+        if let Err(err) = published {
+            // the job is never picked up, so it fails right away
+            let err = format!("Failed to queue job {}: {}", job.id, err);
+            job.fail(err.clone());
+            store.set(&job)?;
+            return Err(err);
+        }
+        Ok(job.id)
+    }
 
-        // wasmcloud::bus::lattice::set_link_name("action-example-b", vec![yourinterface]);
+    fn job(id: String) -> Result<Option<ActionJob>, String> {
+        Ok(job_store()?.get(&id)?.map(ActionJob::from))
+    }
+}
 
-        // Calls over link foo to perform a keyvalue operation
-        let x = bettyblocks::runtime_cloud::action::execute();
+impl HandlerGuest for ActionRunner {
+    /// Execute a job published by `enqueue`
+    fn handle_message(message: BrokerMessage) -> Result<(), String> {
+        let message: jobs::Message = serde_json::from_slice(&message.body)
+            .map_err(|err| format!("Invalid job message: {}", err))?;
+        let store = job_store()?;
+        let mut job = store
+            .get(&message.id)?
+            .ok_or_else(|| format!("Job {} not found", message.id))?;
+        // a message delivered again doesn't execute the action again
+        if job.status != jobs::Status::Queued {
+            return Ok(());
+        }
+        let request = message.request(&job);
+        jobs::run(
+            &mut job,
+            |state| <ActionRunner as Guest>::step(request.clone(), state.map(str::to_string)),
+            &store,
+        )
+    }
+}
 
-        let str = format!("henkie app:{}", x);
-        str
+impl RecordEventsGuest for ActionRunner {
+    /// Queue the actions triggered by the change, see [`triggers`]
+    fn on_record_changed(event: RecordChanged) -> Result<(), String> {
+        log(
+            Level::Debug,
+            "record-events",
+            &format!(
+                "Record {} of {} {:?} at {}",
+                event.record_id, event.model, event.kind, event.changed_at
+            ),
+        );
+        let triggers = match config::get(triggers::CONFIG)
+            .map_err(|err| format!("Failed to read config {}: {:?}", triggers::CONFIG, err))?
+        {
+            Some(json) => triggers::parse(&json)?,
+            None => return Ok(()),
+        };
+        let errors: Vec<String> = triggers::requests(&triggers, &event)
+            .into_iter()
+            .filter_map(|request| <ActionRunner as Guest>::enqueue(request).err())
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join(", "))
+        }
     }
 }
 
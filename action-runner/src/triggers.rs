@@ -0,0 +1,150 @@
+//! Actions triggered by changes in the Data API.
+//!
+//! The `record_triggers` config lists the actions to queue when a record of a model changes, as
+//! a JSON array of triggers:
+//!
+//! ```json
+//! [{ "model": "Order", "kinds": ["created"], "app_uuid": "…", "action_uuid": "…", "component": "…" }]
+//! ```
+//!
+//! A trigger without `kinds` fires on every change of its model. The change is the input of the
+//! action, it runs without a caller token.
+
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+
+use crate::exports::bettyblocks::runtime_cloud::action_runner::ActionRequest;
+use crate::exports::bettyblocks::runtime_cloud::record_events::{ChangeKind, RecordChanged};
+
+/// Config key with the triggers
+pub const CONFIG: &str = "record_triggers";
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct Trigger {
+    pub model: String,
+    /// `created`, `updated` or `deleted`, every kind when empty
+    #[serde(default)]
+    pub kinds: Vec<String>,
+    pub app_uuid: String,
+    pub action_uuid: String,
+    /// Component executing the action, as named in the artefact
+    pub component: Option<String>,
+}
+
+pub fn parse(json: &str) -> Result<Vec<Trigger>, String> {
+    serde_json::from_str(json).map_err(|err| format!("Invalid `{}` config: {}", CONFIG, err))
+}
+
+fn kind_name(kind: ChangeKind) -> &'static str {
+    match kind {
+        ChangeKind::Created => "created",
+        ChangeKind::Updated => "updated",
+        ChangeKind::Deleted => "deleted",
+    }
+}
+
+impl Trigger {
+    fn fires_on(&self, event: &RecordChanged) -> bool {
+        self.model == event.model
+            && (self.kinds.is_empty()
+                || self.kinds.iter().any(|kind| kind == kind_name(event.kind)))
+    }
+}
+
+/// The actions to queue for a change, with the change as their input
+pub fn requests(triggers: &[Trigger], event: &RecordChanged) -> Vec<ActionRequest> {
+    let data = event
+        .data
+        .as_deref()
+        .and_then(|data| serde_json::from_str(data).ok())
+        .unwrap_or(Value::Null);
+    let input = json!({
+        "model": event.model,
+        "record_id": event.record_id,
+        "kind": kind_name(event.kind),
+        "data": data,
+        "changed_at": event.changed_at,
+    })
+    .to_string();
+    triggers
+        .iter()
+        .filter(|trigger| trigger.fires_on(event))
+        .map(|trigger| ActionRequest {
+            app_uuid: trigger.app_uuid.clone(),
+            action_uuid: trigger.action_uuid.clone(),
+            component: trigger.component.clone(),
+            input: input.clone(),
+            token: None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn triggers() -> Vec<Trigger> {
+        parse(
+            r#"[
+                { "model": "Order", "kinds": ["created"], "app_uuid": "693b22e983fb46afa4eb353d82ece4bb", "action_uuid": "5c2e8f1a9b3d4c7e8a6f0d2b4e1c3a57", "component": "action-example-a" },
+                { "model": "Order", "app_uuid": "693b22e983fb46afa4eb353d82ece4bb", "action_uuid": "b1d7e3a0c6f94b0e9d5f2a4c8e7b6d51", "component": "action-example-b" }
+            ]"#,
+        )
+        .unwrap()
+    }
+
+    fn event(model: &str, kind: ChangeKind) -> RecordChanged {
+        RecordChanged {
+            model: model.to_string(),
+            record_id: "12".to_string(),
+            kind,
+            data: Some(r#"{"id": 12}"#.to_string()),
+            changed_at: "2025-01-10T12:00:00Z".to_string(),
+        }
+    }
+
+    fn components(requests: &[ActionRequest]) -> Vec<&str> {
+        requests
+            .iter()
+            .filter_map(|request| request.component.as_deref())
+            .collect()
+    }
+
+    #[test]
+    fn test_requests_for_model_and_kind() {
+        let triggers = triggers();
+        assert_eq!(
+            components(&requests(&triggers, &event("Order", ChangeKind::Created))),
+            vec!["action-example-a", "action-example-b"]
+        );
+        assert_eq!(
+            components(&requests(&triggers, &event("Order", ChangeKind::Deleted))),
+            vec!["action-example-b"]
+        );
+        assert!(requests(&triggers, &event("Invoice", ChangeKind::Created)).is_empty());
+    }
+
+    #[test]
+    fn test_change_is_input() {
+        let requests = requests(&triggers(), &event("Order", ChangeKind::Updated));
+        let input: Value = serde_json::from_str(&requests[0].input).unwrap();
+        assert_eq!(
+            input,
+            json!({
+                "model": "Order",
+                "record_id": "12",
+                "kind": "updated",
+                "data": { "id": 12 },
+                "changed_at": "2025-01-10T12:00:00Z",
+            })
+        );
+        assert_eq!(requests[0].action_uuid, "b1d7e3a0c6f94b0e9d5f2a4c8e7b6d51");
+        assert_eq!(requests[0].token, None);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(parse(r#"[{ "model": "Order" }]"#).is_err());
+        assert_eq!(parse("[]"), Ok(vec![]));
+    }
+}
@@ -1,6 +1,12 @@
 use core::str;
 
-use crate::wasi::keyvalue::store;
+use crate::exports::bettyblocks::runtime_cloud::meta_artefact::ArtefactError;
+use crate::history::{self, Recorder};
+use crate::index::Index;
+use crate::migrate;
+use crate::revision::{self, ClaimError};
+use crate::storage::{Storage, CLOUD_KEY};
+use crate::validation::{self, Violation};
 use serde_derive::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -8,6 +14,19 @@ struct Application {
     uuid: String,
     name: String,
     actions: Vec<Action>,
+    /// Persisted queries the GraphQL endpoint of the application executes by their hash
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    queries: Vec<PersistedQuery>,
+    /// Origins browsers may call the GraphQL endpoint of the application from
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    origins: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PersistedQuery {
+    /// Lowercase hex SHA-256 of `query`
+    sha256: String,
+    query: String,
 }
 
 trait GetAction {
@@ -16,12 +35,9 @@ trait GetAction {
 
 impl GetAction for Application {
     fn get_action(&self, uuid: String) -> Option<&Action> {
-        for action in &self.actions {
-            if action.uuid == uuid {
-                return Some(action);
-            }
-        }
-        None
+        self.actions
+            .iter()
+            .find(|action| is_uuid(&action.uuid, &uuid))
     }
 }
 
@@ -29,27 +45,40 @@ impl GetAction for Application {
 //but needs to stay here for to be used in the action_exists_in_app function
 impl Default for Application {
     fn default() -> Self {
-        let app = Application {
+        Application {
             uuid: String::from("default-uuid"),
             name: String::from("default-name"),
             actions: vec![],
-        };
-        app
+            queries: vec![],
+            origins: vec![],
+        }
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Action {
     uuid: String,
+    /// Name of the mutation executing the action in the GraphQL schema of the application
+    name: String,
     auth: String,
     scope: String,
     etag: String,
+    /// Name of the component executing the action, one of [`Cloud::components`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    component: Option<String>,
+    /// JSON Schema of the input, its properties are the arguments of the mutation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    input: Option<serde_json::Value>,
+    /// JSON Schema of the result, a string when left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output: Option<serde_json::Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Cloud {
     name: String,
     applications: Vec<Application>,
+    components: Vec<String>,
 }
 trait GetApplication {
     fn get_application(&self, uuid: String) -> Option<&Application>;
@@ -57,87 +86,468 @@ trait GetApplication {
 
 impl GetApplication for Cloud {
     fn get_application(&self, uuid: String) -> Option<&Application> {
-        for app in &self.applications {
-            if app.uuid == uuid {
-                return Some(app);
-            }
-        }
-        None
+        self.applications
+            .iter()
+            .find(|app| is_uuid(&app.uuid, &uuid))
     }
 }
 
+/// The versioned envelope the artefact is stored in, see [`migrate`]
 #[derive(Serialize, Deserialize, Debug)]
 struct Root {
+    version: u64,
+    /// Number of writes to the artefact, see [`revision`]
+    #[serde(default)]
+    revision: u64,
     cloud: Cloud,
 }
 
+/// Parse a stored artefact, upgrading it to the current version first.
+fn parse_root(artefact: &str) -> Result<Root, String> {
+    let value: serde_json::Value = serde_json::from_str(artefact)
+        .map_err(|err| format!("Stored artefact is not valid JSON: {}", err))?;
+    let upgraded = migrate::upgrade(value)?;
+    serde_json::from_value(upgraded).map_err(|err| format!("Stored artefact is invalid: {}", err))
+}
+
 fn action_exists_in_app(
     cloud: &Cloud,
     app_uuid: String,
     action_uid: String,
-) -> Result<bool, String> {
+) -> Result<bool, ArtefactError> {
     //FIX: findout how to define a default trait that unwrap_or_default can use
     let _default_app = Application::default();
     match cloud.get_application(app_uuid) {
         Some(app) => match app.get_action(action_uid) {
             Some(_) => Ok(true),
-            None => Err("Action not found".to_string()),
+            None => Err(ArtefactError::ActionNotFound),
         },
-        None => Err("Application not found".to_string()),
-    }
-}
-
-pub fn validate(app_uuid: String, action_uid: String) -> Result<bool, String> {
-    let bucket = store::open("default").unwrap();
-    println!("app_uuid: {}", app_uuid);
-    //FIX: make the KV key a constant or a config
-    match bucket.get("cloud") {
-        Ok(str) => {
-            let artefact = String::from_utf8(str.unwrap()).expect("Stored value is not valid utf8");
-            let Root { cloud } =
-                serde_json::from_str(&artefact).expect("Error parsing json from store");
-            match action_exists_in_app(&cloud, app_uuid, action_uid) {
-                Ok(_) => Ok(true),
-                Err(err) => Err(err),
+        None => Err(ArtefactError::ApplicationNotFound),
+    }
+}
+
+/// Parse the stored artefact as read from the store, `None` when nothing is stored yet.
+fn parse_stored(stored: Option<Vec<u8>>) -> Result<Root, ArtefactError> {
+    let stored = stored.ok_or(ArtefactError::NotSeeded)?;
+    let artefact = String::from_utf8(stored)
+        .map_err(|_| ArtefactError::Corrupt("Stored artefact is not valid utf8".to_string()))?;
+    parse_root(&artefact).map_err(ArtefactError::Corrupt)
+}
+
+/// Open the storage and read the stored cloud.
+fn load_cloud() -> Result<(Storage, Cloud), ArtefactError> {
+    let storage = Storage::open().map_err(ArtefactError::StorageUnavailable)?;
+    let stored = storage
+        .get(CLOUD_KEY)
+        .map_err(ArtefactError::StorageUnavailable)?;
+    let Root { cloud, .. } = parse_stored(stored)?;
+    Ok((storage, cloud))
+}
+
+/// The error for an application missing from the cloud, telling removed applications apart.
+fn application_missing(storage: &Storage, app_uuid: &str) -> ArtefactError {
+    match Index::load(storage) {
+        Ok(index) if index.is_removed(app_uuid) => ArtefactError::ApplicationRemoved,
+        Ok(_) => ArtefactError::ApplicationNotFound,
+        Err(err) => ArtefactError::StorageUnavailable(err),
+    }
+}
+
+pub fn validate(app_uuid: String, action_uid: String) -> Result<bool, ArtefactError> {
+    let (storage, cloud) = load_cloud()?;
+
+    match action_exists_in_app(&cloud, app_uuid.clone(), action_uid) {
+        Err(ArtefactError::ApplicationNotFound) => Err(application_missing(&storage, &app_uuid)),
+        result => result,
+    }
+}
+
+/// An application as stored with its etag, `None` when its etag is still `known_etag`.
+pub fn application(
+    app_uuid: &str,
+    known_etag: Option<&str>,
+) -> Result<Option<(String, serde_json::Value)>, ArtefactError> {
+    let (storage, cloud) = load_cloud()?;
+    let application = cloud
+        .applications
+        .iter()
+        .find(|app| is_uuid(&app.uuid, app_uuid))
+        .ok_or_else(|| application_missing(&storage, app_uuid))?;
+    // the etag of the application as stored, the same one the webhook and history use
+    let value =
+        serde_json::to_value(application).map_err(|err| ArtefactError::Corrupt(err.to_string()))?;
+    let etag = history::etag(&value);
+    if known_etag == Some(etag.as_str()) {
+        return Ok(None);
+    }
+    Ok(Some((etag, value)))
+}
+
+/// The origins of every application, without duplicates.
+pub fn origins() -> Result<Vec<String>, ArtefactError> {
+    let (_, cloud) = load_cloud()?;
+    Ok(all_origins(&cloud))
+}
+
+fn all_origins(cloud: &Cloud) -> Vec<String> {
+    let mut origins: Vec<String> = cloud
+        .applications
+        .iter()
+        .flat_map(|app| app.origins.iter().map(|origin| origin.to_lowercase()))
+        .collect();
+    origins.sort();
+    origins.dedup();
+    origins
+}
+
+pub enum UploadError {
+    /// The uploaded artefact breaks the artefact format, with every violation found
+    Invalid(Vec<Violation>),
+    /// The application or version to change doesn't exist
+    NotFound(String),
+    /// The application changed since the etag the change was based on
+    PreconditionFailed(String),
+    /// The artefact changed since the revision the change was based on
+    Conflict(String),
+    Storage(String),
+}
+
+fn open_storage() -> Result<Storage, UploadError> {
+    Storage::open().map_err(UploadError::Storage)
+}
+
+/// Validate an uploaded artefact and store it, returning the stored artefact.
+///
+/// Artefacts of an older version are upgraded before they are validated. When an `expected`
+/// revision is given, or the artefact has a revision, the artefact is only stored if that is
+/// still the current revision.
+pub fn write_artefact(
+    body: &[u8],
+    expected: Option<u64>,
+    recorder: &Recorder,
+) -> Result<String, UploadError> {
+    let invalid =
+        |path: &str, message: String| UploadError::Invalid(vec![Violation::new(path, message)]);
+
+    let value: serde_json::Value = serde_json::from_slice(body)
+        .map_err(|err| invalid("$", format!("not valid JSON: {}", err)))?;
+    // an artefact read from the webhook carries the revision it was read at
+    let (value, read_at) = revision::split(value);
+    let expected = expected.or(read_at);
+    let value = migrate::upgrade(value).map_err(|err| invalid("$.version", err))?;
+
+    let storage = open_storage()?;
+    let current = match read_value(&storage).map_err(UploadError::Storage)? {
+        Some((_, revision)) => revision,
+        // writes continue from the last indexed revision, its claim was taken when it was stored
+        None => {
+            Index::load(&storage)
+                .map_err(UploadError::Storage)?
+                .revision
+        }
+    };
+    if let Some(expected) = expected.filter(|expected| *expected != current) {
+        return Err(UploadError::Conflict(format!(
+            "Artefact is at revision {}, not {}",
+            current, expected
+        )));
+    }
+    store_artefact(&storage, value, current, recorder)
+}
+
+/// Validate an artefact of the current version and store it as the write after revision `base`,
+/// recording the history of its applications.
+fn store_artefact(
+    storage: &Storage,
+    value: serde_json::Value,
+    base: u64,
+    recorder: &Recorder,
+) -> Result<String, UploadError> {
+    let mut value = value;
+    if let Some(applications) = value
+        .pointer_mut("/cloud/applications")
+        .and_then(serde_json::Value::as_array_mut)
+    {
+        applications.iter_mut().for_each(migrate::lowercase_uuids);
+    }
+    let violations = validation::violations(&value);
+    if !violations.is_empty() {
+        return Err(UploadError::Invalid(violations));
+    }
+    let mut root: Root = serde_json::from_value(value)
+        .map_err(|err| UploadError::Invalid(vec![Violation::new("$", err.to_string())]))?;
+
+    root.revision = revision::claim(storage, base).map_err(|err| match err {
+        ClaimError::Conflict => UploadError::Conflict(format!(
+            "Artefact revision {} was changed by another write",
+            base
+        )),
+        ClaimError::Storage(err) => UploadError::Storage(err),
+    })?;
+    let json = serde_json::to_string(&root).map_err(|err| UploadError::Storage(err.to_string()))?;
+    if let Err(err) = storage.set(CLOUD_KEY, json.as_bytes()) {
+        revision::release(storage, base);
+        return Err(UploadError::Storage(err));
+    }
+
+    // record the applications as stored, so their etags match what is read back
+    let stored =
+        serde_json::to_value(&root).map_err(|err| UploadError::Storage(err.to_string()))?;
+    if let Some(applications) = stored["cloud"]["applications"].as_array() {
+        recorder
+            .record(storage, applications, root.revision)
+            .map_err(|err| {
+                UploadError::Storage(format!(
+                    "Artefact was stored, but recording its history failed: {}",
+                    err
+                ))
+            })?;
+    }
+    let uuids = root.cloud.applications.iter().map(|app| app.uuid.as_str());
+    Index::load(storage)
+        .and_then(|index| {
+            match index.update(uuids, root.revision, history::now_millis()) {
+                Some(index) => index.save(storage),
+                // a newer write updated the index already
+                None => Ok(()),
             }
+        })
+        .map_err(|err| {
+            UploadError::Storage(format!(
+                "Artefact was stored, but updating the application index failed: {}",
+                err
+            ))
+        })?;
+    Ok(json)
+}
+
+/// The stored versions of an application, newest first.
+pub fn versions(app_uuid: &str, keep: usize) -> Result<Vec<history::Version>, String> {
+    let storage = Storage::open()?;
+    history::load(&storage, app_uuid, keep)
+}
+
+/// Roll an application back to one of its stored versions, returning the stored artefact.
+///
+/// Only the application is replaced, the rest of the cloud is left as is. The cloud is validated
+/// again, the version may reference components that are no longer listed.
+pub fn rollback(app_uuid: &str, etag: &str, recorder: &Recorder) -> Result<String, UploadError> {
+    let storage = open_storage()?;
+    let version = history::load(&storage, app_uuid, recorder.keep)
+        .map_err(UploadError::Storage)?
+        .into_iter()
+        .find(|version| version.etag == etag)
+        .ok_or_else(|| {
+            UploadError::NotFound(format!("Application {} has no version {}", app_uuid, etag))
+        })?;
+    let application = version
+        .upgraded_application()
+        .map_err(|err| UploadError::Storage(format!("Version {} is corrupt: {}", etag, err)))?;
+
+    let (mut value, base) = read_existing(&storage)?;
+    let applications = applications_mut(&mut value)?;
+    match applications
+        .iter_mut()
+        .find(|candidate| has_uuid(candidate, app_uuid))
+    {
+        Some(current) => *current = application,
+        None => applications.push(application),
+    }
+    store_artefact(&storage, value, base, recorder)
+}
+
+/// Remove an application, or one action of it, from the stored artefact, returning the stored
+/// artefact. A removed application is marked removed in the [`Index`].
+pub fn remove(
+    app_uuid: &str,
+    action_uuid: Option<&str>,
+    recorder: &Recorder,
+) -> Result<String, UploadError> {
+    let storage = open_storage()?;
+    let (mut value, base) = read_existing(&storage)?;
+    let applications = applications_mut(&mut value)?;
+    let position = applications
+        .iter()
+        .position(|candidate| has_uuid(candidate, app_uuid))
+        .ok_or_else(|| UploadError::NotFound(format!("Application {} not found", app_uuid)))?;
+
+    match action_uuid {
+        None => {
+            applications.remove(position);
         }
-        Err(err) => Err(format!("Error: {}", err)),
-    }
-}
-
-pub fn write_artefact(_artefact: &String) -> Result<String, String> {
-    // we don't accept any input for now, so we use the json string below
-    let json_str = "{
-        \"cloud\": {
-            \"name\": \"BettyBlocks\",
-            \"applications\": [
-                {
-                    \"uuid\": \"693b22e983fb46afa4eb353d82ece4bb\",
-                    \"name\": \"MyApp\",
-                    \"actions\": [
-                        {
-                            \"uuid\": \"456\",
-                            \"auth\": \"None\",
-                            \"scope\": \"Public\",
-                            \"etag\": \"Public\"                            
-                        }
-                    ]
-                }
-            ]
+        Some(action_uuid) => {
+            let actions = applications[position]["actions"]
+                .as_array_mut()
+                .ok_or_else(|| {
+                    UploadError::Storage(format!("Application {} has no actions", app_uuid))
+                })?;
+            let before = actions.len();
+            actions.retain(|candidate| !has_uuid(candidate, action_uuid));
+            if actions.len() == before {
+                return Err(UploadError::NotFound(format!(
+                    "Action {} not found in application {}",
+                    action_uuid, app_uuid
+                )));
+            }
         }
-    }";
+    }
+    store_artefact(&storage, value, base, recorder)
+}
 
-    let bucket = store::open("default").unwrap();
+/// A part of the stored artefact, as read through the webhook
+pub enum Selection<'a> {
+    Cloud,
+    Application(&'a str),
+    Action {
+        application: &'a str,
+        action: &'a str,
+    },
+}
 
-    match bucket.set("cloud", json_str.as_bytes()) {
-        Ok(_) => match bucket.get("cloud") {
-            Ok(str) => Ok(String::from_utf8(str.unwrap()).unwrap()),
-            Err(err) => Err(format!("Error: {}", err)),
-        },
-        Err(err) => Err(format!("Error: {}", err)),
+/// Read a part of the stored artefact, `None` when it doesn't exist. The cloud is read with its
+/// revision, so it can be changed and uploaded again.
+pub fn read(selection: &Selection) -> Result<Option<serde_json::Value>, String> {
+    let storage = Storage::open()?;
+    let Some((mut value, revision)) = read_value(&storage)? else {
+        return Ok(None);
+    };
+    let application = |value: &mut serde_json::Value, uuid: &str| {
+        value["cloud"]["applications"]
+            .as_array_mut()
+            .and_then(|applications| {
+                applications
+                    .iter_mut()
+                    .find(|candidate| has_uuid(candidate, uuid))
+            })
+            .map(serde_json::Value::take)
+    };
+
+    Ok(match selection {
+        Selection::Cloud => {
+            value["revision"] = serde_json::Value::from(revision);
+            Some(value)
+        }
+        Selection::Application(uuid) => application(&mut value, uuid),
+        Selection::Action {
+            application: app_uuid,
+            action,
+        } => application(&mut value, app_uuid).and_then(|mut application| {
+            application["actions"].as_array_mut().and_then(|actions| {
+                actions
+                    .iter_mut()
+                    .find(|candidate| has_uuid(candidate, action))
+                    .map(serde_json::Value::take)
+            })
+        }),
+    })
+}
+
+/// A change to one application, as sent with a PATCH request.
+pub enum Patch {
+    /// An RFC 6902 JSON Patch
+    Json(json_patch::Patch),
+    /// An RFC 7386 JSON Merge Patch
+    Merge(serde_json::Value),
+}
+
+/// Apply a patch to an application. JSON Patches are applied all or nothing, and neither kind
+/// of patch may change the uuid of the application.
+fn apply_patch(
+    application: &serde_json::Value,
+    patch: &Patch,
+) -> Result<serde_json::Value, UploadError> {
+    let mut patched = application.clone();
+    match patch {
+        Patch::Json(patch) => json_patch::patch(&mut patched, patch).map_err(|err| {
+            UploadError::Invalid(vec![Violation::new(
+                &format!("$[{}]", err.operation),
+                err.to_string(),
+            )])
+        })?,
+        Patch::Merge(patch) => json_patch::merge(&mut patched, patch),
     }
+    // the etag of the patched application is the one it is stored with
+    migrate::lowercase_uuids(&mut patched);
+    if patched.get("uuid") != application.get("uuid") {
+        return Err(UploadError::Invalid(vec![Violation::new(
+            "$.uuid",
+            "the uuid of an application can't be changed",
+        )]));
+    }
+    Ok(patched)
+}
+
+/// Patch one application of the stored artefact, returning the stored artefact and the new etag
+/// of the application.
+///
+/// The patch is only applied when `if_match` is the current etag of the application, otherwise
+/// it would overwrite changes made since the patch was made.
+pub fn patch_application(
+    app_uuid: &str,
+    if_match: &str,
+    patch: &Patch,
+    recorder: &Recorder,
+) -> Result<(String, String), UploadError> {
+    let storage = open_storage()?;
+    let (mut value, base) = read_existing(&storage)?;
+    let application = applications_mut(&mut value)?
+        .iter_mut()
+        .find(|candidate| has_uuid(candidate, app_uuid))
+        .ok_or_else(|| UploadError::NotFound(format!("Application {} not found", app_uuid)))?;
+
+    let etag = history::etag(application);
+    if etag != if_match {
+        return Err(UploadError::PreconditionFailed(format!(
+            "Application {} changed, its etag is {}",
+            app_uuid, etag
+        )));
+    }
+    *application = apply_patch(application, patch)?;
+    let etag = history::etag(application);
+    let stored = store_artefact(&storage, value, base, recorder)?;
+    Ok((stored, etag))
+}
+
+/// Read the stored artefact, upgraded to the current version, and its revision.
+fn read_value(storage: &Storage) -> Result<Option<(serde_json::Value, u64)>, String> {
+    let Some(stored) = storage.get(CLOUD_KEY)? else {
+        return Ok(None);
+    };
+    let value = serde_json::from_slice(&stored)
+        .map_err(|err| format!("Stored artefact is not valid JSON: {}", err))?;
+    let (value, revision) = revision::split(value);
+    let value = migrate::upgrade(value)?;
+    Ok(Some((value, revision.unwrap_or(0))))
+}
+
+/// Read the stored artefact to change it.
+fn read_existing(storage: &Storage) -> Result<(serde_json::Value, u64), UploadError> {
+    read_value(storage)
+        .map_err(UploadError::Storage)?
+        .ok_or_else(|| UploadError::NotFound("No artefact has been uploaded yet".to_string()))
 }
 
+fn applications_mut(
+    value: &mut serde_json::Value,
+) -> Result<&mut Vec<serde_json::Value>, UploadError> {
+    value["cloud"]["applications"]
+        .as_array_mut()
+        .ok_or_else(|| UploadError::Storage("Stored artefact has no applications".to_string()))
+}
+
+fn has_uuid(value: &serde_json::Value, uuid: &str) -> bool {
+    value["uuid"]
+        .as_str()
+        .is_some_and(|candidate| is_uuid(candidate, uuid))
+}
+
+/// Whether a stored uuid is `uuid`. Uuids are stored lowercase, see [`migrate::lowercase_uuids`],
+/// so only the requested one is lowercased.
+fn is_uuid(stored: &str, uuid: &str) -> bool {
+    stored == uuid.to_lowercase()
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -150,11 +560,18 @@ mod tests {
                 name: "MyApp".to_string(),
                 actions: vec![Action {
                     uuid: "456".to_string(),
+                    name: "sendMail".to_string(),
                     auth: "None".to_string(),
                     scope: "Public".to_string(),
                     etag: "123".to_string(),
+                    component: None,
+                    input: None,
+                    output: None,
                 }],
+                queries: vec![],
+                origins: vec![],
             }],
+            components: vec![],
         };
         let result = action_exists_in_app(&cloud, "123".to_string(), "456".to_string());
         assert!(result.is_ok());
@@ -169,16 +586,53 @@ mod tests {
                 name: "MyApp".to_string(),
                 actions: vec![Action {
                     uuid: "456".to_string(),
+                    name: "sendMail".to_string(),
                     auth: "None".to_string(),
                     scope: "Public".to_string(),
                     etag: "123".to_string(),
+                    component: None,
+                    input: None,
+                    output: None,
                 }],
+                queries: vec![],
+                origins: vec![],
             }],
+            components: vec![],
         };
         let result = action_exists_in_app(&cloud, "123".to_string(), "46".to_string());
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_action_exists_in_app_ignores_case() {
+        let cloud = Cloud {
+            name: "BettyBlocks".to_string(),
+            applications: vec![Application {
+                uuid: "693b22e983fb46afa4eb353d82ece4bb".to_string(),
+                name: "MyApp".to_string(),
+                actions: vec![Action {
+                    uuid: "b1d7e3a0c6f94b0e9d5f2a4c8e7b6d51".to_string(),
+                    name: "sendMail".to_string(),
+                    auth: "None".to_string(),
+                    scope: "Public".to_string(),
+                    etag: "123".to_string(),
+                    component: None,
+                    input: None,
+                    output: None,
+                }],
+                queries: vec![],
+                origins: vec![],
+            }],
+            components: vec![],
+        };
+        let result = action_exists_in_app(
+            &cloud,
+            "693B22E983FB46AFA4EB353D82ECE4BB".to_string(),
+            "B1D7E3A0C6F94B0E9D5F2A4C8E7B6D51".to_string(),
+        );
+        assert!(result.is_ok());
+    }
+
     #[test]
     fn test_application_does_not_exists_in_app() {
         let cloud = Cloud {
@@ -188,16 +642,38 @@ mod tests {
                 name: "MyApp".to_string(),
                 actions: vec![Action {
                     uuid: "456".to_string(),
+                    name: "sendMail".to_string(),
                     auth: "None".to_string(),
                     scope: "Public".to_string(),
                     etag: "123".to_string(),
+                    component: None,
+                    input: None,
+                    output: None,
                 }],
+                queries: vec![],
+                origins: vec![],
             }],
+            components: vec![],
         };
         let result = action_exists_in_app(&cloud, "13".to_string(), "46".to_string());
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_parse_root_upgrades_unversioned_artefact() {
+        let root = parse_root(
+            r#"{"cloud": {"name": "BettyBlocks", "applications": [{"uuid": "123", "name": "MyApp", "actions": []}]}}"#,
+        )
+        .unwrap();
+        assert_eq!(root.version, migrate::CURRENT_VERSION);
+        assert_eq!(root.cloud.applications[0].uuid, "123");
+    }
+
+    #[test]
+    fn test_parse_root_rejects_invalid_json() {
+        assert!(parse_root("{\"cloud\": ").is_err());
+    }
+
     #[test]
     fn test_empty_args_for_exists_in_app() {
         let cloud = Cloud {
@@ -207,13 +683,166 @@ mod tests {
                 name: "MyApp".to_string(),
                 actions: vec![Action {
                     uuid: "456".to_string(),
+                    name: "sendMail".to_string(),
                     auth: "None".to_string(),
                     scope: "Public".to_string(),
                     etag: "123".to_string(),
+                    component: None,
+                    input: None,
+                    output: None,
                 }],
+                queries: vec![],
+                origins: vec![],
             }],
+            components: vec![],
         };
         let result = action_exists_in_app(&cloud, "".to_string(), "".to_string());
         assert!(result.is_err());
     }
+
+    fn application() -> serde_json::Value {
+        serde_json::json!({
+            "uuid": "693b22e983fb46afa4eb353d82ece4bb",
+            "name": "MyApp",
+            "actions": [{ "uuid": "b1d7e3a0c6f94b0e9d5f2a4c8e7b6d51", "auth": "None", "scope": "Public", "etag": "1" }]
+        })
+    }
+
+    #[test]
+    fn test_apply_json_patch() {
+        let patch = Patch::Json(
+            serde_json::from_value(serde_json::json!([
+                { "op": "replace", "path": "/actions/0/auth", "value": "Jwt" },
+                { "op": "replace", "path": "/name", "value": "Renamed" }
+            ]))
+            .unwrap(),
+        );
+        let patched = apply_patch(&application(), &patch).ok().unwrap();
+        assert_eq!(patched["actions"][0]["auth"], "Jwt");
+        assert_eq!(patched["name"], "Renamed");
+    }
+
+    #[test]
+    fn test_apply_json_patch_fails_as_a_whole() {
+        let patch = Patch::Json(
+            serde_json::from_value(serde_json::json!([
+                { "op": "replace", "path": "/name", "value": "Renamed" },
+                { "op": "test", "path": "/actions/0/auth", "value": "Jwt" }
+            ]))
+            .unwrap(),
+        );
+        match apply_patch(&application(), &patch) {
+            Err(UploadError::Invalid(violations)) => assert_eq!(violations[0].path, "$[1]"),
+            _ => panic!("patch with a failing test should be invalid"),
+        }
+    }
+
+    #[test]
+    fn test_apply_merge_patch() {
+        let patch = Patch::Merge(serde_json::json!({ "name": "Renamed" }));
+        let patched = apply_patch(&application(), &patch).ok().unwrap();
+        assert_eq!(patched["name"], "Renamed");
+        assert_eq!(patched["actions"], application()["actions"]);
+    }
+
+    #[test]
+    fn test_apply_patch_lowercases_uuids() {
+        let patch = Patch::Merge(serde_json::json!({ "uuid": "693B22E983FB46AFA4EB353D82ECE4BB" }));
+        let patched = apply_patch(&application(), &patch).ok().unwrap();
+        assert_eq!(patched, application());
+    }
+
+    #[test]
+    fn test_apply_patch_keeps_uuid() {
+        let patch = Patch::Merge(serde_json::json!({ "uuid": "0b1d7e3a0c6f94b0e9d5f2a4c8e7b6d5" }));
+        assert!(matches!(
+            apply_patch(&application(), &patch),
+            Err(UploadError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_stored_not_seeded() {
+        assert!(matches!(parse_stored(None), Err(ArtefactError::NotSeeded)));
+    }
+
+    #[test]
+    fn test_parse_stored_invalid_utf8() {
+        assert!(matches!(
+            parse_stored(Some(vec![0xff, 0xfe])),
+            Err(ArtefactError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_stored_invalid_json() {
+        assert!(matches!(
+            parse_stored(Some(b"{\"cloud\": ".to_vec())),
+            Err(ArtefactError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_stored_invalid_artefact() {
+        assert!(matches!(
+            parse_stored(Some(b"{\"version\": 3, \"cloud\": {\"name\": 1}}".to_vec())),
+            Err(ArtefactError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_stored_artefact() {
+        let root = parse_stored(Some(
+            br#"{"version": 4, "revision": 2, "cloud": {"name": "BettyBlocks", "applications": [], "components": []}}"#.to_vec(),
+        ))
+        .ok()
+        .unwrap();
+        assert_eq!(root.revision, 2);
+    }
+
+    #[test]
+    fn test_all_origins() {
+        let application = |uuid: &str, origins: &[&str]| Application {
+            uuid: uuid.to_string(),
+            name: "MyApp".to_string(),
+            actions: vec![],
+            queries: vec![],
+            origins: origins.iter().map(|origin| origin.to_string()).collect(),
+        };
+        let cloud = Cloud {
+            name: "BettyBlocks".to_string(),
+            applications: vec![
+                application("123", &["https://b.example.com", "https://A.example.com"]),
+                application("456", &["https://a.example.com"]),
+            ],
+            components: vec![],
+        };
+        assert_eq!(
+            all_origins(&cloud),
+            vec!["https://a.example.com", "https://b.example.com"]
+        );
+    }
+
+    #[test]
+    fn test_missing_application_and_action_errors() {
+        let cloud = Cloud {
+            name: "BettyBlocks".to_string(),
+            applications: vec![Application {
+                uuid: "123".to_string(),
+                name: "MyApp".to_string(),
+                actions: vec![],
+                queries: vec![],
+                origins: vec![],
+            }],
+            components: vec![],
+        };
+        assert!(matches!(
+            action_exists_in_app(&cloud, "13".to_string(), "456".to_string()),
+            Err(ArtefactError::ApplicationNotFound)
+        ));
+        assert!(matches!(
+            action_exists_in_app(&cloud, "123".to_string(), "456".to_string()),
+            Err(ArtefactError::ActionNotFound)
+        ));
+    }
 }
@@ -0,0 +1,312 @@
+//! History of the applications in the artefact.
+//!
+//! Every stored version of an application is appended to its own `history.<uuid>` journal, so an
+//! application can be rolled back without touching the rest of the cloud. Only the last
+//! [`DEFAULT_KEEP`] versions are kept unless the `history_size` config says otherwise. Versions
+//! carry the revision of the artefact they were stored in and are listed by it, a write that
+//! finishes after a newer one doesn't put its older version on top or lose the newer one.
+//! Histories from before the journal, a list under the `history.<uuid>` key, are still read.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::journal::Journal;
+use crate::migrate;
+use crate::storage::KeyValue;
+
+/// Config key with the number of versions to keep per application
+pub const KEEP_CONFIG: &str = "history_size";
+pub const DEFAULT_KEEP: usize = 10;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Version {
+    /// SHA-256 of the application, identifies the version
+    pub etag: String,
+    /// Milliseconds since the unix epoch
+    pub uploaded_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uploader: Option<String>,
+    /// Version of the artefact format the application was stored in
+    pub format: u64,
+    /// Revision of the artefact the application was stored in, 0 for versions from before
+    /// revisions
+    #[serde(default)]
+    pub revision: u64,
+    pub application: Value,
+}
+
+/// A version without the application, as listed by the webhook
+#[derive(Serialize, Debug)]
+pub struct Summary<'a> {
+    pub etag: &'a str,
+    pub uploaded_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uploader: Option<&'a str>,
+}
+
+impl Version {
+    pub fn summary(&self) -> Summary<'_> {
+        Summary {
+            etag: &self.etag,
+            uploaded_at: self.uploaded_at,
+            uploader: self.uploader.as_deref(),
+        }
+    }
+
+    /// The application upgraded to the current artefact format.
+    pub fn upgraded_application(&self) -> Result<Value, String> {
+        let root = serde_json::json!({
+            "version": self.format,
+            "cloud": { "applications": [self.application] },
+        });
+        let mut upgraded = migrate::upgrade(root)?;
+        Ok(upgraded["cloud"]["applications"][0].take())
+    }
+}
+
+/// Records the applications of every stored artefact.
+pub struct Recorder {
+    pub uploader: Option<String>,
+    /// Number of versions to keep per application
+    pub keep: usize,
+}
+
+impl Recorder {
+    /// Add the applications of the artefact stored as `revision` to their history, applications
+    /// that didn't change since their last version are skipped.
+    pub fn record(
+        &self,
+        storage: &impl KeyValue,
+        applications: &[Value],
+        revision: u64,
+    ) -> Result<(), String> {
+        let uploaded_at = now_millis();
+
+        for application in applications {
+            let Some(uuid) = application.get("uuid").and_then(Value::as_str) else {
+                continue;
+            };
+            let versions = load(storage, uuid, self.keep)?;
+            let version = Version {
+                etag: etag(application),
+                uploaded_at,
+                uploader: self.uploader.clone(),
+                format: migrate::CURRENT_VERSION,
+                revision,
+                application: application.clone(),
+            };
+            if changed(&versions, &version) {
+                let json = serde_json::to_vec(&version).map_err(|err| err.to_string())?;
+                journal(storage, uuid, self.keep).append(&json)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Milliseconds since the unix epoch
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn key(app_uuid: &str) -> String {
+    format!("history.{}", app_uuid.to_lowercase())
+}
+
+fn journal<'a, S: KeyValue>(storage: &'a S, app_uuid: &str, keep: usize) -> Journal<'a, S> {
+    Journal::new(storage, key(app_uuid), keep)
+}
+
+/// The stored versions of an application, at most `keep`, newest first.
+pub fn load(storage: &impl KeyValue, app_uuid: &str, keep: usize) -> Result<Vec<Version>, String> {
+    let corrupt = |err: serde_json::Error| format!("History of {} is corrupt: {}", app_uuid, err);
+    let mut versions: Vec<Version> = match storage.get(&key(app_uuid))? {
+        Some(json) => serde_json::from_slice(&json).map_err(corrupt)?,
+        None => vec![],
+    };
+    for json in journal(storage, app_uuid, keep).recent()?.into_iter().rev() {
+        versions.insert(0, serde_json::from_slice(&json).map_err(corrupt)?);
+    }
+    Ok(ordered(versions, keep))
+}
+
+/// Versions listed newest first by the order they were written in, ordered by revision instead.
+/// Of versions that are the same application the oldest is kept.
+fn ordered(mut versions: Vec<Version>, keep: usize) -> Vec<Version> {
+    versions.reverse();
+    // stable, versions of equal revisions stay in the order they were written in
+    versions.sort_by_key(|version| version.revision);
+    versions.dedup_by(|newer, older| newer.etag == older.etag);
+    versions.reverse();
+    versions.truncate(keep.max(1));
+    versions
+}
+
+/// The etag of an application, or any other part of the artefact, a SHA-256 of its JSON.
+pub fn etag(application: &Value) -> String {
+    // serde_json sorts object keys, so equal applications always hash the same
+    hex::encode(Sha256::digest(application.to_string()))
+}
+
+/// Whether a version changes the application compared to the version before it, the newest of
+/// the `versions` of an earlier revision.
+fn changed(versions: &[Version], version: &Version) -> bool {
+    versions
+        .iter()
+        .find(|previous| previous.revision < version.revision)
+        .is_none_or(|previous| previous.etag != version.etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use serde_json::json;
+
+    const APP: &str = "693b22e983fb46afa4eb353d82ece4bb";
+
+    fn version(name: &str) -> Version {
+        let application = json!({ "uuid": APP, "name": name, "actions": [] });
+        Version {
+            etag: etag(&application),
+            uploaded_at: 0,
+            uploader: None,
+            format: migrate::CURRENT_VERSION,
+            revision: 0,
+            application,
+        }
+    }
+
+    fn at(revision: u64, version: Version) -> Version {
+        Version {
+            revision,
+            ..version
+        }
+    }
+
+    #[test]
+    fn test_changed_compares_with_previous_revision() {
+        let versions = vec![at(2, version("a")), at(1, version("b"))];
+        assert!(!changed(&versions, &at(3, version("a"))));
+        // only the newest version counts, rolling back to an older version is a change
+        assert!(changed(&versions, &at(3, version("b"))));
+        // a write of revision 2 finishing after revision 3 compares with revision 1
+        assert!(changed(&[at(3, version("a"))], &at(2, version("b"))));
+        assert!(changed(&[], &version("a")));
+    }
+
+    #[test]
+    fn test_ordered_by_revision() {
+        let versions = vec![
+            at(2, version("b")),
+            at(3, version("c")),
+            at(1, version("a")),
+        ];
+        assert_eq!(
+            ordered(versions, 10),
+            vec![
+                at(3, version("c")),
+                at(2, version("b")),
+                at(1, version("a"))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ordered_keeps_oldest_of_same_application() {
+        let versions = vec![
+            at(3, version("b")),
+            at(2, version("b")),
+            at(1, version("a")),
+        ];
+        assert_eq!(
+            ordered(versions, 10),
+            vec![at(2, version("b")), at(1, version("a"))]
+        );
+    }
+
+    #[test]
+    fn test_ordered_drops_oldest_versions() {
+        let versions = vec![
+            at(3, version("c")),
+            at(2, version("b")),
+            at(1, version("a")),
+        ];
+        assert_eq!(
+            ordered(versions, 2),
+            vec![at(3, version("c")), at(2, version("b"))]
+        );
+    }
+
+    #[test]
+    fn test_interleaved_records_keep_both_versions() {
+        let storage = MemoryStorage::default();
+        let recorder = Recorder {
+            uploader: None,
+            keep: 10,
+        };
+        let application = |name| version(name).application;
+        recorder.record(&storage, &[application("a")], 4).unwrap();
+        // revision 6 is recorded before revision 5
+        recorder.record(&storage, &[application("c")], 6).unwrap();
+        recorder.record(&storage, &[application("b")], 5).unwrap();
+
+        let versions = load(&storage, APP, 10).unwrap();
+        let names: Vec<_> = versions
+            .iter()
+            .map(|version| {
+                (
+                    version.revision,
+                    version.application["name"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(names, vec![(6, "c"), (5, "b"), (4, "a")]);
+    }
+
+    #[test]
+    fn test_load_reads_history_from_before_journal() {
+        let storage = MemoryStorage::default();
+        let legacy = vec![version("b"), version("a")];
+        storage
+            .set(&key(APP), &serde_json::to_vec(&legacy).unwrap())
+            .unwrap();
+        assert_eq!(load(&storage, APP, 10), Ok(legacy));
+
+        let recorder = Recorder {
+            uploader: None,
+            keep: 2,
+        };
+        recorder
+            .record(&storage, &[version("c").application], 1)
+            .unwrap();
+        recorder
+            .record(&storage, &[version("b").application], 2)
+            .unwrap();
+        let versions = load(&storage, APP, 2).unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[0].etag, version("b").etag);
+        assert_eq!(versions[1].etag, version("c").etag);
+    }
+
+    #[test]
+    fn test_etag_ignores_key_order() {
+        let a = json!({ "uuid": "1", "name": "MyApp" });
+        let b: Value = serde_json::from_str(r#"{"name": "MyApp", "uuid": "1"}"#).unwrap();
+        assert_eq!(etag(&a), etag(&b));
+        assert_ne!(etag(&a), etag(&json!({ "uuid": "1", "name": "Other" })));
+    }
+
+    #[test]
+    fn test_upgraded_application() {
+        let mut version = version("a");
+        version.format = 1;
+        assert_eq!(version.upgraded_application().unwrap(), version.application);
+    }
+}
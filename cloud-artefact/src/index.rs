@@ -0,0 +1,213 @@
+//! Index of the applications in the artefact.
+//!
+//! The `index` key lists the applications in the stored artefact, and remembers the ones that
+//! were removed from it. That way a request for a removed application can be told apart from a
+//! request for an application that never existed.
+//!
+//! The index remembers the revision of the artefact it was updated for, and every update is
+//! appended to the `index` journal instead of overwriting the last one. The index of the highest
+//! revision in the journal is the current one, so a write that finishes after a newer one can't
+//! undo it. The revision is also where writes continue from when the artefact itself is gone.
+//! Indexes from before the journal are read from the `index` key.
+
+use std::collections::BTreeMap;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::journal::Journal;
+use crate::storage::KeyValue;
+
+const KEY: &str = "index";
+/// Indexes kept in the journal, enough for the writes that may finish out of order
+const KEEP: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct Index {
+    /// UUIDs of the applications in the stored artefact, lowercased
+    pub applications: Vec<String>,
+    /// Applications removed from the artefact, by lowercased UUID
+    pub removed: BTreeMap<String, Removal>,
+    /// Revision of the artefact the index was updated for, 0 for indexes from before revisions
+    #[serde(default)]
+    pub revision: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Removal {
+    /// Milliseconds since the unix epoch
+    pub removed_at: u64,
+    /// Revision of the artefact the application was removed in
+    pub revision: u64,
+}
+
+impl Index {
+    /// The index of the highest revision, the most recently written one of equal revisions.
+    pub fn load(storage: &impl KeyValue) -> Result<Index, String> {
+        let mut current: Option<Index> = None;
+        for json in journal(storage).recent()? {
+            let index = parse(&json)?;
+            if current
+                .as_ref()
+                .is_none_or(|current| index.revision > current.revision)
+            {
+                current = Some(index);
+            }
+        }
+        match (current, storage.get(KEY)?) {
+            (Some(index), _) => Ok(index),
+            (None, Some(json)) => parse(&json),
+            (None, None) => Ok(Index::default()),
+        }
+    }
+
+    pub fn save(&self, storage: &impl KeyValue) -> Result<(), String> {
+        let json = serde_json::to_vec(self).map_err(|err| err.to_string())?;
+        journal(storage).append(&json)
+    }
+
+    pub fn is_removed(&self, app_uuid: &str) -> bool {
+        self.removed.contains_key(&app_uuid.to_lowercase())
+    }
+
+    /// The index after storing an artefact with `applications` as `revision`. Applications
+    /// missing from it are marked removed, applications that are back are no longer removed.
+    /// Returns `None` when the index is already at a newer revision.
+    pub fn update<'a>(
+        mut self,
+        applications: impl IntoIterator<Item = &'a str>,
+        revision: u64,
+        now: u64,
+    ) -> Option<Index> {
+        if self.revision > revision {
+            return None;
+        }
+        let applications: Vec<String> = applications.into_iter().map(str::to_lowercase).collect();
+        for uuid in self.applications.drain(..) {
+            if !applications.contains(&uuid) {
+                self.removed.entry(uuid).or_insert(Removal {
+                    removed_at: now,
+                    revision,
+                });
+            }
+        }
+        for uuid in &applications {
+            self.removed.remove(uuid);
+        }
+        self.applications = applications;
+        self.revision = revision;
+        Some(self)
+    }
+}
+
+fn journal<S: KeyValue>(storage: &S) -> Journal<'_, S> {
+    Journal::new(storage, KEY.to_string(), KEEP)
+}
+
+fn parse(json: &[u8]) -> Result<Index, String> {
+    serde_json::from_slice(json).map_err(|err| format!("Application index is corrupt: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    const APP: &str = "693b22e983fb46afa4eb353d82ece4bb";
+    const OTHER_APP: &str = "2f1c5e7a9b3d4c6e8f0a1b2c3d4e5f60";
+
+    #[test]
+    fn test_update_marks_missing_applications_removed() {
+        let index = Index::default()
+            .update([APP, OTHER_APP], 1, 100)
+            .and_then(|index| index.update([OTHER_APP], 2, 200))
+            .unwrap();
+        assert_eq!(index.applications, vec![OTHER_APP]);
+        assert!(index.is_removed(APP));
+        assert!(!index.is_removed(OTHER_APP));
+        assert_eq!(
+            index.removed[APP],
+            Removal {
+                removed_at: 200,
+                revision: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_update_keeps_first_removal() {
+        let index = Index::default()
+            .update([APP], 1, 100)
+            .and_then(|index| index.update([], 2, 200))
+            .and_then(|index| index.update([], 3, 300))
+            .unwrap();
+        assert_eq!(index.removed[APP].revision, 2);
+    }
+
+    #[test]
+    fn test_update_restores_applications() {
+        let index = Index::default()
+            .update([APP], 1, 100)
+            .and_then(|index| index.update([], 2, 200))
+            .and_then(|index| index.update([&APP.to_uppercase() as &str], 3, 300))
+            .unwrap();
+        assert!(!index.is_removed(APP));
+        assert_eq!(index.applications, vec![APP]);
+    }
+
+    #[test]
+    fn test_never_stored_application_is_not_removed() {
+        let index = Index::default().update([APP], 1, 100).unwrap();
+        assert!(!index.is_removed(OTHER_APP));
+    }
+
+    #[test]
+    fn test_update_skips_older_revision() {
+        let index = Index::default().update([APP], 3, 100).unwrap();
+        assert_eq!(index.revision, 3);
+        // a write of revision 2 finishing after revision 3 was indexed
+        assert_eq!(index.update([OTHER_APP], 2, 200), None);
+    }
+
+    #[test]
+    fn test_interleaved_writes_keep_newest_revision() {
+        let storage = MemoryStorage::default();
+        Index::default()
+            .update([APP], 4, 100)
+            .unwrap()
+            .save(&storage)
+            .unwrap();
+
+        // writes of revision 5 and 6 both load the index of revision 4, 6 saves first
+        let five = Index::load(&storage).unwrap();
+        let six = Index::load(&storage).unwrap();
+        six.update([OTHER_APP], 6, 300)
+            .unwrap()
+            .save(&storage)
+            .unwrap();
+        five.update([APP], 5, 200).unwrap().save(&storage).unwrap();
+
+        let index = Index::load(&storage).unwrap();
+        assert_eq!(index.revision, 6);
+        assert_eq!(index.applications, vec![OTHER_APP]);
+        assert!(index.is_removed(APP));
+    }
+
+    #[test]
+    fn test_load_reads_index_from_before_journal() {
+        let storage = MemoryStorage::default();
+        assert_eq!(Index::load(&storage), Ok(Index::default()));
+        let index = Index::default().update([APP], 3, 100).unwrap();
+        storage
+            .set(KEY, &serde_json::to_vec(&index).unwrap())
+            .unwrap();
+        assert_eq!(Index::load(&storage), Ok(index));
+
+        Index::load(&storage)
+            .unwrap()
+            .update([OTHER_APP], 4, 200)
+            .unwrap()
+            .save(&storage)
+            .unwrap();
+        assert_eq!(Index::load(&storage).unwrap().revision, 4);
+    }
+}
@@ -0,0 +1,76 @@
+//! Values written to keys of their own.
+//!
+//! A read-modify-write of a single key loses one of two writes that read it before either saved
+//! it. A journal gives every write its own `<name>.<n>` key instead, numbered by an atomic
+//! increment of `<name>.seq`, so no write overwrites another. Readers take the most recent
+//! entries and decide which one counts, e.g. the one of the highest revision. Only the last
+//! `keep` entries are kept.
+
+use crate::storage::KeyValue;
+
+pub struct Journal<'a, S: KeyValue> {
+    storage: &'a S,
+    name: String,
+    keep: u64,
+}
+
+impl<'a, S: KeyValue> Journal<'a, S> {
+    pub fn new(storage: &'a S, name: String, keep: usize) -> Self {
+        Journal {
+            storage,
+            name,
+            keep: keep.max(1) as u64,
+        }
+    }
+
+    fn entry_key(&self, n: u64) -> String {
+        format!("{}.{}", self.name, n)
+    }
+
+    fn seq_key(&self) -> String {
+        format!("{}.seq", self.name)
+    }
+
+    pub fn append(&self, value: &[u8]) -> Result<(), String> {
+        let n = self.storage.increment(&self.seq_key(), 1)?;
+        self.storage.set(&self.entry_key(n), value)?;
+        if n > self.keep {
+            // nothing depends on removing it, a failure only leaves a stale key behind
+            let _ = self.storage.delete(&self.entry_key(n - self.keep));
+        }
+        Ok(())
+    }
+
+    /// The last `keep` entries, most recently numbered first. Entries still being written are
+    /// left out.
+    pub fn recent(&self) -> Result<Vec<Vec<u8>>, String> {
+        let last = self.storage.increment(&self.seq_key(), 0)?;
+        let first = last.saturating_sub(self.keep) + 1;
+        let mut entries = vec![];
+        for n in (first..=last).rev() {
+            if let Some(value) = self.storage.get(&self.entry_key(n))? {
+                entries.push(value);
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_recent_entries_newest_first() {
+        let storage = MemoryStorage::default();
+        let journal = Journal::new(&storage, "log".to_string(), 2);
+        assert_eq!(journal.recent(), Ok(vec![]));
+        for value in ["a", "b", "c"] {
+            journal.append(value.as_bytes()).unwrap();
+        }
+        assert_eq!(journal.recent(), Ok(vec![b"c".to_vec(), b"b".to_vec()]));
+        // the oldest entry is removed
+        assert_eq!(storage.get("log.1"), Ok(None));
+    }
+}
@@ -1,41 +1,543 @@
 wit_bindgen::generate!({ generate_all });
-use exports::bettyblocks::runtime_cloud::meta_artefact::Guest;
+use artefact::{Patch, Selection, UploadError};
+use exports::bettyblocks::runtime_cloud::meta_artefact::{Application, ArtefactError, Guest};
 use exports::wasi::http::incoming_handler::Guest as IncomingHandler;
+use history::Recorder;
+use serde_derive::Deserialize;
+use signature::SignatureError;
+use storage::Storage;
 use wasi::http::types::*;
+use wasi::io::streams::StreamError;
 use wasi::logging::logging::{log, Level};
 mod artefact;
+mod history;
+mod index;
+mod journal;
+mod migrate;
+mod revision;
+mod signature;
+mod storage;
+mod validation;
 
 struct Component;
 
+/// Config key holding the bearer token to read artefacts with
+const ADMIN_TOKEN_CONFIG: &str = "admin_token";
+
 export!(Component);
 
 impl Guest for Component {
-    fn validate(app_uuid: String, action_uuid: String) -> Result<bool, String> {
-        //for now we always first store the artefact
-        artefact::write_artefact(&"".to_string()).expect("error");
+    fn validate(app_uuid: String, action_uuid: String) -> Result<bool, ArtefactError> {
+        let context = format!(
+            "validate action {} of application {}",
+            action_uuid, app_uuid
+        );
+        artefact::validate(app_uuid, action_uuid).inspect_err(|err| log_failure(&context, err))
+    }
+
+    fn get_application(
+        app_uuid: String,
+        known_etag: Option<String>,
+    ) -> Result<Option<Application>, ArtefactError> {
+        let application = artefact::application(&app_uuid, known_etag.as_deref())
+            .inspect_err(|err| log_failure(&format!("read application {}", app_uuid), err))?;
+        Ok(application.map(|(etag, application)| Application {
+            etag,
+            json: application.to_string(),
+        }))
+    }
+
+    fn origins() -> Result<Vec<String>, ArtefactError> {
+        artefact::origins().inspect_err(|err| log_failure("read the origins", err))
+    }
+}
 
-        match artefact::validate(app_uuid, action_uuid) {
-            Ok(result) => Ok(result),
-            Err(err) => Err(err),
+fn log_failure(context: &str, err: &ArtefactError) {
+    let level = match err {
+        ArtefactError::NotSeeded
+        | ArtefactError::Corrupt(_)
+        | ArtefactError::StorageUnavailable(_) => Level::Error,
+        ArtefactError::ApplicationNotFound
+        | ArtefactError::ApplicationRemoved
+        | ArtefactError::ActionNotFound => Level::Info,
+    };
+    log(level, "BG", &format!("Failed to {}: {:?}", context, err));
+}
+
+fn read_body(request: &IncomingRequest) -> Result<Vec<u8>, String> {
+    let body = request
+        .consume()
+        .map_err(|_| "Request body was already consumed".to_string())?;
+    let stream = body
+        .stream()
+        .map_err(|_| "Request body stream was already taken".to_string())?;
+    let mut buf = vec![];
+    loop {
+        match stream.blocking_read(4096) {
+            Ok(chunk) => buf.extend(chunk),
+            Err(StreamError::Closed) => break,
+            Err(StreamError::LastOperationFailed(err)) => {
+                return Err(format!(
+                    "Failed to read request body: {}",
+                    err.to_debug_string()
+                ))
+            }
         }
     }
+    // the stream is a child of the body and has to be dropped first
+    drop(stream);
+    IncomingBody::finish(body);
+    Ok(buf)
+}
+
+fn respond(response_out: ResponseOutparam, status: u16, content_type: &str, body: &[u8]) {
+    respond_with(response_out, status, content_type, &[], body)
+}
+
+fn respond_with(
+    response_out: ResponseOutparam,
+    status: u16,
+    content_type: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) {
+    let headers: Vec<_> = std::iter::once(("content-type", content_type))
+        .chain(headers.iter().copied())
+        .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+        .collect();
+    let headers = Fields::from_list(&headers).unwrap();
+    let response = OutgoingResponse::new(headers);
+    response.set_status_code(status).unwrap();
+    let response_body = response.body().unwrap();
+    ResponseOutparam::set(response_out, Ok(response));
+    let stream = response_body.write().unwrap();
+    // a single blocking write is limited to 4096 bytes
+    for chunk in body.chunks(4096) {
+        stream.blocking_write_and_flush(chunk).unwrap();
+    }
+    drop(stream);
+    OutgoingBody::finish(response_body, None).expect("failed to finish response body");
+}
+
+/// Read the body of a mutating request and check its signature, see [`signature`].
+fn signed_body(request: &IncomingRequest) -> Result<Vec<u8>, (u16, String)> {
+    let body = read_body(request).map_err(|err| (400, err))?;
+    verify_signature(request, &body)?;
+    Ok(body)
+}
+
+/// Check the signature of a mutating request over its timestamp and `signed`, its body or for
+/// requests without a body its path and query.
+fn verify_signature(request: &IncomingRequest, signed: &[u8]) -> Result<(), (u16, String)> {
+    let key = match wasi::config::runtime::get(signature::KEY_CONFIG) {
+        Ok(Some(key)) if !key.is_empty() => key,
+        Ok(_) => {
+            return Err((
+                500,
+                format!(
+                    "No `{}` configured, refusing to store artefacts",
+                    signature::KEY_CONFIG
+                ),
+            ))
+        }
+        Err(err) => return Err((500, format!("Failed to read the signing key: {:?}", err))),
+    };
+    let header = request.headers().get(&signature::HEADER.to_string());
+    let timestamp = request
+        .headers()
+        .get(&signature::TIMESTAMP_HEADER.to_string());
+    let now = history::now_millis() / 1000;
+    let rejected = |err: SignatureError| {
+        let status = match err {
+            SignatureError::Missing | SignatureError::MissingTimestamp => 401,
+            SignatureError::Malformed
+            | SignatureError::Mismatch
+            | SignatureError::MalformedTimestamp
+            | SignatureError::Expired
+            | SignatureError::Replayed => 403,
+        };
+        let err = format!("Rejected request of {} bytes: {}", signed.len(), err);
+        (status, err)
+    };
+    let valid = signature::verify(
+        key.as_bytes(),
+        header.first().map(Vec::as_slice),
+        timestamp.first().map(Vec::as_slice),
+        signed,
+        now,
+    )
+    .map_err(rejected)?;
+    let storage = Storage::open().map_err(|err| (500, err))?;
+    signature::claim(&storage, &valid, now)
+        .map_err(|err| (500, format!("Failed to claim the signature: {}", err)))?
+        .map_err(rejected)
+}
+
+fn reject(response_out: ResponseOutparam, status: u16, err: &str) {
+    let level = if status >= 500 {
+        Level::Error
+    } else {
+        Level::Warn
+    };
+    log(level, "BG", err);
+    respond(response_out, status, "text/plain", err.as_bytes());
+}
+
+/// Check the request has the `admin_token` config as bearer token.
+fn authorize_admin(request: &IncomingRequest) -> Result<(), (u16, String)> {
+    let expected = match wasi::config::runtime::get(ADMIN_TOKEN_CONFIG) {
+        Ok(Some(token)) if !token.is_empty() => token,
+        Ok(_) => {
+            return Err((
+                500,
+                format!(
+                    "No `{}` configured, refusing to read artefacts",
+                    ADMIN_TOKEN_CONFIG
+                ),
+            ))
+        }
+        Err(err) => return Err((500, format!("Failed to read the admin token: {:?}", err))),
+    };
+    let authorization = header(request, "authorization");
+    match authorization
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+    {
+        Some(token) if signature::token_matches(&expected, token.trim()) => Ok(()),
+        Some(_) => Err((403, "Invalid admin token".to_string())),
+        None => Err((
+            401,
+            "Reading artefacts requires an admin bearer token".to_string(),
+        )),
+    }
+}
+
+/// The first value of a request header
+fn header(request: &IncomingRequest, name: &str) -> Option<String> {
+    request
+        .headers()
+        .get(&name.to_string())
+        .into_iter()
+        .next()
+        .and_then(|value| String::from_utf8(value).ok())
+}
+
+/// The history recorder for a change, with the uploader from the `X-Artefact-Uploader` header.
+fn recorder(request: &IncomingRequest) -> Recorder {
+    let uploader = header(request, "x-artefact-uploader");
+    Recorder {
+        uploader,
+        keep: history_size(),
+    }
+}
+
+/// The number of versions to keep per application, from the `history_size` config.
+fn history_size() -> usize {
+    match wasi::config::runtime::get(history::KEEP_CONFIG) {
+        Ok(Some(keep)) => keep.parse().unwrap_or_else(|_| {
+            log(
+                Level::Warn,
+                "BG",
+                &format!("Invalid `{}`: {}", history::KEEP_CONFIG, keep),
+            );
+            history::DEFAULT_KEEP
+        }),
+        _ => history::DEFAULT_KEEP,
+    }
+}
+
+fn respond_stored(response_out: ResponseOutparam, result: Result<String, UploadError>) {
+    match result {
+        Ok(result) => {
+            log(Level::Info, "BG", &format!("Artefact: {}", result));
+            respond(response_out, 200, "application/json", result.as_bytes());
+        }
+        Err(UploadError::Invalid(violations)) => {
+            log(
+                Level::Warn,
+                "BG",
+                &format!("Rejected artefact with {} violations", violations.len()),
+            );
+            let errors = serde_json::json!({ "errors": violations }).to_string();
+            respond(response_out, 422, "application/json", errors.as_bytes());
+        }
+        Err(UploadError::NotFound(err)) => {
+            log(Level::Warn, "BG", &err);
+            respond(response_out, 404, "text/plain", err.as_bytes());
+        }
+        Err(UploadError::PreconditionFailed(err)) => {
+            log(Level::Warn, "BG", &err);
+            respond(response_out, 412, "text/plain", err.as_bytes());
+        }
+        Err(UploadError::Conflict(err)) => {
+            log(Level::Warn, "BG", &err);
+            respond(response_out, 409, "text/plain", err.as_bytes());
+        }
+        Err(UploadError::Storage(err)) => {
+            log(
+                Level::Error,
+                "BG",
+                &format!("Failed to store artefact: {}", err),
+            );
+            respond(response_out, 500, "text/plain", err.as_bytes());
+        }
+    }
+}
+
+fn upload(request: IncomingRequest, response_out: ResponseOutparam) {
+    let body = match signed_body(&request) {
+        Ok(body) => body,
+        Err((status, err)) => return reject(response_out, status, &err),
+    };
+    let expected = match header(&request, "x-artefact-revision") {
+        Some(revision) => match revision.trim().parse() {
+            Ok(revision) => Some(revision),
+            Err(_) => {
+                let err = format!("Invalid X-Artefact-Revision header: {}", revision);
+                return reject(response_out, 400, &err);
+            }
+        },
+        None => None,
+    };
+    let result = artefact::write_artefact(&body, expected, &recorder(&request));
+    respond_stored(response_out, result);
+}
+
+#[derive(Deserialize)]
+struct Rollback {
+    application: String,
+    etag: String,
+}
+
+fn rollback(request: IncomingRequest, response_out: ResponseOutparam) {
+    let body = match signed_body(&request) {
+        Ok(body) => body,
+        Err((status, err)) => return reject(response_out, status, &err),
+    };
+    let Rollback { application, etag } = match serde_json::from_slice(&body) {
+        Ok(rollback) => rollback,
+        Err(err) => {
+            let err = format!("Invalid rollback request: {}", err);
+            return reject(response_out, 400, &err);
+        }
+    };
+    log(
+        Level::Info,
+        "BG",
+        &format!("Rolling back application {} to {}", application, etag),
+    );
+    let result = artefact::rollback(&application, &etag, &recorder(&request));
+    respond_stored(response_out, result);
+}
+
+const JSON_PATCH: &str = "application/json-patch+json";
+const MERGE_PATCH: &str = "application/merge-patch+json";
+
+fn patch(request: IncomingRequest, app_uuid: &str, response_out: ResponseOutparam) {
+    let media_type = header(&request, "content-type").and_then(|value| {
+        value
+            .split(';')
+            .next()
+            .map(str::trim)
+            .map(str::to_lowercase)
+    });
+    if !matches!(media_type.as_deref(), Some(JSON_PATCH | MERGE_PATCH)) {
+        let accepted = format!("{}, {}", JSON_PATCH, MERGE_PATCH);
+        let err = format!("Unsupported patch format, expected one of {}\n", accepted);
+        return respond_with(
+            response_out,
+            415,
+            "text/plain",
+            &[("accept-patch", accepted.as_str())],
+            err.as_bytes(),
+        );
+    }
+    // patches must be based on the current version of the application to not lose updates
+    let Some(if_match) = header(&request, "if-match") else {
+        return reject(
+            response_out,
+            428,
+            "A patch requires an If-Match header with the etag of the application",
+        );
+    };
+    let if_match = if_match.trim().trim_start_matches("W/").trim_matches('"');
+
+    let body = match signed_body(&request) {
+        Ok(body) => body,
+        Err((status, err)) => return reject(response_out, status, &err),
+    };
+    let patch = match media_type.as_deref() {
+        Some(JSON_PATCH) => serde_json::from_slice(&body).map(Patch::Json),
+        _ => serde_json::from_slice(&body).map(Patch::Merge),
+    };
+    let patch = match patch {
+        Ok(patch) => patch,
+        Err(err) => return reject(response_out, 400, &format!("Invalid patch: {}", err)),
+    };
+
+    log(
+        Level::Info,
+        "BG",
+        &format!("Patching application {}", app_uuid),
+    );
+    match artefact::patch_application(app_uuid, if_match, &patch, &recorder(&request)) {
+        Ok((stored, etag)) => respond_with(
+            response_out,
+            200,
+            "application/json",
+            &[("etag", format!("\"{}\"", etag).as_str())],
+            stored.as_bytes(),
+        ),
+        Err(err) => respond_stored(response_out, Err(err)),
+    }
+}
+
+fn list_versions(request: IncomingRequest, app_uuid: &str, response_out: ResponseOutparam) {
+    if let Err((status, err)) = authorize_admin(&request) {
+        return reject(response_out, status, &err);
+    }
+    match artefact::versions(app_uuid, history_size()) {
+        Ok(versions) => {
+            let summaries: Vec<_> = versions.iter().map(history::Version::summary).collect();
+            let body = serde_json::json!({ "application": app_uuid, "versions": summaries });
+            respond(
+                response_out,
+                200,
+                "application/json",
+                body.to_string().as_bytes(),
+            );
+        }
+        Err(err) => {
+            log(Level::Error, "BG", &err);
+            respond(response_out, 500, "text/plain", err.as_bytes());
+        }
+    }
+}
+
+fn remove(
+    request: IncomingRequest,
+    app_uuid: &str,
+    action_uuid: Option<&str>,
+    response_out: ResponseOutparam,
+) {
+    // a DELETE has no body, the request target is signed instead
+    let target = request.path_with_query().unwrap_or_default();
+    if let Err((status, err)) = verify_signature(&request, target.as_bytes()) {
+        return reject(response_out, status, &err);
+    }
+    match action_uuid {
+        Some(action_uuid) => log(
+            Level::Info,
+            "BG",
+            &format!(
+                "Removing action {} of application {}",
+                action_uuid, app_uuid
+            ),
+        ),
+        None => log(
+            Level::Info,
+            "BG",
+            &format!("Removing application {}", app_uuid),
+        ),
+    }
+    let result = artefact::remove(app_uuid, action_uuid, &recorder(&request));
+    respond_stored(response_out, result);
+}
+
+/// Respond with a part of the artefact, or `304 Not Modified` when the client has it cached.
+fn read(request: IncomingRequest, selection: Selection, response_out: ResponseOutparam) {
+    if let Err((status, err)) = authorize_admin(&request) {
+        return reject(response_out, status, &err);
+    }
+    let value = match artefact::read(&selection) {
+        Ok(Some(value)) => value,
+        Ok(None) => return respond(response_out, 404, "text/plain", b"Not found\n"),
+        Err(err) => return reject(response_out, 500, &err),
+    };
+
+    let etag = format!("\"{}\"", history::etag(&value));
+    let cached = header(&request, "if-none-match").is_some_and(|if_none_match| {
+        if_none_match
+            .split(',')
+            .map(|candidate| candidate.trim().trim_start_matches("W/"))
+            .any(|candidate| candidate == "*" || candidate == etag)
+    });
+    let headers = [("etag", etag.as_str())];
+    if cached {
+        respond_with(response_out, 304, "application/json", &headers, b"")
+    } else {
+        let body = value.to_string();
+        respond_with(
+            response_out,
+            200,
+            "application/json",
+            &headers,
+            body.as_bytes(),
+        )
+    }
 }
 
 impl IncomingHandler for Component {
     //this handle implements the /artefact-webhook endpoint
-    fn handle(_request: IncomingRequest, response_out: ResponseOutparam) {
-        let result = artefact::write_artefact(&"".to_string()).expect("error");
-        log(Level::Info, "BG", &format!("Artefact: {}", result));
-
-        let response = OutgoingResponse::new(Fields::new());
-        response.set_status_code(200).unwrap();
-        let response_body = response.body().unwrap();
-        ResponseOutparam::set(response_out, Ok(response));
-        response_body
-            .write()
-            .unwrap()
-            .blocking_write_and_flush(b"Hello from Rust!\n")
-            .unwrap();
-        OutgoingBody::finish(response_body, None).expect("failed to finish response body");
+    fn handle(request: IncomingRequest, response_out: ResponseOutparam) {
+        let query = request
+            .path_with_query()
+            .and_then(|path| path.split_once('?').map(|(_, query)| query.to_string()));
+        let params: Vec<(&str, &str)> = query
+            .as_deref()
+            .unwrap_or("")
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .map(|param| param.split_once('=').unwrap_or((param, "")))
+            .collect();
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(key, value)| *key == name && !value.is_empty())
+                .map(|(_, value)| *value)
+        };
+        let route = params.first().map_or("", |(key, _)| *key);
+
+        match (request.method(), route) {
+            (Method::Get, "schema") => respond(
+                response_out,
+                200,
+                "application/schema+json",
+                validation::SCHEMA.as_bytes(),
+            ),
+            (Method::Get, "cloud") => read(request, Selection::Cloud, response_out),
+            (Method::Get, "application") => match (param("application"), param("action")) {
+                (Some(application), Some(action)) => read(
+                    request,
+                    Selection::Action {
+                        application,
+                        action,
+                    },
+                    response_out,
+                ),
+                (Some(application), None) => {
+                    read(request, Selection::Application(application), response_out)
+                }
+                _ => respond(response_out, 404, "text/plain", b"Not found\n"),
+            },
+            (Method::Get, "history") => match param("history") {
+                Some(application) => list_versions(request, application, response_out),
+                None => respond(response_out, 404, "text/plain", b"Not found\n"),
+            },
+            (Method::Post, "rollback") => rollback(request, response_out),
+            (Method::Post, "") => upload(request, response_out),
+            (Method::Patch, "application") => match param("application") {
+                Some(application) => patch(request, application, response_out),
+                None => respond(response_out, 404, "text/plain", b"Not found\n"),
+            },
+            (Method::Delete, "application") => match param("application") {
+                Some(application) => remove(request, application, param("action"), response_out),
+                None => respond(response_out, 404, "text/plain", b"Not found\n"),
+            },
+            (Method::Get | Method::Post | Method::Patch | Method::Delete, _) => {
+                respond(response_out, 404, "text/plain", b"Not found\n")
+            }
+            _ => respond(response_out, 405, "text/plain", b"Method not allowed\n"),
+        }
     }
 }
@@ -0,0 +1,232 @@
+//! Versioning of the stored artefact.
+//!
+//! Every artefact is stored in a versioned envelope, `{ "version": n, "cloud": { .. } }`.
+//! Artefacts written by older versions are upgraded on read by running them through the
+//! migrations from their version up to [`CURRENT_VERSION`]. When the artefact format changes,
+//! bump [`CURRENT_VERSION`] and add a migration from the previous version to [`MIGRATIONS`].
+
+use serde_json::{Map, Value};
+
+/// Version of the artefact format this component reads and writes.
+pub const CURRENT_VERSION: u64 = 7;
+
+type Migration = fn(Value) -> Result<Value, String>;
+
+/// `MIGRATIONS[n]` upgrades an artefact from version `n + 1` to version `n + 2`.
+const MIGRATIONS: [Migration; (CURRENT_VERSION - 1) as usize] =
+    [v1_to_v2, v2_to_v3, v3_to_v4, v4_to_v5, v5_to_v6, v6_to_v7];
+
+/// Version 1 is the unversioned `{ "cloud": { .. } }` root, version 2 adds the envelope.
+fn v1_to_v2(artefact: Value) -> Result<Value, String> {
+    let Value::Object(mut root) = artefact else {
+        return Err("Artefact is not a JSON object".to_string());
+    };
+    let cloud = root
+        .remove("cloud")
+        .ok_or_else(|| "Artefact has no cloud".to_string())?;
+    let mut envelope = Map::new();
+    envelope.insert("version".to_string(), Value::from(2));
+    envelope.insert("cloud".to_string(), cloud);
+    Ok(Value::Object(envelope))
+}
+
+/// Version 3 adds the list of components actions can reference.
+fn v2_to_v3(mut artefact: Value) -> Result<Value, String> {
+    let cloud = artefact
+        .get_mut("cloud")
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| "Artefact has no cloud".to_string())?;
+    cloud
+        .entry("components")
+        .or_insert_with(|| Value::Array(vec![]));
+    artefact["version"] = Value::from(3);
+    Ok(artefact)
+}
+
+/// Version 4 names every action, the name of its field in the GraphQL schema of the application.
+/// Existing actions are named after their uuid, which is unique within the application.
+fn v3_to_v4(mut artefact: Value) -> Result<Value, String> {
+    let applications = artefact["cloud"]["applications"]
+        .as_array_mut()
+        .ok_or_else(|| "Artefact has no applications".to_string())?;
+    for application in applications {
+        let Some(actions) = application["actions"].as_array_mut() else {
+            continue;
+        };
+        for action in actions.iter_mut().filter_map(Value::as_object_mut) {
+            if action.contains_key("name") {
+                continue;
+            }
+            let uuid = action
+                .get("uuid")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            let name = format!("action_{}", uuid.replace('-', "").to_lowercase());
+            action.insert("name".to_string(), Value::from(name));
+        }
+    }
+    artefact["version"] = Value::from(4);
+    Ok(artefact)
+}
+
+/// Version 5 adds the persisted queries of applications, which are optional.
+fn v4_to_v5(mut artefact: Value) -> Result<Value, String> {
+    artefact["version"] = Value::from(5);
+    Ok(artefact)
+}
+
+/// Version 6 adds the origins browsers may call the GraphQL endpoint of an application from,
+/// which are optional.
+fn v5_to_v6(mut artefact: Value) -> Result<Value, String> {
+    artefact["version"] = Value::from(6);
+    Ok(artefact)
+}
+
+/// Version 7 stores the uuids of applications and actions lowercase, so they can be looked up
+/// by comparing them to the lowercased uuid.
+fn v6_to_v7(mut artefact: Value) -> Result<Value, String> {
+    artefact
+        .pointer_mut("/cloud/applications")
+        .and_then(Value::as_array_mut)
+        .ok_or_else(|| "Artefact has no applications".to_string())?
+        .iter_mut()
+        .for_each(lowercase_uuids);
+    artefact["version"] = Value::from(7);
+    Ok(artefact)
+}
+
+/// Lowercase the uuid of an application and the uuids of its actions, as they are stored.
+pub fn lowercase_uuids(application: &mut Value) {
+    lowercase_uuid(application);
+    if let Some(actions) = application.get_mut("actions").and_then(Value::as_array_mut) {
+        actions.iter_mut().for_each(lowercase_uuid);
+    }
+}
+
+fn lowercase_uuid(value: &mut Value) {
+    if let Some(uuid) = value.get_mut("uuid") {
+        if let Some(lowercase) = uuid.as_str().map(str::to_lowercase) {
+            *uuid = Value::from(lowercase);
+        }
+    }
+}
+
+/// The version of an artefact, artefacts without a version are version 1.
+pub fn version_of(artefact: &Value) -> Result<u64, String> {
+    match artefact.get("version") {
+        None => Ok(1),
+        Some(version) => version
+            .as_u64()
+            .filter(|version| *version > 0)
+            .ok_or_else(|| format!("Invalid artefact version: {}", version)),
+    }
+}
+
+/// Upgrade an artefact to the current version. Artefacts from a newer version of this component
+/// are rejected, they can't be read without losing data.
+pub fn upgrade(mut artefact: Value) -> Result<Value, String> {
+    let version = version_of(&artefact)?;
+    if version > CURRENT_VERSION {
+        return Err(format!(
+            "Artefact version {} is newer than the supported version {}",
+            version, CURRENT_VERSION
+        ));
+    }
+    for migration in &MIGRATIONS[(version - 1) as usize..] {
+        artefact = migration(artefact)?;
+    }
+    Ok(artefact)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_upgrade_unversioned_artefact() {
+        let artefact = json!({ "cloud": { "name": "BettyBlocks", "applications": [] } });
+        let upgraded = upgrade(artefact).unwrap();
+        assert_eq!(
+            upgraded,
+            json!({ "version": CURRENT_VERSION, "cloud": { "name": "BettyBlocks", "applications": [], "components": [] } })
+        );
+    }
+
+    #[test]
+    fn test_upgrade_v2_adds_components() {
+        let artefact =
+            json!({ "version": 2, "cloud": { "name": "BettyBlocks", "applications": [] } });
+        let upgraded = upgrade(artefact).unwrap();
+        assert_eq!(upgraded["version"], json!(CURRENT_VERSION));
+        assert_eq!(upgraded["cloud"]["components"], json!([]));
+    }
+
+    #[test]
+    fn test_upgrade_v3_names_actions() {
+        let artefact = json!({ "version": 3, "cloud": { "name": "BettyBlocks", "components": [], "applications": [{
+            "uuid": "693b22e983fb46afa4eb353d82ece4bb",
+            "name": "MyApp",
+            "actions": [
+                { "uuid": "B1D7E3A0-C6F9-4B0E-9D5F-2A4C8E7B6D51" },
+                { "uuid": "0b1d7e3a0c6f94b0e9d5f2a4c8e7b6d5", "name": "sendMail" }
+            ]
+        }] } });
+        let upgraded = upgrade(artefact).unwrap();
+        let actions = &upgraded["cloud"]["applications"][0]["actions"];
+        assert_eq!(
+            actions[0]["name"],
+            json!("action_b1d7e3a0c6f94b0e9d5f2a4c8e7b6d51")
+        );
+        assert_eq!(actions[1]["name"], json!("sendMail"));
+    }
+
+    #[test]
+    fn test_upgrade_v6_lowercases_uuids() {
+        let artefact = json!({ "version": 6, "cloud": { "name": "BettyBlocks", "components": [], "applications": [{
+            "uuid": "693B22E983FB46AFA4EB353D82ECE4BB",
+            "name": "MyApp",
+            "actions": [{ "uuid": "B1D7E3A0-C6F9-4B0E-9D5F-2A4C8E7B6D51", "name": "sendMail" }]
+        }] } });
+        let upgraded = upgrade(artefact).unwrap();
+        let application = &upgraded["cloud"]["applications"][0];
+        assert_eq!(
+            application["uuid"],
+            json!("693b22e983fb46afa4eb353d82ece4bb")
+        );
+        assert_eq!(
+            application["actions"][0]["uuid"],
+            json!("b1d7e3a0-c6f9-4b0e-9d5f-2a4c8e7b6d51")
+        );
+    }
+
+    #[test]
+    fn test_upgrade_current_artefact_is_unchanged() {
+        let artefact = json!({ "version": CURRENT_VERSION, "cloud": { "name": "BettyBlocks", "applications": [], "components": [] } });
+        assert_eq!(upgrade(artefact.clone()).unwrap(), artefact);
+    }
+
+    #[test]
+    fn test_upgrade_rejects_future_version() {
+        let artefact = json!({ "version": CURRENT_VERSION + 1, "cloud": {} });
+        assert_eq!(
+            upgrade(artefact).err(),
+            Some(format!(
+                "Artefact version {} is newer than the supported version {}",
+                CURRENT_VERSION + 1,
+                CURRENT_VERSION
+            ))
+        );
+    }
+
+    #[test]
+    fn test_upgrade_rejects_invalid_version() {
+        assert!(upgrade(json!({ "version": "two", "cloud": {} })).is_err());
+        assert!(upgrade(json!({ "version": 0, "cloud": {} })).is_err());
+    }
+
+    #[test]
+    fn test_upgrade_rejects_artefact_without_cloud() {
+        assert!(upgrade(json!({ "name": "BettyBlocks" })).is_err());
+    }
+}
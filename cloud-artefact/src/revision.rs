@@ -0,0 +1,82 @@
+//! Optimistic concurrency for writes to the artefact.
+//!
+//! The stored artefact carries a revision, counting the writes to it. A write based on revision
+//! `n` first claims the `claim.<n>` key with an atomic increment. Only the first writer gets the
+//! claim and stores revision `n + 1`, any other write based on revision `n` lost the race and is
+//! rejected, instead of silently overwriting the other write.
+//!
+//! The history and the application index are written after the artefact. Every write appends
+//! them under keys of its own, tagged with its revision, and readers go by the highest revision,
+//! so a write that finishes after a newer one can't overwrite what the newer one wrote.
+//!
+//! When the artefact is gone its claims remain. Writes then continue from the revision of the
+//! application index, whose claim is still free. Removing the index as well leaves claims of
+//! revisions that are written again, so the `claim.*` keys have to be removed along with it.
+
+use serde_json::Value;
+
+use crate::storage::Storage;
+
+/// Claims of older revisions are removed, a write based on a revision this old is not detected
+const KEEP_CLAIMS: u64 = 64;
+
+pub enum ClaimError {
+    /// Another write based on the same revision claimed it first
+    Conflict,
+    Storage(String),
+}
+
+fn claim_key(revision: u64) -> String {
+    format!("claim.{}", revision)
+}
+
+/// Claim the write after `base`, returning the revision to store.
+pub fn claim(storage: &Storage, base: u64) -> Result<u64, ClaimError> {
+    let claims = storage
+        .increment(&claim_key(base), 1)
+        .map_err(ClaimError::Storage)?;
+    if claims != 1 {
+        return Err(ClaimError::Conflict);
+    }
+    if base >= KEEP_CLAIMS {
+        // nothing depends on removing it, a failure only leaves a stale key behind
+        let _ = storage.delete(&claim_key(base - KEEP_CLAIMS));
+    }
+    Ok(base + 1)
+}
+
+/// Give up a claim when the write it was claimed for failed, so the revision can be written
+/// again.
+pub fn release(storage: &Storage, base: u64) {
+    let _ = storage.delete(&claim_key(base));
+}
+
+/// Take the revision out of an artefact. Artefacts stored before revisions were counted have
+/// none, they are revision 0.
+pub fn split(mut artefact: Value) -> (Value, Option<u64>) {
+    let revision = artefact
+        .as_object_mut()
+        .and_then(|root| root.remove("revision"))
+        .and_then(|revision| revision.as_u64());
+    (artefact, revision)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_split_revision() {
+        let (artefact, revision) = split(json!({ "version": 3, "revision": 7, "cloud": {} }));
+        assert_eq!(revision, Some(7));
+        assert_eq!(artefact, json!({ "version": 3, "cloud": {} }));
+    }
+
+    #[test]
+    fn test_split_without_revision() {
+        let (artefact, revision) = split(json!({ "version": 3, "cloud": {} }));
+        assert_eq!(revision, None);
+        assert_eq!(artefact, json!({ "version": 3, "cloud": {} }));
+    }
+}
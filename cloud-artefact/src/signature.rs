@@ -0,0 +1,375 @@
+//! Verification of signed artefact uploads.
+//!
+//! Uploads carry an HMAC-SHA256 in the `X-Artefact-Signature` header, as `sha256=<hex digest>`,
+//! of the unix time in the `X-Artefact-Timestamp` header, a `.` and the exact request body. The
+//! key is shared with the uploader through the `signing_key` config of this component. Only
+//! uploads with a valid signature are stored. Requests without a body, like a DELETE, sign their
+//! path and query instead.
+//!
+//! The timestamp has to be within [`MAX_SKEW`] of the time the request is received, so a
+//! captured request can't be replayed later. Within that time every signature is only accepted
+//! once: it is claimed with an atomic increment of its own `signature.<digest>` key, like
+//! revisions are claimed, so a captured request replayed right away is rejected too.
+//!
+//! Reads are not signed, they are authorized with the `admin_token` config as a bearer token.
+
+use hmac::{Hmac, Mac};
+use serde_derive::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::storage::KeyValue;
+
+pub const HEADER: &str = "x-artefact-signature";
+pub const TIMESTAMP_HEADER: &str = "x-artefact-timestamp";
+
+/// How far the signed timestamp may be off from the time a request is received, in seconds
+pub const MAX_SKEW: u64 = 300;
+
+/// Config key holding the shared signing key
+pub const KEY_CONFIG: &str = "signing_key";
+
+const SCHEME: &str = "sha256=";
+
+/// Used signatures remembered in order, older ones are forgotten once they expired
+const KEEP_SEEN: u64 = 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum SignatureError {
+    /// The upload has no signature header
+    Missing,
+    /// The signature header isn't a `sha256=<hex digest>`
+    Malformed,
+    /// The signature doesn't match the body, it was tampered with or signed with another key
+    Mismatch,
+    /// The upload has no timestamp header
+    MissingTimestamp,
+    /// The timestamp header isn't a unix time in seconds
+    MalformedTimestamp,
+    /// The timestamp is further off than [`MAX_SKEW`], the request may be replayed
+    Expired,
+    /// The signature was used before, the request is replayed
+    Replayed,
+}
+
+impl std::fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignatureError::Missing => {
+                write!(f, "Artefact is not signed, missing {} header", HEADER)
+            }
+            SignatureError::Malformed => {
+                write!(
+                    f,
+                    "Malformed {} header, expected {}<hex digest>",
+                    HEADER, SCHEME
+                )
+            }
+            SignatureError::Mismatch => write!(f, "Artefact signature does not match"),
+            SignatureError::MissingTimestamp => {
+                write!(f, "Artefact signature has no {} header", TIMESTAMP_HEADER)
+            }
+            SignatureError::MalformedTimestamp => {
+                write!(
+                    f,
+                    "Malformed {} header, expected a unix time in seconds",
+                    TIMESTAMP_HEADER
+                )
+            }
+            SignatureError::Expired => write!(
+                f,
+                "Artefact signature expired, {} is more than {} seconds off",
+                TIMESTAMP_HEADER, MAX_SKEW
+            ),
+            SignatureError::Replayed => write!(f, "Artefact signature was used before"),
+        }
+    }
+}
+
+/// A valid signature
+#[derive(Debug, PartialEq)]
+pub struct Signed {
+    digest: Vec<u8>,
+    /// Unix time in seconds
+    signed_at: u64,
+}
+
+/// Verify the signature and timestamp headers of an upload against its body, received at `now`
+/// in seconds since the unix epoch.
+pub fn verify(
+    key: &[u8],
+    signature: Option<&[u8]>,
+    timestamp: Option<&[u8]>,
+    body: &[u8],
+    now: u64,
+) -> Result<Signed, SignatureError> {
+    let signature = signature.ok_or(SignatureError::Missing)?;
+    let digest = std::str::from_utf8(signature)
+        .ok()
+        .and_then(|signature| signature.trim().strip_prefix(SCHEME))
+        .and_then(|digest| hex::decode(digest).ok())
+        .ok_or(SignatureError::Malformed)?;
+    let timestamp = timestamp.ok_or(SignatureError::MissingTimestamp)?;
+    let signed_at: u64 = std::str::from_utf8(timestamp)
+        .ok()
+        .and_then(|timestamp| timestamp.trim().parse().ok())
+        .ok_or(SignatureError::MalformedTimestamp)?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(signed_at.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    // compares in constant time, so the digest can't be guessed byte by byte
+    mac.verify_slice(&digest)
+        .map_err(|_| SignatureError::Mismatch)?;
+    if now.abs_diff(signed_at) > MAX_SKEW {
+        return Err(SignatureError::Expired);
+    }
+    Ok(Signed { digest, signed_at })
+}
+
+/// A used signature, remembered until it expired
+#[derive(Serialize, Deserialize)]
+struct Seen {
+    key: String,
+    signed_at: u64,
+}
+
+fn seen_key(n: u64) -> String {
+    format!("signature.seen.{}", n)
+}
+
+/// Claim a valid signature at `now`, failing with [`SignatureError::Replayed`] when it was
+/// claimed before.
+///
+/// Claims are listed in order under `signature.seen.<n>` keys. Claiming one forgets the claim
+/// [`KEEP_SEEN`] before it once it expired, a claim that is still valid is kept.
+pub fn claim(
+    storage: &impl KeyValue,
+    signed: &Signed,
+    now: u64,
+) -> Result<Result<(), SignatureError>, String> {
+    let key = format!("signature.{}", hex::encode(&signed.digest));
+    if storage.increment(&key, 1)? > 1 {
+        return Ok(Err(SignatureError::Replayed));
+    }
+    let seen = Seen {
+        key,
+        signed_at: signed.signed_at,
+    };
+    let n = storage.increment("signature.seq", 1)?;
+    let json = serde_json::to_vec(&seen).map_err(|err| err.to_string())?;
+    storage.set(&seen_key(n), &json)?;
+    if n > KEEP_SEEN {
+        // nothing depends on forgetting it, a failure only leaves stale keys behind
+        let _ = forget(storage, n - KEEP_SEEN, now);
+    }
+    Ok(Ok(()))
+}
+
+fn forget(storage: &impl KeyValue, n: u64, now: u64) -> Result<(), String> {
+    let Some(json) = storage.get(&seen_key(n))? else {
+        return Ok(());
+    };
+    let seen: Seen = serde_json::from_slice(&json).map_err(|err| err.to_string())?;
+    if now.abs_diff(seen.signed_at) > MAX_SKEW {
+        storage.delete(&seen.key)?;
+        storage.delete(&seen_key(n))?;
+    }
+    Ok(())
+}
+
+/// Compare a token to the expected one without leaking how much of it matched.
+pub fn token_matches(expected: &str, token: &str) -> bool {
+    expected.len() == token.len()
+        && expected
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    const KEY: &[u8] = b"artefact-signing-key";
+    const BODY: &[u8] = br#"{"version": 3, "cloud": {}}"#;
+    const NOW: u64 = 1_760_000_000;
+    const TIMESTAMP: &[u8] = b"1760000000";
+
+    fn sign(key: &[u8], timestamp: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        mac.update(timestamp);
+        mac.update(b".");
+        mac.update(body);
+        format!("{}{}", SCHEME, hex::encode(mac.finalize().into_bytes())).into_bytes()
+    }
+
+    #[test]
+    fn test_valid_signature() {
+        let signature = sign(KEY, TIMESTAMP, BODY);
+        assert_eq!(
+            verify(KEY, Some(&signature), Some(TIMESTAMP), BODY, NOW)
+                .map(|signed| signed.signed_at),
+            Ok(NOW)
+        );
+    }
+
+    #[test]
+    fn test_missing_signature() {
+        assert_eq!(
+            verify(KEY, None, Some(TIMESTAMP), BODY, NOW),
+            Err(SignatureError::Missing)
+        );
+    }
+
+    #[test]
+    fn test_malformed_signature() {
+        assert_eq!(
+            verify(KEY, Some(b"md5=abc"), Some(TIMESTAMP), BODY, NOW),
+            Err(SignatureError::Malformed)
+        );
+        assert_eq!(
+            verify(KEY, Some(b"sha256=not-hex"), Some(TIMESTAMP), BODY, NOW),
+            Err(SignatureError::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_tampered_body() {
+        let signature = sign(KEY, TIMESTAMP, BODY);
+        assert_eq!(
+            verify(
+                KEY,
+                Some(&signature),
+                Some(TIMESTAMP),
+                br#"{"version": 3, "cloud": null}"#,
+                NOW
+            ),
+            Err(SignatureError::Mismatch)
+        );
+    }
+
+    #[test]
+    fn test_other_key() {
+        let signature = sign(b"another-key", TIMESTAMP, BODY);
+        assert_eq!(
+            verify(KEY, Some(&signature), Some(TIMESTAMP), BODY, NOW),
+            Err(SignatureError::Mismatch)
+        );
+    }
+
+    #[test]
+    fn test_missing_or_malformed_timestamp() {
+        let signature = sign(KEY, TIMESTAMP, BODY);
+        assert_eq!(
+            verify(KEY, Some(&signature), None, BODY, NOW),
+            Err(SignatureError::MissingTimestamp)
+        );
+        assert_eq!(
+            verify(KEY, Some(&signature), Some(b"yesterday"), BODY, NOW),
+            Err(SignatureError::MalformedTimestamp)
+        );
+    }
+
+    #[test]
+    fn test_replayed_signature_expires() {
+        let signature = sign(KEY, TIMESTAMP, BODY);
+        assert!(verify(KEY, Some(&signature), Some(TIMESTAMP), BODY, NOW + MAX_SKEW).is_ok());
+        assert_eq!(
+            verify(
+                KEY,
+                Some(&signature),
+                Some(TIMESTAMP),
+                BODY,
+                NOW + MAX_SKEW + 1
+            ),
+            Err(SignatureError::Expired)
+        );
+        assert_eq!(
+            verify(
+                KEY,
+                Some(&signature),
+                Some(TIMESTAMP),
+                BODY,
+                NOW - MAX_SKEW - 1
+            ),
+            Err(SignatureError::Expired)
+        );
+    }
+
+    #[test]
+    fn test_signature_covers_timestamp() {
+        // moving the timestamp forward to replay the request breaks the signature
+        let signature = sign(KEY, TIMESTAMP, BODY);
+        assert_eq!(
+            verify(KEY, Some(&signature), Some(b"1760000600"), BODY, NOW + 600),
+            Err(SignatureError::Mismatch)
+        );
+    }
+
+    #[test]
+    fn test_replayed_signature_is_rejected() {
+        let storage = MemoryStorage::default();
+        let signature = sign(KEY, TIMESTAMP, BODY);
+        let signed = verify(KEY, Some(&signature), Some(TIMESTAMP), BODY, NOW).unwrap();
+        assert_eq!(claim(&storage, &signed, NOW), Ok(Ok(())));
+        // the same request again within the allowed skew
+        let signed = verify(KEY, Some(&signature), Some(TIMESTAMP), BODY, NOW + 1).unwrap();
+        assert_eq!(
+            claim(&storage, &signed, NOW + 1),
+            Ok(Err(SignatureError::Replayed))
+        );
+        // another request signed at the same time
+        let other = br#"{"version": 3, "cloud": {"name": "Other"}}"#;
+        let signature = sign(KEY, TIMESTAMP, other);
+        let signed = verify(KEY, Some(&signature), Some(TIMESTAMP), other, NOW).unwrap();
+        assert_eq!(claim(&storage, &signed, NOW), Ok(Ok(())));
+    }
+
+    #[test]
+    fn test_expired_claims_are_forgotten() {
+        let storage = MemoryStorage::default();
+        let signed_at = |n: u64| NOW + n * MAX_SKEW;
+        let signed = |n: u64| Signed {
+            digest: n.to_be_bytes().to_vec(),
+            signed_at: signed_at(n),
+        };
+        for n in 0..KEEP_SEEN {
+            claim(&storage, &signed(n), signed_at(n)).unwrap().unwrap();
+        }
+        let first = format!("signature.{}", hex::encode(signed(0).digest));
+        assert!(storage.get(&first).unwrap().is_some());
+        claim(&storage, &signed(KEEP_SEEN), signed_at(KEEP_SEEN))
+            .unwrap()
+            .unwrap();
+        assert_eq!(storage.get(&first), Ok(None));
+        assert_eq!(storage.get(&seen_key(1)), Ok(None));
+    }
+
+    #[test]
+    fn test_valid_claims_are_kept() {
+        let storage = MemoryStorage::default();
+        let signed = |n: u64| Signed {
+            digest: n.to_be_bytes().to_vec(),
+            signed_at: NOW,
+        };
+        for n in 0..=KEEP_SEEN {
+            claim(&storage, &signed(n), NOW).unwrap().unwrap();
+        }
+        // a burst of requests doesn't make room to replay the first one
+        assert_eq!(
+            claim(&storage, &signed(0), NOW),
+            Ok(Err(SignatureError::Replayed))
+        );
+    }
+
+    #[test]
+    fn test_token_matches() {
+        assert!(token_matches("admin-token", "admin-token"));
+        assert!(!token_matches("admin-token", "admin-tokem"));
+        assert!(!token_matches("admin-token", "admin"));
+        assert!(!token_matches("admin-token", ""));
+    }
+}
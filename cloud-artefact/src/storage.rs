@@ -0,0 +1,224 @@
+//! Where the artefact is stored.
+//!
+//! The keyvalue bucket and the prefix of every key come from the config of this component, so
+//! several clouds or environments, like staging and production, can share one store:
+//!
+//! | Config       | Default   |                                              |
+//! | ------------ | --------- | -------------------------------------------- |
+//! | `bucket`     | `default` | Bucket the artefact is stored in             |
+//! | `key_prefix` |           | Prefix of every key, e.g. the name of the cloud |
+//! | `namespace`  |           | Namespace within the prefix, e.g. `staging`  |
+//!
+//! With prefix `bettyblocks` and namespace `staging` the artefact is stored under
+//! `bettyblocks.staging.cloud`. All keys are built by [`Storage`], the other modules only name
+//! the key within the namespace.
+
+use crate::wasi::keyvalue::{atomics, store};
+
+pub const BUCKET_CONFIG: &str = "bucket";
+pub const KEY_PREFIX_CONFIG: &str = "key_prefix";
+pub const NAMESPACE_CONFIG: &str = "namespace";
+
+const DEFAULT_BUCKET: &str = "default";
+
+/// Key of the stored artefact
+pub const CLOUD_KEY: &str = "cloud";
+
+#[derive(Debug, PartialEq)]
+pub struct StorageConfig {
+    pub bucket: String,
+    /// Prefix and namespace joined, empty when neither is configured
+    pub key_prefix: String,
+}
+
+impl StorageConfig {
+    /// Read the storage config with `get`, which looks up a config key.
+    pub fn from_config(
+        get: impl Fn(&str) -> Result<Option<String>, String>,
+    ) -> Result<StorageConfig, String> {
+        let bucket = get(BUCKET_CONFIG)?
+            .filter(|bucket| !bucket.is_empty())
+            .unwrap_or_else(|| DEFAULT_BUCKET.to_string());
+        let mut parts = vec![];
+        for config in [KEY_PREFIX_CONFIG, NAMESPACE_CONFIG] {
+            if let Some(part) = get(config)?.filter(|part| !part.is_empty()) {
+                if !part.chars().all(is_key_char) {
+                    return Err(format!(
+                        "Invalid `{}` config `{}`, only letters, digits, `-`, `_`, `/` and `=` are allowed",
+                        config, part
+                    ));
+                }
+                parts.push(part);
+            }
+        }
+        Ok(StorageConfig {
+            bucket,
+            key_prefix: parts.join("."),
+        })
+    }
+
+    /// The full key of `name` within the configured prefix and namespace
+    pub fn key(&self, name: &str) -> String {
+        if self.key_prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", self.key_prefix, name)
+        }
+    }
+}
+
+/// Characters a part of a key may contain, keys are separated by dots
+fn is_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '/' | '=')
+}
+
+/// The configured bucket, with every key within the configured prefix and namespace.
+pub struct Storage {
+    config: StorageConfig,
+    bucket: store::Bucket,
+}
+
+impl Storage {
+    /// Open the bucket from the config of this component.
+    pub fn open() -> Result<Storage, String> {
+        let config = StorageConfig::from_config(|key| {
+            crate::wasi::config::runtime::get(key)
+                .map_err(|err| format!("Failed to read the `{}` config: {:?}", key, err))
+        })?;
+        let bucket = store::open(&config.bucket)
+            .map_err(|err| format!("Failed to open bucket `{}`: {}", config.bucket, err))?;
+        Ok(Storage { config, bucket })
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<Vec<u8>>, String> {
+        self.bucket
+            .get(&self.config.key(name))
+            .map_err(|err| format!("Error: {}", err))
+    }
+
+    pub fn set(&self, name: &str, value: &[u8]) -> Result<(), String> {
+        self.bucket
+            .set(&self.config.key(name), value)
+            .map_err(|err| format!("Error: {}", err))
+    }
+
+    pub fn delete(&self, name: &str) -> Result<(), String> {
+        self.bucket
+            .delete(&self.config.key(name))
+            .map_err(|err| format!("Error: {}", err))
+    }
+
+    pub fn increment(&self, name: &str, delta: u64) -> Result<u64, String> {
+        atomics::increment(&self.bucket, &self.config.key(name), delta)
+            .map_err(|err| format!("Error: {}", err))
+    }
+}
+
+/// The operations on stored keys, so modules writing more than one key can be tested without a
+/// bucket.
+pub trait KeyValue {
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, String>;
+    fn set(&self, name: &str, value: &[u8]) -> Result<(), String>;
+    fn delete(&self, name: &str) -> Result<(), String>;
+    fn increment(&self, name: &str, delta: u64) -> Result<u64, String>;
+}
+
+impl KeyValue for Storage {
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, String> {
+        Storage::get(self, name)
+    }
+
+    fn set(&self, name: &str, value: &[u8]) -> Result<(), String> {
+        Storage::set(self, name, value)
+    }
+
+    fn delete(&self, name: &str) -> Result<(), String> {
+        Storage::delete(self, name)
+    }
+
+    fn increment(&self, name: &str, delta: u64) -> Result<u64, String> {
+        Storage::increment(self, name, delta)
+    }
+}
+
+/// Keys kept in memory, for tests
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryStorage(std::cell::RefCell<std::collections::HashMap<String, Vec<u8>>>);
+
+#[cfg(test)]
+impl KeyValue for MemoryStorage {
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.0.borrow().get(name).cloned())
+    }
+
+    fn set(&self, name: &str, value: &[u8]) -> Result<(), String> {
+        self.0.borrow_mut().insert(name.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> Result<(), String> {
+        self.0.borrow_mut().remove(name);
+        Ok(())
+    }
+
+    fn increment(&self, name: &str, delta: u64) -> Result<u64, String> {
+        let mut keys = self.0.borrow_mut();
+        let value = keys
+            .get(name)
+            .and_then(|value| std::str::from_utf8(value).ok()?.parse::<u64>().ok())
+            .unwrap_or(0)
+            + delta;
+        keys.insert(name.to_string(), value.to_string().into_bytes());
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config(values: &[(&str, &str)]) -> Result<StorageConfig, String> {
+        let values: HashMap<_, _> = values.iter().copied().collect();
+        StorageConfig::from_config(|key| Ok(values.get(key).map(|value| value.to_string())))
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = config(&[]).unwrap();
+        assert_eq!(config.bucket, "default");
+        assert_eq!(config.key(CLOUD_KEY), "cloud");
+    }
+
+    #[test]
+    fn test_prefix_and_namespace() {
+        let config = config(&[
+            ("bucket", "CLOUDARTEFACT"),
+            ("key_prefix", "bettyblocks"),
+            ("namespace", "staging"),
+        ])
+        .unwrap();
+        assert_eq!(config.bucket, "CLOUDARTEFACT");
+        assert_eq!(config.key(CLOUD_KEY), "bettyblocks.staging.cloud");
+        assert_eq!(config.key("history.1"), "bettyblocks.staging.history.1");
+    }
+
+    #[test]
+    fn test_namespace_without_prefix() {
+        let config = config(&[("namespace", "production"), ("key_prefix", "")]).unwrap();
+        assert_eq!(config.key(CLOUD_KEY), "production.cloud");
+    }
+
+    #[test]
+    fn test_rejects_invalid_key_parts() {
+        assert!(config(&[("namespace", "staging.eu")]).is_err());
+        assert!(config(&[("key_prefix", "betty blocks")]).is_err());
+    }
+
+    #[test]
+    fn test_config_errors_are_returned() {
+        let result = StorageConfig::from_config(|_| Err("unavailable".to_string()));
+        assert_eq!(result, Err("unavailable".to_string()));
+    }
+}
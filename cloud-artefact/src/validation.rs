@@ -0,0 +1,696 @@
+//! Validation of uploaded artefacts.
+//!
+//! The artefact format is published as a JSON Schema in `artefact.schema.json`. Uploads are
+//! checked against the same rules here, plus the rules a schema can't express like unique
+//! UUIDs and actions referencing known components. All violations are collected so the
+//! uploader can fix them in one go.
+
+use std::collections::HashSet;
+
+use serde_derive::Serialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::migrate::CURRENT_VERSION;
+
+/// The published JSON Schema of the artefact format.
+pub const SCHEMA: &str = include_str!("../artefact.schema.json");
+
+pub const AUTH_KINDS: [&str; 2] = ["None", "Jwt"];
+pub const SCOPES: [&str; 2] = ["Public", "Private"];
+/// Types of the JSON Schema subset describing the input and output of actions
+pub const FIELD_TYPES: [&str; 6] = ["string", "integer", "number", "boolean", "object", "array"];
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Violation {
+    /// JSON path of the offending value, e.g. `$.cloud.applications[0].uuid`
+    pub path: String,
+    pub message: String,
+}
+
+impl Violation {
+    pub fn new(path: &str, message: impl Into<String>) -> Self {
+        Violation {
+            path: path.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+/// Check an artefact, which must already be upgraded to the current version.
+pub fn violations(artefact: &Value) -> Vec<Violation> {
+    let mut checker = Checker::default();
+    checker.root(artefact);
+    checker.violations
+}
+
+#[derive(Default)]
+struct Checker {
+    violations: Vec<Violation>,
+    application_uuids: HashSet<String>,
+    action_uuids: HashSet<String>,
+}
+
+impl Checker {
+    fn violation(&mut self, path: &str, message: impl Into<String>) {
+        self.violations.push(Violation::new(path, message));
+    }
+
+    fn root(&mut self, root: &Value) {
+        let Some(root) = self.object("$", root, &["version", "cloud"]) else {
+            return;
+        };
+        if root.get("version").and_then(Value::as_u64) != Some(CURRENT_VERSION) {
+            self.violation("$.version", format!("must be {}", CURRENT_VERSION));
+        }
+        match root.get("cloud") {
+            Some(cloud) => self.cloud("$.cloud", cloud),
+            None => self.violation("$.cloud", "is required"),
+        }
+    }
+
+    fn cloud(&mut self, path: &str, cloud: &Value) {
+        let Some(cloud) = self.object(path, cloud, &["name", "components", "applications"]) else {
+            return;
+        };
+        self.name(&format!("{}.name", path), cloud.get("name"));
+
+        let mut components = HashSet::new();
+        let components_path = format!("{}.components", path);
+        for (index, component) in self.array(&components_path, cloud.get("components")) {
+            let component_path = format!("{}[{}]", components_path, index);
+            match component.as_str() {
+                Some("") | None => self.violation(&component_path, "must be a non-empty string"),
+                Some(name) if !components.insert(name) => {
+                    self.violation(&component_path, format!("duplicate component `{}`", name))
+                }
+                Some(_) => {}
+            }
+        }
+
+        let applications_path = format!("{}.applications", path);
+        for (index, application) in self.array(&applications_path, cloud.get("applications")) {
+            self.application(
+                &format!("{}[{}]", applications_path, index),
+                application,
+                &components,
+            );
+        }
+    }
+
+    fn application(&mut self, path: &str, application: &Value, components: &HashSet<&str>) {
+        let Some(application) = self.object(
+            path,
+            application,
+            &["uuid", "name", "actions", "queries", "origins"],
+        ) else {
+            return;
+        };
+        if let Some(uuid) = self.uuid(&format!("{}.uuid", path), application.get("uuid")) {
+            if !self.application_uuids.insert(uuid.to_lowercase()) {
+                self.violation(
+                    &format!("{}.uuid", path),
+                    format!("duplicate application uuid `{}`", uuid),
+                );
+            }
+        }
+        self.name(&format!("{}.name", path), application.get("name"));
+
+        let actions_path = format!("{}.actions", path);
+        let mut names = HashSet::new();
+        for (index, action) in self.array(&actions_path, application.get("actions")) {
+            self.action(
+                &format!("{}[{}]", actions_path, index),
+                action,
+                components,
+                &mut names,
+            );
+        }
+
+        if application.contains_key("queries") {
+            let queries_path = format!("{}.queries", path);
+            let mut hashes = HashSet::new();
+            for (index, query) in self.array(&queries_path, application.get("queries")) {
+                self.persisted_query(&format!("{}[{}]", queries_path, index), query, &mut hashes);
+            }
+        }
+
+        if application.contains_key("origins") {
+            let origins_path = format!("{}.origins", path);
+            let mut origins = HashSet::new();
+            for (index, origin) in self.array(&origins_path, application.get("origins")) {
+                let origin_path = format!("{}[{}]", origins_path, index);
+                match origin.as_str() {
+                    Some(origin) if !is_origin(origin) => self.violation(
+                        &origin_path,
+                        format!("`{}` is not an origin like `https://example.com`", origin),
+                    ),
+                    Some(origin) if !origins.insert(origin.to_lowercase()) => {
+                        self.violation(&origin_path, format!("duplicate origin `{}`", origin))
+                    }
+                    Some(_) => {}
+                    None => self.violation(&origin_path, "must be a string"),
+                }
+            }
+        }
+    }
+
+    /// Check a persisted query is identified by the SHA-256 of its document
+    fn persisted_query<'a>(&mut self, path: &str, query: &'a Value, hashes: &mut HashSet<&'a str>) {
+        let Some(query) = self.object(path, query, &["sha256", "query"]) else {
+            return;
+        };
+        let document = match query.get("query") {
+            Some(Value::String(document)) if !document.is_empty() => Some(document),
+            Some(_) => {
+                self.violation(&format!("{}.query", path), "must be a non-empty string");
+                None
+            }
+            None => {
+                self.violation(&format!("{}.query", path), "is required");
+                None
+            }
+        };
+        let sha256_path = format!("{}.sha256", path);
+        match query.get("sha256") {
+            Some(Value::String(sha256)) if is_sha256(sha256) => {
+                if !hashes.insert(sha256) {
+                    self.violation(&sha256_path, format!("duplicate query `{}`", sha256));
+                } else if document
+                    .is_some_and(|document| *sha256 != hex::encode(Sha256::digest(document)))
+                {
+                    self.violation(&sha256_path, "is not the SHA-256 of the query");
+                }
+            }
+            Some(Value::String(sha256)) => self.violation(
+                &sha256_path,
+                format!("`{}` is not a lowercase hex SHA-256", sha256),
+            ),
+            Some(_) => self.violation(&sha256_path, "must be a string"),
+            None => self.violation(&sha256_path, "is required"),
+        }
+    }
+
+    fn action<'a>(
+        &mut self,
+        path: &str,
+        action: &'a Value,
+        components: &HashSet<&str>,
+        names: &mut HashSet<&'a str>,
+    ) {
+        let Some(action) = self.object(
+            path,
+            action,
+            &[
+                "uuid",
+                "name",
+                "auth",
+                "scope",
+                "etag",
+                "component",
+                "input",
+                "output",
+            ],
+        ) else {
+            return;
+        };
+        if let Some(uuid) = self.uuid(&format!("{}.uuid", path), action.get("uuid")) {
+            if !self.action_uuids.insert(uuid.to_lowercase()) {
+                self.violation(
+                    &format!("{}.uuid", path),
+                    format!("duplicate action uuid `{}`", uuid),
+                );
+            }
+        }
+        let name_path = format!("{}.name", path);
+        if let Some(name) = self.identifier(&name_path, action.get("name")) {
+            if !names.insert(name) {
+                self.violation(&name_path, format!("duplicate action name `{}`", name));
+            }
+        }
+        self.one_of(&format!("{}.auth", path), action.get("auth"), &AUTH_KINDS);
+        self.one_of(&format!("{}.scope", path), action.get("scope"), &SCOPES);
+        if !matches!(action.get("etag"), Some(Value::String(_))) {
+            self.violation(&format!("{}.etag", path), "must be a string");
+        }
+        match action.get("component") {
+            None => {}
+            Some(Value::String(component)) if components.contains(component.as_str()) => {}
+            Some(Value::String(component)) => self.violation(
+                &format!("{}.component", path),
+                format!(
+                    "unknown component `{}`, it is not listed in $.cloud.components",
+                    component
+                ),
+            ),
+            Some(_) => self.violation(&format!("{}.component", path), "must be a string"),
+        }
+        if let Some(input) = action.get("input") {
+            let input_path = format!("{}.input", path);
+            if input.get("type").and_then(Value::as_str) == Some("object") {
+                self.field_schema(&input_path, input);
+            } else {
+                self.violation(
+                    &format!("{}.type", input_path),
+                    "must be `object`, its properties are the arguments of the action",
+                );
+            }
+        }
+        if let Some(output) = action.get("output") {
+            self.field_schema(&format!("{}.output", path), output);
+        }
+    }
+
+    /// Check the JSON Schema subset describing the input or output of an action
+    fn field_schema(&mut self, path: &str, schema: &Value) {
+        let Some(schema) = self.object(
+            path,
+            schema,
+            &["type", "description", "properties", "required", "items"],
+        ) else {
+            return;
+        };
+        if !matches!(schema.get("description"), None | Some(Value::String(_))) {
+            self.violation(&format!("{}.description", path), "must be a string");
+        }
+        let type_path = format!("{}.type", path);
+        self.one_of(&type_path, schema.get("type"), &FIELD_TYPES);
+        match schema.get("type").and_then(Value::as_str) {
+            Some("object") => {
+                let properties_path = format!("{}.properties", path);
+                let properties = match schema.get("properties") {
+                    Some(Value::Object(properties)) if !properties.is_empty() => properties,
+                    Some(_) => {
+                        self.violation(&properties_path, "must be a non-empty object");
+                        return;
+                    }
+                    None => {
+                        self.violation(&properties_path, "is required");
+                        return;
+                    }
+                };
+                for (name, property) in properties {
+                    let property_path = format!("{}.{}", properties_path, name);
+                    if !is_identifier(name) {
+                        self.violation(
+                            &property_path,
+                            format!("`{}` is not a valid GraphQL name", name),
+                        );
+                    }
+                    self.field_schema(&property_path, property);
+                }
+                let required_path = format!("{}.required", path);
+                if schema.contains_key("required") {
+                    for (index, required) in self.array(&required_path, schema.get("required")) {
+                        match required.as_str() {
+                            Some(name) if properties.contains_key(name) => {}
+                            Some(name) => self.violation(
+                                &format!("{}[{}]", required_path, index),
+                                format!("unknown property `{}`", name),
+                            ),
+                            None => self.violation(
+                                &format!("{}[{}]", required_path, index),
+                                "must be a string",
+                            ),
+                        }
+                    }
+                }
+            }
+            Some("array") => match schema.get("items") {
+                Some(items) => self.field_schema(&format!("{}.items", path), items),
+                None => self.violation(&format!("{}.items", path), "is required"),
+            },
+            _ => {}
+        }
+    }
+
+    /// Check the value is an object without unknown properties
+    fn object<'a>(
+        &mut self,
+        path: &str,
+        value: &'a Value,
+        properties: &[&str],
+    ) -> Option<&'a Map<String, Value>> {
+        let Some(object) = value.as_object() else {
+            self.violation(path, "must be an object");
+            return None;
+        };
+        for key in object.keys() {
+            if !properties.contains(&key.as_str()) {
+                self.violation(&format!("{}.{}", path, key), "unknown property");
+            }
+        }
+        Some(object)
+    }
+
+    /// Check the value is an array, returning its items for further checks
+    fn array<'a>(&mut self, path: &str, value: Option<&'a Value>) -> Vec<(usize, &'a Value)> {
+        match value {
+            Some(Value::Array(items)) => items.iter().enumerate().collect(),
+            Some(_) => {
+                self.violation(path, "must be an array");
+                vec![]
+            }
+            None => {
+                self.violation(path, "is required");
+                vec![]
+            }
+        }
+    }
+
+    fn name(&mut self, path: &str, value: Option<&Value>) {
+        match value {
+            Some(Value::String(name)) if !name.is_empty() => {}
+            Some(_) => self.violation(path, "must be a non-empty string"),
+            None => self.violation(path, "is required"),
+        }
+    }
+
+    /// Check the value is a GraphQL name, it becomes a field of the schema of the application
+    fn identifier<'a>(&mut self, path: &str, value: Option<&'a Value>) -> Option<&'a str> {
+        match value {
+            Some(Value::String(name)) if is_identifier(name) => Some(name),
+            Some(Value::String(name)) => {
+                self.violation(path, format!("`{}` is not a valid GraphQL name", name));
+                None
+            }
+            Some(_) => {
+                self.violation(path, "must be a string");
+                None
+            }
+            None => {
+                self.violation(path, "is required");
+                None
+            }
+        }
+    }
+
+    fn uuid<'a>(&mut self, path: &str, value: Option<&'a Value>) -> Option<&'a str> {
+        match value {
+            Some(Value::String(uuid)) if is_uuid(uuid) => Some(uuid),
+            Some(Value::String(uuid)) => {
+                self.violation(path, format!("`{}` is not a well-formed uuid", uuid));
+                None
+            }
+            Some(_) => {
+                self.violation(path, "must be a string");
+                None
+            }
+            None => {
+                self.violation(path, "is required");
+                None
+            }
+        }
+    }
+
+    fn one_of(&mut self, path: &str, value: Option<&Value>, allowed: &[&str]) {
+        match value.and_then(Value::as_str) {
+            Some(value) if allowed.contains(&value) => {}
+            Some(value) => self.violation(
+                path,
+                format!(
+                    "unknown value `{}`, expected one of {}",
+                    value,
+                    allowed.join(", ")
+                ),
+            ),
+            None if value.is_some() => self.violation(path, "must be a string"),
+            None => self.violation(path, "is required"),
+        }
+    }
+}
+
+/// A GraphQL name, names starting with `__` are reserved for introspection
+fn is_identifier(value: &str) -> bool {
+    let mut chars = value.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !value.starts_with("__")
+}
+
+/// A SHA-256 as 64 lowercase hex digits, as sent in `extensions.persistedQuery.sha256Hash`
+fn is_sha256(value: &str) -> bool {
+    value.len() == 64
+        && value
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+/// An origin as sent by browsers, a scheme and host with an optional port but no path
+fn is_origin(value: &str) -> bool {
+    let Some((scheme, host)) = value.split_once("://") else {
+        return false;
+    };
+    matches!(scheme, "http" | "https")
+        && !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '[' | ']'))
+}
+
+/// A UUID in its hyphenated form or as 32 hex digits, as used in the Betty Blocks JWTs
+fn is_uuid(value: &str) -> bool {
+    match value.len() {
+        32 => value.chars().all(|c| c.is_ascii_hexdigit()),
+        36 => value.char_indices().all(|(index, c)| match index {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        }),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn artefact() -> Value {
+        json!({
+            "version": CURRENT_VERSION,
+            "cloud": {
+                "name": "BettyBlocks",
+                "components": ["action-example-a"],
+                "applications": [{
+                    "uuid": "693b22e983fb46afa4eb353d82ece4bb",
+                    "name": "MyApp",
+                    "actions": [{
+                        "uuid": "b1d7e3a0-c6f9-4b0e-9d5f-2a4c8e7b6d51",
+                        "name": "sendMail",
+                        "auth": "None",
+                        "scope": "Public",
+                        "etag": "1",
+                        "component": "action-example-a",
+                        "input": {
+                            "type": "object",
+                            "properties": {
+                                "to": { "type": "string" },
+                                "tags": { "type": "array", "items": { "type": "string" } }
+                            },
+                            "required": ["to"]
+                        },
+                        "output": { "type": "boolean" }
+                    }]
+                }]
+            }
+        })
+    }
+
+    fn paths(violations: &[Violation]) -> Vec<&str> {
+        violations.iter().map(|v| v.path.as_str()).collect()
+    }
+
+    #[test]
+    fn test_valid_artefact() {
+        assert_eq!(violations(&artefact()), vec![]);
+    }
+
+    #[test]
+    fn test_reports_all_violations_with_paths() {
+        let mut artefact = artefact();
+        let action = &mut artefact["cloud"]["applications"][0]["actions"][0];
+        action["uuid"] = json!("456");
+        action["auth"] = json!("Basic");
+        action["scope"] = json!("Internal");
+        action["component"] = json!("action-example-z");
+        artefact["cloud"]["applications"][0]["color"] = json!("blue");
+
+        let violations = violations(&artefact);
+        assert_eq!(
+            paths(&violations),
+            vec![
+                "$.cloud.applications[0].color",
+                "$.cloud.applications[0].actions[0].uuid",
+                "$.cloud.applications[0].actions[0].auth",
+                "$.cloud.applications[0].actions[0].scope",
+                "$.cloud.applications[0].actions[0].component",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_uuids() {
+        let mut artefact = artefact();
+        let application = artefact["cloud"]["applications"][0].clone();
+        artefact["cloud"]["applications"]
+            .as_array_mut()
+            .unwrap()
+            .push(application);
+
+        let violations = violations(&artefact);
+        assert_eq!(
+            paths(&violations),
+            vec![
+                "$.cloud.applications[1].uuid",
+                "$.cloud.applications[1].actions[0].uuid",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missing_properties() {
+        let violations = violations(&json!({ "version": CURRENT_VERSION, "cloud": {} }));
+        assert_eq!(
+            paths(&violations),
+            vec!["$.cloud.name", "$.cloud.components", "$.cloud.applications"]
+        );
+    }
+
+    #[test]
+    fn test_action_names_and_schemas() {
+        let mut artefact = artefact();
+        let actions = artefact["cloud"]["applications"][0]["actions"]
+            .as_array_mut()
+            .unwrap();
+        let mut second = actions[0].clone();
+        second["uuid"] = json!("0b1d7e3a0c6f94b0e9d5f2a4c8e7b6d5");
+        second["input"]["required"] = json!(["from"]);
+        second["output"] = json!({ "type": "array" });
+        actions.push(second);
+        actions[0]["name"] = json!("send-mail");
+        actions[0]["input"] = json!({ "type": "string" });
+
+        let violations = violations(&artefact);
+        assert_eq!(
+            paths(&violations),
+            vec![
+                "$.cloud.applications[0].actions[0].name",
+                "$.cloud.applications[0].actions[0].input.type",
+                "$.cloud.applications[0].actions[1].input.required[0]",
+                "$.cloud.applications[0].actions[1].output.items",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_action_names() {
+        let mut artefact = artefact();
+        let actions = artefact["cloud"]["applications"][0]["actions"]
+            .as_array_mut()
+            .unwrap();
+        let mut second = actions[0].clone();
+        second["uuid"] = json!("0b1d7e3a0c6f94b0e9d5f2a4c8e7b6d5");
+        actions.push(second);
+
+        let violations = violations(&artefact);
+        assert_eq!(
+            paths(&violations),
+            vec!["$.cloud.applications[0].actions[1].name"]
+        );
+    }
+
+    #[test]
+    fn test_is_identifier() {
+        assert!(is_identifier("sendMail"));
+        assert!(is_identifier("_send_mail2"));
+        assert!(!is_identifier("2send"));
+        assert!(!is_identifier("send-mail"));
+        assert!(!is_identifier("__schema"));
+        assert!(!is_identifier(""));
+    }
+
+    #[test]
+    fn test_persisted_queries() {
+        let mut artefact = artefact();
+        let document = "{ application { name } }";
+        let sha256 = hex::encode(Sha256::digest(document));
+        artefact["cloud"]["applications"][0]["queries"] = json!([
+            { "sha256": sha256, "query": document },
+            { "sha256": sha256, "query": document },
+            { "sha256": sha256.to_uppercase(), "query": document },
+            { "sha256": hex::encode(Sha256::digest("{ other }")), "query": document },
+            { "sha256": sha256, "query": "" }
+        ]);
+
+        let violations = violations(&artefact);
+        assert_eq!(
+            paths(&violations),
+            vec![
+                "$.cloud.applications[0].queries[1].sha256",
+                "$.cloud.applications[0].queries[2].sha256",
+                "$.cloud.applications[0].queries[3].sha256",
+                "$.cloud.applications[0].queries[4].query",
+                "$.cloud.applications[0].queries[4].sha256",
+            ]
+        );
+        assert_eq!(violations[2].message, "is not the SHA-256 of the query");
+    }
+
+    #[test]
+    fn test_origins() {
+        let mut artefact = artefact();
+        artefact["cloud"]["applications"][0]["origins"] = json!([
+            "https://myapp.example.com",
+            "https://MyApp.example.com",
+            "https://myapp.example.com/",
+            "http://localhost:3000"
+        ]);
+
+        let violations = violations(&artefact);
+        assert_eq!(
+            paths(&violations),
+            vec![
+                "$.cloud.applications[0].origins[1]",
+                "$.cloud.applications[0].origins[2]",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_origin() {
+        assert!(is_origin("https://myapp.example.com"));
+        assert!(is_origin("http://localhost:3000"));
+        assert!(is_origin("http://[::1]:3000"));
+        assert!(!is_origin("myapp.example.com"));
+        assert!(!is_origin("https://myapp.example.com/path"));
+        assert!(!is_origin("ftp://myapp.example.com"));
+        assert!(!is_origin("*"));
+    }
+
+    #[test]
+    fn test_is_uuid() {
+        assert!(is_uuid("693b22e983fb46afa4eb353d82ece4bb"));
+        assert!(is_uuid("B1D7E3A0-C6F9-4B0E-9D5F-2A4C8E7B6D51"));
+        assert!(!is_uuid("456"));
+        assert!(!is_uuid("b1d7e3a0c-6f9-4b0e-9d5f-2a4c8e7b6d51"));
+        assert!(!is_uuid("693b22e983fb46afa4eb353d82ece4bz"));
+    }
+
+    #[test]
+    fn test_schema_matches_validation() {
+        let schema: Value = serde_json::from_str(SCHEMA).unwrap();
+        assert_eq!(
+            schema["properties"]["version"]["const"],
+            json!(CURRENT_VERSION)
+        );
+        let action = &schema["$defs"]["action"]["properties"];
+        assert_eq!(action["auth"]["enum"], json!(AUTH_KINDS));
+        assert_eq!(action["scope"]["enum"], json!(SCOPES));
+        assert_eq!(
+            schema["$defs"]["field"]["properties"]["type"]["enum"],
+            json!(FIELD_TYPES)
+        );
+    }
+}
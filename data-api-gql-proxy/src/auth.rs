@@ -0,0 +1,396 @@
+//! Credentials used for calls to the Data API.
+//!
+//! Every link decides whether calls are made as the end user, by forwarding the caller's JWT,
+//! or as a service identity, by exchanging it for a short-lived service token.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use crate::breaker::CircuitBreaker;
+use crate::upstream::UpstreamError;
+
+const TOKEN_EXCHANGE_GRANT: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+const JWT_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:jwt";
+/// Used when the token endpoint doesn't tell how long a token lives
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(300);
+/// Tokens are refreshed this long before they expire
+const EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+
+/// A token or credential that must never end up in logs. `Debug` and `Display` are redacted.
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
+}
+
+/// How a link authenticates its calls to the Data API, link config `auth_mode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AuthMode {
+    /// Call the Data API as the end user with the caller's JWT
+    #[default]
+    Forward,
+    /// Call the Data API as a service identity. Calls without a caller token are only made as
+    /// the provider's own identity when `allow_service_identity` is set on the link.
+    Service { allow_service_identity: bool },
+}
+
+impl AuthMode {
+    pub fn from_link_config(config: &HashMap<String, String>) -> Result<Self, String> {
+        match config.get("auth_mode").map(String::as_str) {
+            None | Some("forward") => Ok(AuthMode::Forward),
+            Some("service") => {
+                let allow_service_identity =
+                    match config.get("allow_service_identity").map(String::as_str) {
+                        None | Some("false") => false,
+                        Some("true") => true,
+                        Some(other) => {
+                            return Err(format!("invalid allow_service_identity `{}`", other))
+                        }
+                    };
+                Ok(AuthMode::Service {
+                    allow_service_identity,
+                })
+            }
+            Some(other) => Err(format!("unknown auth_mode `{}`", other)),
+        }
+    }
+}
+
+/// Credentials of this provider at the token endpoint, from the provider config
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServiceCredentials {
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: Secret,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: Secret,
+    expires_in: Option<u64>,
+}
+
+struct CachedToken {
+    token: Secret,
+    expires_at: Instant,
+}
+
+/// Token cache counters reported through the status export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenCacheStats {
+    pub cached_tokens: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Exchanges caller tokens for service tokens, caching them until shortly before they expire.
+#[derive(Clone)]
+pub struct TokenExchange {
+    http: reqwest::Client,
+    credentials: ServiceCredentials,
+    breaker: Arc<CircuitBreaker>,
+    /// Service tokens by the caller token they were exchanged for, `None` for the plain service identity
+    cache: Arc<Mutex<HashMap<Option<Secret>, CachedToken>>>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+impl TokenExchange {
+    pub fn new(
+        credentials: ServiceCredentials,
+        timeout: Duration,
+        breaker: Arc<CircuitBreaker>,
+    ) -> anyhow::Result<Self> {
+        let http = reqwest::Client::builder().timeout(timeout).build()?;
+        Ok(TokenExchange {
+            http,
+            credentials,
+            breaker,
+            cache: Arc::default(),
+            hits: Arc::default(),
+            misses: Arc::default(),
+        })
+    }
+
+    /// Get a service token for the caller. Without a caller token the provider's own
+    /// service identity is used (client credentials grant).
+    ///
+    /// The cache isn't locked during the exchange, so a slow token endpoint doesn't hold up
+    /// callers with a cached token. Concurrent misses for the same caller each exchange a
+    /// token, the last one is cached.
+    pub async fn service_token(&self, subject: Option<&Secret>) -> Result<Secret, UpstreamError> {
+        let key = subject.cloned();
+        let now = Instant::now();
+        if let Some(cached) = self
+            .cache
+            .lock()
+            .await
+            .get(&key)
+            .filter(|cached| cached.expires_at > now)
+        {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(cached.token.clone());
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let permit = self
+            .breaker
+            .try_acquire()
+            .map_err(|retry_after| UpstreamError::CircuitOpen { retry_after })?;
+        let response = self.request_token(subject).await;
+        permit.record(!matches!(&response, Err(e) if e.is_upstream_failure()));
+        let response = response?;
+        let lifetime = response
+            .expires_in
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_TOKEN_LIFETIME);
+        debug!(
+            client_id = %self.credentials.client_id,
+            lifetime_secs = lifetime.as_secs(),
+            "obtained service token"
+        );
+
+        let mut cache = self.cache.lock().await;
+        cache.retain(|_, cached| cached.expires_at > now);
+        cache.insert(
+            key,
+            CachedToken {
+                token: response.access_token.clone(),
+                expires_at: now + lifetime.saturating_sub(EXPIRY_MARGIN),
+            },
+        );
+        Ok(response.access_token)
+    }
+
+    pub async fn stats(&self) -> TokenCacheStats {
+        TokenCacheStats {
+            cached_tokens: self.cache.lock().await.len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    async fn request_token(
+        &self,
+        subject: Option<&Secret>,
+    ) -> Result<TokenResponse, UpstreamError> {
+        let mut form = vec![
+            ("client_id", self.credentials.client_id.as_str()),
+            ("client_secret", self.credentials.client_secret.expose()),
+        ];
+        match subject {
+            Some(subject) => form.extend([
+                ("grant_type", TOKEN_EXCHANGE_GRANT),
+                ("subject_token", subject.expose()),
+                ("subject_token_type", JWT_TOKEN_TYPE),
+            ]),
+            None => form.push(("grant_type", "client_credentials")),
+        }
+
+        // reqwest errors can contain the request, only report what went wrong
+        let response = self
+            .http
+            .post(&self.credentials.token_url)
+            .form(&form)
+            .send()
+            .await
+            .map_err(|e| UpstreamError::Transport(e.without_url().to_string()))?;
+        let status = response.status();
+        if status.is_client_error() {
+            return Err(UpstreamError::Rejected(format!(
+                "token endpoint responded with {}",
+                status
+            )));
+        }
+        if !status.is_success() {
+            return Err(UpstreamError::Transport(format!(
+                "token endpoint responded with {}",
+                status
+            )));
+        }
+        response.json().await.map_err(|_| {
+            UpstreamError::InvalidResponse(
+                "token endpoint returned an invalid response".to_string(),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breaker::BreakerConfig;
+    use crate::test_server::{self, Received};
+
+    /// A token endpoint answering every request with `status` and `body`
+    async fn token_endpoint(status: u16, body: &'static str) -> (String, Received) {
+        test_server::serve(move |_| (status, body.to_string())).await
+    }
+
+    fn exchange(token_url: String) -> TokenExchange {
+        let breaker = CircuitBreaker::new(
+            token_url.clone(),
+            BreakerConfig {
+                failure_threshold: 0.5,
+                window: 2,
+                min_calls: 2,
+                cooldown: Duration::from_secs(60),
+            },
+        );
+        TokenExchange::new(
+            ServiceCredentials {
+                token_url,
+                client_id: "provider".to_string(),
+                client_secret: Secret::new("provider-secret".to_string()),
+            },
+            Duration::from_secs(5),
+            Arc::new(breaker),
+        )
+        .unwrap()
+    }
+
+    fn caller(token: &str) -> Secret {
+        Secret::new(token.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_service_token_is_cached_per_caller() {
+        let (url, received) =
+            token_endpoint(200, r#"{"access_token":"service-token","expires_in":3600}"#).await;
+        let exchange = exchange(url);
+
+        for _ in 0..2 {
+            let token = exchange.service_token(Some(&caller("caller-a"))).await;
+            assert_eq!(token, Ok(caller("service-token")));
+        }
+        exchange
+            .service_token(Some(&caller("caller-b")))
+            .await
+            .unwrap();
+        exchange.service_token(None).await.unwrap();
+
+        let received = received.lock().unwrap().clone();
+        assert_eq!(received.len(), 3);
+        assert!(received[0]
+            .contains("grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Atoken-exchange"));
+        assert!(received[0].contains("subject_token=caller-a"));
+        assert!(received[1].contains("subject_token=caller-b"));
+        assert!(received[2].contains("grant_type=client_credentials"));
+        assert!(!received[2].contains("subject_token"));
+        assert_eq!(
+            exchange.stats().await,
+            TokenCacheStats {
+                cached_tokens: 3,
+                hits: 1,
+                misses: 3,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_service_token_is_refreshed_before_it_expires() {
+        // a token expiring within the margin is never served from the cache
+        let (url, received) =
+            token_endpoint(200, r#"{"access_token":"service-token","expires_in":30}"#).await;
+        let exchange = exchange(url);
+        for _ in 0..2 {
+            exchange
+                .service_token(Some(&caller("caller-a")))
+                .await
+                .unwrap();
+        }
+        assert_eq!(received.lock().unwrap().len(), 2);
+        assert_eq!(exchange.stats().await.hits, 0);
+    }
+
+    #[tokio::test]
+    async fn test_token_endpoint_failures_open_the_breaker() {
+        let (url, received) = token_endpoint(503, "{}").await;
+        let exchange = exchange(url);
+        for _ in 0..2 {
+            let err = exchange.service_token(None).await.unwrap_err();
+            assert!(matches!(err, UpstreamError::Transport(_)), "{:?}", err);
+        }
+        let err = exchange.service_token(None).await.unwrap_err();
+        assert!(
+            matches!(err, UpstreamError::CircuitOpen { .. }),
+            "{:?}",
+            err
+        );
+        // the open circuit failed the call without reaching the endpoint
+        assert_eq!(received.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_exchanges_keep_the_breaker_closed() {
+        let (url, received) = token_endpoint(401, "{}").await;
+        let exchange = exchange(url);
+        for _ in 0..3 {
+            let err = exchange
+                .service_token(Some(&caller("expired")))
+                .await
+                .unwrap_err();
+            assert!(matches!(err, UpstreamError::Rejected(_)), "{:?}", err);
+        }
+        assert_eq!(received.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_secret_is_redacted() {
+        let secret = Secret::new("eyJhbGciOiJIUzUxMiJ9.e30.c2ln".to_string());
+        assert_eq!(format!("{:?}", secret), "Secret(***)");
+        assert_eq!(format!("{}", secret), "***");
+        assert_eq!(secret.expose(), "eyJhbGciOiJIUzUxMiJ9.e30.c2ln");
+    }
+
+    #[test]
+    fn test_auth_mode_from_link_config() {
+        let mut config = HashMap::new();
+        assert_eq!(AuthMode::from_link_config(&config), Ok(AuthMode::Forward));
+        config.insert("auth_mode".to_string(), "service".to_string());
+        assert_eq!(
+            AuthMode::from_link_config(&config),
+            Ok(AuthMode::Service {
+                allow_service_identity: false
+            })
+        );
+        config.insert("allow_service_identity".to_string(), "true".to_string());
+        assert_eq!(
+            AuthMode::from_link_config(&config),
+            Ok(AuthMode::Service {
+                allow_service_identity: true
+            })
+        );
+        config.insert("allow_service_identity".to_string(), "yes".to_string());
+        assert!(AuthMode::from_link_config(&config).is_err());
+        config.insert("auth_mode".to_string(), "impersonate".to_string());
+        assert!(AuthMode::from_link_config(&config).is_err());
+    }
+}
@@ -0,0 +1,316 @@
+//! Coalesces concurrent Data API requests into batched GraphQL calls.
+//!
+//! Requests arriving within the batch window are collected and sent as one array batch, or one
+//! by one when batching is off or the Data API doesn't accept the batch. Identical requests in
+//! a batch are only sent once and their response is shared, which takes care of flows that
+//! loop over records and issue the same lookup many times. Requests are only batched together
+//! when they are made with the same credentials.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::Value;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::auth::Secret;
+use crate::upstream::{DataApiClient, GraphqlRequest, UpstreamError};
+
+struct Pending {
+    request: GraphqlRequest,
+    token: Option<Secret>,
+    reply: oneshot::Sender<Result<Value, UpstreamError>>,
+}
+
+/// Counters reported through the status export.
+#[derive(Debug, Default)]
+pub struct BatchStats {
+    /// Requests received from components
+    pub requests: AtomicU64,
+    /// Batches sent to the Data API
+    pub batches: AtomicU64,
+    /// Requests answered by an identical request in the same batch
+    pub coalesced: AtomicU64,
+}
+
+/// Handle to the batching task, cheap to clone.
+#[derive(Clone)]
+pub struct Batcher {
+    tx: mpsc::UnboundedSender<Pending>,
+    stats: Arc<BatchStats>,
+}
+
+impl Batcher {
+    /// Start the batching task on the current runtime. Without `batching` the requests of a
+    /// batch are sent one by one.
+    pub fn spawn(
+        client: DataApiClient,
+        window: Duration,
+        max_batch_size: usize,
+        batching: bool,
+    ) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let stats = Arc::new(BatchStats::default());
+        tokio::spawn(collect(
+            client,
+            rx,
+            window,
+            max_batch_size.max(1),
+            batching,
+            stats.clone(),
+        ));
+        Batcher { tx, stats }
+    }
+
+    pub fn stats(&self) -> &BatchStats {
+        &self.stats
+    }
+
+    /// Queue a request for the next batch and wait for its response.
+    pub async fn query(
+        &self,
+        request: GraphqlRequest,
+        token: Option<Secret>,
+    ) -> Result<Value, UpstreamError> {
+        let (reply, response) = oneshot::channel();
+        self.stats.requests.fetch_add(1, Ordering::Relaxed);
+        self.tx
+            .send(Pending {
+                request,
+                token,
+                reply,
+            })
+            .map_err(|_| UpstreamError::Transport("batcher is not running".to_string()))?;
+        response.await.map_err(|_| {
+            UpstreamError::Transport("batch was dropped before completing".to_string())
+        })?
+    }
+}
+
+async fn collect(
+    client: DataApiClient,
+    mut rx: mpsc::UnboundedReceiver<Pending>,
+    window: Duration,
+    max_batch_size: usize,
+    batching: bool,
+    stats: Arc<BatchStats>,
+) {
+    while let Some(first) = rx.recv().await {
+        let deadline = Instant::now() + window;
+        let mut pending = vec![first];
+        while pending.len() < max_batch_size {
+            match tokio::time::timeout_at(deadline, rx.recv()).await {
+                Ok(Some(next)) => pending.push(next),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        let mut by_token: HashMap<Option<Secret>, Vec<Pending>> = HashMap::new();
+        for p in pending {
+            by_token.entry(p.token.clone()).or_default().push(p);
+        }
+        // Dispatch in the background so the next window starts collecting right away
+        for (token, pending) in by_token {
+            tokio::spawn(dispatch(
+                client.clone(),
+                token,
+                pending,
+                batching,
+                stats.clone(),
+            ));
+        }
+    }
+}
+
+async fn dispatch(
+    client: DataApiClient,
+    token: Option<Secret>,
+    pending: Vec<Pending>,
+    batching: bool,
+    stats: Arc<BatchStats>,
+) {
+    let (requests, slots) = coalesce(pending.iter().map(|p| &p.request));
+    stats.batches.fetch_add(1, Ordering::Relaxed);
+    stats
+        .coalesced
+        .fetch_add((pending.len() - requests.len()) as u64, Ordering::Relaxed);
+    debug!(
+        callers = pending.len(),
+        requests = requests.len(),
+        "dispatching batch to data api"
+    );
+
+    let results = client
+        .execute_batch(&requests, token.as_ref(), batching)
+        .await;
+    for (Pending { reply, .. }, slot) in pending.into_iter().zip(slots) {
+        // The caller may have given up waiting, there is nobody to tell
+        let _ = reply.send(results[slot].clone());
+    }
+}
+
+/// Deduplicate identical requests. Returns the unique requests and, for every input request,
+/// the index of the unique request that answers it.
+fn coalesce<'a>(
+    requests: impl IntoIterator<Item = &'a GraphqlRequest>,
+) -> (Vec<GraphqlRequest>, Vec<usize>) {
+    let mut unique = Vec::new();
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    let slots = requests
+        .into_iter()
+        .map(|request| {
+            let key = serde_json::to_string(request).unwrap_or_default();
+            *seen.entry(key).or_insert_with(|| {
+                unique.push(request.clone());
+                unique.len() - 1
+            })
+        })
+        .collect();
+    (unique, slots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breaker::{BreakerConfig, CircuitBreaker};
+    use crate::test_server::{self, Received};
+    use serde_json::json;
+
+    /// A Data API answering every request with its query, and an array batch with an array
+    /// unless `batches` is off, then it rejects them
+    async fn data_api(batches: bool) -> (String, Received) {
+        test_server::serve(move |body| {
+            let answer = |request: &Value| json!({ "data": { "query": request["query"] } });
+            match serde_json::from_str(body).unwrap() {
+                Value::Array(_) if !batches => (400, "{}".to_string()),
+                Value::Array(requests) => {
+                    let answers: Vec<Value> = requests.iter().map(answer).collect();
+                    (200, Value::from(answers).to_string())
+                }
+                request => (200, answer(&request).to_string()),
+            }
+        })
+        .await
+    }
+
+    /// Dispatch the queries as one batch, returning the result of every caller
+    async fn dispatch_queries(
+        url: String,
+        queries: &[&str],
+        batching: bool,
+    ) -> Vec<Result<Value, UpstreamError>> {
+        let breaker = CircuitBreaker::new(url.clone(), BreakerConfig::default());
+        let client = DataApiClient::new(url, Duration::from_secs(5), Arc::new(breaker)).unwrap();
+        let (pending, responses): (Vec<_>, Vec<_>) = queries
+            .iter()
+            .map(|query| {
+                let (reply, response) = oneshot::channel();
+                let pending = Pending {
+                    request: request(query, None),
+                    token: None,
+                    reply,
+                };
+                (pending, response)
+            })
+            .unzip();
+        dispatch(client, None, pending, batching, Arc::default()).await;
+        let mut results = vec![];
+        for response in responses {
+            results.push(response.await.unwrap());
+        }
+        results
+    }
+
+    fn answers(queries: &[&str]) -> Vec<Result<Value, UpstreamError>> {
+        queries
+            .iter()
+            .map(|query| Ok(json!({ "data": { "query": query } })))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_answers_every_caller_with_its_own_response() {
+        let (url, received) = data_api(true).await;
+        let queries = ["{ a }", "{ b }", "{ a }"];
+        assert_eq!(
+            dispatch_queries(url, &queries, true).await,
+            answers(&queries)
+        );
+        // one batch, with the identical requests sent once
+        let received = received.lock().unwrap().clone();
+        assert_eq!(received.len(), 1);
+        assert_eq!(
+            serde_json::from_str::<Value>(&received[0]).unwrap(),
+            json!([{ "query": "{ a }" }, { "query": "{ b }" }])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_shares_an_error_with_every_caller() {
+        let (url, received) = test_server::serve(|_| (503, "{}".to_string())).await;
+        let results = dispatch_queries(url, &["{ a }", "{ b }"], true).await;
+        assert_eq!(results.len(), 2);
+        for result in results {
+            assert!(
+                matches!(&result, Err(UpstreamError::Transport(_))),
+                "{:?}",
+                result
+            );
+        }
+        // the requests are not sent again one by one
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_falls_back_to_single_requests() {
+        let (url, received) = data_api(false).await;
+        let queries = ["{ a }", "{ b }"];
+        assert_eq!(
+            dispatch_queries(url, &queries, true).await,
+            answers(&queries)
+        );
+        assert_eq!(received.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_without_batching() {
+        let (url, received) = data_api(false).await;
+        let queries = ["{ a }", "{ b }"];
+        assert_eq!(
+            dispatch_queries(url, &queries, false).await,
+            answers(&queries)
+        );
+        let received = received.lock().unwrap().clone();
+        assert_eq!(received.len(), 2);
+        assert!(received.iter().all(|body| body.starts_with('{')));
+    }
+
+    fn request(query: &str, variables: Option<Value>) -> GraphqlRequest {
+        GraphqlRequest {
+            query: query.to_string(),
+            variables,
+            operation_name: None,
+        }
+    }
+
+    #[test]
+    fn test_coalesce_identical_requests() {
+        let a = request("{ one { id } }", Some(serde_json::json!({ "id": 1 })));
+        let b = request("{ one { id } }", Some(serde_json::json!({ "id": 2 })));
+        let (unique, slots) = coalesce([&a, &b, &a, &a]);
+        assert_eq!(unique, vec![a, b]);
+        assert_eq!(slots, vec![0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_coalesce_keeps_order_of_first_occurrence() {
+        let a = request("{ a }", None);
+        let b = request("{ b }", None);
+        let c = request("{ c }", None);
+        let (unique, slots) = coalesce([&c, &a, &b, &c]);
+        assert_eq!(unique, vec![c, a, b]);
+        assert_eq!(slots, vec![0, 1, 2, 0]);
+    }
+}
@@ -0,0 +1,393 @@
+//! Circuit breakers that track the health of the upstreams this provider calls.
+//!
+//! Every upstream keeps the outcome of its most recent calls. Once the failure rate over
+//! those calls crosses the threshold the circuit opens and calls fail fast. After the
+//! cooldown a single probe call is let through (half-open), which closes the circuit
+//! again when it succeeds and reopens it when it fails.
+//!
+//! Every call holds a [`Permit`] until its outcome is recorded. A permit dropped without
+//! recording, such as a call whose future was cancelled, counts as a failure, so the probe
+//! can't leave the circuit half-open. Outcomes of calls let through before the circuit last
+//! changed state are ignored.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BreakerConfig {
+    /// Failure rate, between 0 and 1, at which the circuit opens
+    pub failure_threshold: f64,
+    /// Number of recent calls the failure rate is computed over
+    pub window: usize,
+    /// Minimum number of calls in the window before the circuit can open
+    pub min_calls: usize,
+    /// How long the circuit stays open before a probe call is let through
+    pub cooldown: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        BreakerConfig {
+            failure_threshold: 0.5,
+            window: 20,
+            min_calls: 10,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// Point in time view of a circuit, reported through the status export.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CircuitStatus {
+    pub upstream: String,
+    pub state: CircuitState,
+    pub failure_rate: f64,
+    pub recent_calls: usize,
+    /// Time until a probe call is let through, only while open
+    pub retry_after: Option<Duration>,
+}
+
+struct Circuit {
+    state: CircuitState,
+    /// Outcome of the most recent calls, `true` for a failure
+    outcomes: VecDeque<bool>,
+    opened_at: Option<Instant>,
+    probe_in_flight: bool,
+    /// Incremented on every state change, to tell which state a call was let through in
+    generation: u64,
+}
+
+pub struct CircuitBreaker {
+    upstream: String,
+    config: BreakerConfig,
+    circuit: Mutex<Circuit>,
+}
+
+impl CircuitBreaker {
+    pub fn new(upstream: String, config: BreakerConfig) -> Self {
+        CircuitBreaker {
+            upstream,
+            config,
+            circuit: Mutex::new(Circuit {
+                state: CircuitState::Closed,
+                outcomes: VecDeque::with_capacity(config.window),
+                opened_at: None,
+                probe_in_flight: false,
+                generation: 0,
+            }),
+        }
+    }
+
+    /// Ask permission to call the upstream. Fails with the time until the next probe while
+    /// the circuit is open.
+    pub fn try_acquire(&self) -> Result<Permit<'_>, Duration> {
+        self.acquire_at(Instant::now())
+    }
+
+    pub fn status(&self) -> CircuitStatus {
+        self.status_at(Instant::now())
+    }
+
+    fn acquire_at(&self, now: Instant) -> Result<Permit<'_>, Duration> {
+        let generation = self.try_acquire_at(now)?;
+        Ok(Permit {
+            breaker: self,
+            generation,
+            recorded: false,
+        })
+    }
+
+    /// Let a call through, returning the generation of the state it was let through in.
+    fn try_acquire_at(&self, now: Instant) -> Result<u64, Duration> {
+        let mut circuit = self.circuit.lock().unwrap_or_else(|e| e.into_inner());
+        match circuit.state {
+            CircuitState::Closed => Ok(circuit.generation),
+            CircuitState::Open => {
+                let retry_after = self.retry_after(&circuit, now);
+                if !retry_after.is_zero() {
+                    return Err(retry_after);
+                }
+                self.transition(&mut circuit, CircuitState::HalfOpen);
+                circuit.probe_in_flight = true;
+                Ok(circuit.generation)
+            }
+            CircuitState::HalfOpen if circuit.probe_in_flight => Err(self.config.cooldown),
+            CircuitState::HalfOpen => {
+                circuit.probe_in_flight = true;
+                Ok(circuit.generation)
+            }
+        }
+    }
+
+    fn record_at(&self, generation: u64, success: bool, now: Instant) {
+        let mut circuit = self.circuit.lock().unwrap_or_else(|e| e.into_inner());
+        // A call let through before the circuit opened, or a slow call from the closed state
+        // finishing while the probe is in flight, says nothing about the current state
+        if generation != circuit.generation {
+            return;
+        }
+        if circuit.outcomes.len() == self.config.window {
+            circuit.outcomes.pop_front();
+        }
+        circuit.outcomes.push_back(!success);
+
+        match circuit.state {
+            CircuitState::HalfOpen => {
+                circuit.probe_in_flight = false;
+                if success {
+                    circuit.outcomes.clear();
+                    circuit.opened_at = None;
+                    self.transition(&mut circuit, CircuitState::Closed);
+                } else {
+                    circuit.opened_at = Some(now);
+                    self.transition(&mut circuit, CircuitState::Open);
+                }
+            }
+            CircuitState::Closed
+                if circuit.outcomes.len() >= self.config.min_calls
+                    && failure_rate(&circuit) >= self.config.failure_threshold =>
+            {
+                circuit.opened_at = Some(now);
+                self.transition(&mut circuit, CircuitState::Open);
+            }
+            _ => {}
+        }
+    }
+
+    fn status_at(&self, now: Instant) -> CircuitStatus {
+        let circuit = self.circuit.lock().unwrap_or_else(|e| e.into_inner());
+        CircuitStatus {
+            upstream: self.upstream.clone(),
+            state: circuit.state,
+            failure_rate: failure_rate(&circuit),
+            recent_calls: circuit.outcomes.len(),
+            retry_after: (circuit.state == CircuitState::Open)
+                .then(|| self.retry_after(&circuit, now)),
+        }
+    }
+
+    fn retry_after(&self, circuit: &Circuit, now: Instant) -> Duration {
+        let opened_at = circuit.opened_at.unwrap_or(now);
+        (opened_at + self.config.cooldown).saturating_duration_since(now)
+    }
+
+    fn transition(&self, circuit: &mut Circuit, state: CircuitState) {
+        let from = circuit.state;
+        circuit.state = state;
+        circuit.generation += 1;
+        let failure_rate = failure_rate(circuit);
+        match state {
+            CircuitState::Open => warn!(
+                upstream = %self.upstream,
+                ?from,
+                failure_rate,
+                "circuit opened, failing fast"
+            ),
+            CircuitState::HalfOpen => info!(
+                upstream = %self.upstream,
+                ?from,
+                "circuit half-open, letting a probe call through"
+            ),
+            CircuitState::Closed => info!(upstream = %self.upstream, ?from, "circuit closed"),
+        }
+    }
+}
+
+/// Permission to make a single call, recording a failure when it is dropped without
+/// [`Permit::record`].
+pub struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    generation: u64,
+    recorded: bool,
+}
+
+impl Permit<'_> {
+    /// Record the outcome of the call.
+    pub fn record(mut self, success: bool) {
+        self.recorded = true;
+        self.breaker
+            .record_at(self.generation, success, Instant::now());
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if !self.recorded {
+            self.breaker
+                .record_at(self.generation, false, Instant::now());
+        }
+    }
+}
+
+fn failure_rate(circuit: &Circuit) -> f64 {
+    if circuit.outcomes.is_empty() {
+        return 0.0;
+    }
+    let failures = circuit.outcomes.iter().filter(|failed| **failed).count();
+    failures as f64 / circuit.outcomes.len() as f64
+}
+
+/// The circuit breakers of all upstreams, shared between the clients and the status export.
+#[derive(Clone, Default)]
+pub struct Breakers {
+    config: BreakerConfig,
+    circuits: Arc<RwLock<HashMap<String, Arc<CircuitBreaker>>>>,
+}
+
+impl Breakers {
+    pub fn new(config: BreakerConfig) -> Self {
+        Breakers {
+            config,
+            circuits: Arc::default(),
+        }
+    }
+
+    /// Get the circuit breaker of an upstream, creating it on first use.
+    pub fn for_upstream(&self, upstream: &str) -> Arc<CircuitBreaker> {
+        if let Some(breaker) = self
+            .circuits
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(upstream)
+        {
+            return breaker.clone();
+        }
+        self.circuits
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(upstream.to_string())
+            .or_insert_with(|| Arc::new(CircuitBreaker::new(upstream.to_string(), self.config)))
+            .clone()
+    }
+
+    pub fn status(&self) -> Vec<CircuitStatus> {
+        let mut status: Vec<CircuitStatus> = self
+            .circuits
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .map(|breaker| breaker.status())
+            .collect();
+        status.sort_by(|a, b| a.upstream.cmp(&b.upstream));
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(
+            "http://data-api".to_string(),
+            BreakerConfig {
+                failure_threshold: 0.5,
+                window: 4,
+                min_calls: 4,
+                cooldown: Duration::from_secs(10),
+            },
+        )
+    }
+
+    #[test]
+    fn test_opens_when_failure_rate_crosses_threshold() {
+        let breaker = breaker();
+        let now = Instant::now();
+        breaker.record_at(0, true, now);
+        breaker.record_at(0, true, now);
+        breaker.record_at(0, false, now);
+        assert_eq!(breaker.status_at(now).state, CircuitState::Closed);
+        breaker.record_at(0, false, now);
+        assert_eq!(breaker.status_at(now).state, CircuitState::Open);
+        assert_eq!(breaker.try_acquire_at(now), Err(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_stays_closed_below_min_calls() {
+        let breaker = breaker();
+        let now = Instant::now();
+        for _ in 0..3 {
+            breaker.record_at(0, false, now);
+        }
+        assert_eq!(breaker.status_at(now).state, CircuitState::Closed);
+        assert_eq!(breaker.try_acquire_at(now), Ok(0));
+    }
+
+    #[test]
+    fn test_half_opens_after_cooldown_with_single_probe() {
+        let breaker = breaker();
+        let now = Instant::now();
+        for _ in 0..4 {
+            breaker.record_at(0, false, now);
+        }
+        let later = now + Duration::from_secs(10);
+        let probe = breaker.try_acquire_at(later).unwrap();
+        assert_eq!(breaker.status_at(later).state, CircuitState::HalfOpen);
+        assert!(breaker.try_acquire_at(later).is_err());
+
+        breaker.record_at(probe, true, later);
+        assert_eq!(breaker.status_at(later).state, CircuitState::Closed);
+        assert!(breaker.try_acquire_at(later).is_ok());
+    }
+
+    #[test]
+    fn test_failed_probe_reopens() {
+        let breaker = breaker();
+        let now = Instant::now();
+        for _ in 0..4 {
+            breaker.record_at(0, false, now);
+        }
+        let later = now + Duration::from_secs(11);
+        let probe = breaker.try_acquire_at(later).unwrap();
+        breaker.record_at(probe, false, later);
+
+        let status = breaker.status_at(later);
+        assert_eq!(status.state, CircuitState::Open);
+        assert_eq!(status.retry_after, Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_dropped_probe_reopens() {
+        let breaker = breaker();
+        let now = Instant::now();
+        for _ in 0..4 {
+            breaker.record_at(0, false, now);
+        }
+        let probe = breaker.acquire_at(now + Duration::from_secs(10)).unwrap();
+        assert_eq!(breaker.status().state, CircuitState::HalfOpen);
+        drop(probe);
+
+        assert_eq!(breaker.status().state, CircuitState::Open);
+        assert!(breaker.try_acquire().is_err());
+    }
+
+    #[test]
+    fn test_call_from_earlier_state_does_not_decide_probe() {
+        let breaker = breaker();
+        let now = Instant::now();
+        let slow = breaker.try_acquire_at(now).unwrap();
+        for _ in 0..4 {
+            breaker.record_at(0, false, now);
+        }
+        let later = now + Duration::from_secs(10);
+        let probe = breaker.try_acquire_at(later).unwrap();
+
+        breaker.record_at(slow, true, later);
+        assert_eq!(breaker.status_at(later).state, CircuitState::HalfOpen);
+        assert!(breaker.try_acquire_at(later).is_err());
+
+        breaker.record_at(probe, true, later);
+        assert_eq!(breaker.status_at(later).state, CircuitState::Closed);
+    }
+}
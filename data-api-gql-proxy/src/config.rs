@@ -1,21 +1,133 @@
 use std::collections::HashMap;
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::auth::{Secret, ServiceCredentials};
+use crate::breaker::BreakerConfig;
+
+const DEFAULT_BATCH_WINDOW_MS: u64 = 5;
+const DEFAULT_MAX_BATCH_SIZE: usize = 50;
+const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_EVENTS_POLL_INTERVAL_MS: u64 = 5_000;
+/// Keys that are kept out of `values`, so they can't leak when the config is logged
+const SECRET_KEYS: &[&str] = &["client_secret"];
 
 /// Configuration for this provider, which is passed to the provider from the host.
-#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct ProviderConfig {
     values: HashMap<String, String>,
+    /// GraphQL endpoint of the Data API, `data_api_url`
+    pub data_api_url: Option<String>,
+    /// How long to wait for more requests before dispatching a batch, `batch_window_ms`.
+    /// A window of zero only batches requests that are already queued.
+    pub batch_window: Duration,
+    /// Maximum number of GraphQL requests sent in one batch, `max_batch_size`
+    pub max_batch_size: usize,
+    /// Whether requests are sent as array batches, `batching`. Without it the requests of a
+    /// batch are sent one by one, for a Data API that doesn't accept batches.
+    pub batching: bool,
+    /// Timeout for a single call to the Data API, `request_timeout_ms`
+    pub request_timeout: Duration,
+    /// Credentials to exchange caller tokens for service tokens, `token_url`, `client_id`
+    /// and `client_secret`. Only needed when a link uses `auth_mode: service`.
+    pub service_credentials: Option<ServiceCredentials>,
+    /// Circuit breaker settings for every upstream, `breaker_failure_threshold` (percentage),
+    /// `breaker_window`, `breaker_min_calls` and `breaker_cooldown_ms`
+    pub breaker: BreakerConfig,
+    /// Change feed of the Data API, `events_url`. Events are only delivered when it is set.
+    pub events_url: Option<String>,
+    /// How often the change feed is polled, `events_poll_interval_ms`
+    pub events_poll_interval: Duration,
+}
+
+impl Default for ProviderConfig {
+    fn default() -> Self {
+        ProviderConfig {
+            values: HashMap::new(),
+            data_api_url: None,
+            batch_window: Duration::from_millis(DEFAULT_BATCH_WINDOW_MS),
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+            batching: true,
+            request_timeout: Duration::from_millis(DEFAULT_REQUEST_TIMEOUT_MS),
+            service_credentials: None,
+            breaker: BreakerConfig::default(),
+            events_url: None,
+            events_poll_interval: Duration::from_millis(DEFAULT_EVENTS_POLL_INTERVAL_MS),
+        }
+    }
 }
 
 impl From<&HashMap<String, String>> for ProviderConfig {
     /// Construct configuration struct from the passed config values.
     ///
-    /// For this example, we just store the values directly for any later reference.
-    /// You can use this as a base to create your own strongly typed configuration struct.
+    /// Unknown keys are kept in `values`, values that fail to parse fall back to their default.
     fn from(values: &HashMap<String, String>) -> ProviderConfig {
+        let defaults = ProviderConfig::default();
+        let service_credentials = match (
+            values.get("token_url"),
+            values.get("client_id"),
+            values.get("client_secret"),
+        ) {
+            (Some(token_url), Some(client_id), Some(client_secret)) => Some(ServiceCredentials {
+                token_url: token_url.clone(),
+                client_id: client_id.clone(),
+                client_secret: Secret::new(client_secret.clone()),
+            }),
+            (None, None, None) => None,
+            _ => {
+                warn!("token_url, client_id and client_secret must be configured together");
+                None
+            }
+        };
         ProviderConfig {
-            values: values.clone(),
+            values: values
+                .iter()
+                .filter(|(key, _)| !SECRET_KEYS.contains(&key.as_str()))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+            data_api_url: values.get("data_api_url").cloned(),
+            batch_window: parse(values, "batch_window_ms")
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.batch_window),
+            max_batch_size: parse(values, "max_batch_size")
+                .filter(|size| *size > 0)
+                .unwrap_or(defaults.max_batch_size),
+            batching: parse(values, "batching").unwrap_or(defaults.batching),
+            request_timeout: parse(values, "request_timeout_ms")
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.request_timeout),
+            service_credentials,
+            breaker: BreakerConfig {
+                failure_threshold: parse::<u8>(values, "breaker_failure_threshold")
+                    .filter(|percentage| (1..=100).contains(percentage))
+                    .map(|percentage| f64::from(percentage) / 100.0)
+                    .unwrap_or(defaults.breaker.failure_threshold),
+                window: parse(values, "breaker_window")
+                    .filter(|window| *window > 0)
+                    .unwrap_or(defaults.breaker.window),
+                min_calls: parse(values, "breaker_min_calls").unwrap_or(defaults.breaker.min_calls),
+                cooldown: parse(values, "breaker_cooldown_ms")
+                    .map(Duration::from_millis)
+                    .unwrap_or(defaults.breaker.cooldown),
+            },
+            events_url: values.get("events_url").cloned(),
+            events_poll_interval: parse(values, "events_poll_interval_ms")
+                .filter(|interval| *interval > 0)
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.events_poll_interval),
+        }
+    }
+}
+
+fn parse<T: std::str::FromStr>(values: &HashMap<String, String>, key: &str) -> Option<T> {
+    let value = values.get(key)?;
+    match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            warn!(key, value, "ignoring invalid config value");
+            None
         }
     }
 }
@@ -0,0 +1,224 @@
+//! Change notifications from the Data API.
+//!
+//! The provider polls the Data API change feed and delivers every change to the components
+//! it is linked to. The feed is read with a cursor, so every poll only returns the changes
+//! since the previous one.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::auth::Secret;
+use crate::breaker::CircuitBreaker;
+use crate::upstream::UpstreamError;
+
+/// Config of the links to a component, by link name
+pub type LinkConfigs = HashMap<String, HashMap<String, String>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+/// A single change as returned by the change feed.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangeEvent {
+    pub model: String,
+    pub record_id: String,
+    pub kind: ChangeKind,
+    /// The record after the change, absent for deletions
+    #[serde(default)]
+    pub data: Option<serde_json::Value>,
+    pub changed_at: String,
+}
+
+#[derive(Deserialize)]
+struct FeedPage {
+    events: Vec<ChangeEvent>,
+    cursor: Option<String>,
+}
+
+pub struct EventFeed {
+    http: reqwest::Client,
+    url: String,
+    breaker: Arc<CircuitBreaker>,
+    /// Position in the feed, `None` until the first poll which starts at the latest change
+    cursor: Option<String>,
+}
+
+impl EventFeed {
+    pub fn new(
+        url: String,
+        timeout: Duration,
+        breaker: Arc<CircuitBreaker>,
+    ) -> anyhow::Result<Self> {
+        let http = reqwest::Client::builder().timeout(timeout).build()?;
+        Ok(EventFeed {
+            http,
+            url,
+            breaker,
+            cursor: None,
+        })
+    }
+
+    /// Fetch the changes since the previous poll. The cursor only moves forward when the page
+    /// was read, so a failed poll is retried from the same position.
+    pub async fn poll(
+        &mut self,
+        token: Option<&Secret>,
+    ) -> Result<Vec<ChangeEvent>, UpstreamError> {
+        let permit = self
+            .breaker
+            .try_acquire()
+            .map_err(|retry_after| UpstreamError::CircuitOpen { retry_after })?;
+        let page = self.fetch(token).await;
+        permit.record(!matches!(&page, Err(e) if e.is_upstream_failure()));
+
+        let page = page?;
+        if page.cursor.is_some() {
+            self.cursor = page.cursor;
+        }
+        Ok(page.events)
+    }
+
+    async fn fetch(&self, token: Option<&Secret>) -> Result<FeedPage, UpstreamError> {
+        let mut request = self.http.get(&self.url);
+        if let Some(cursor) = &self.cursor {
+            request = request.query(&[("cursor", cursor)]);
+        }
+        if let Some(token) = token {
+            request = request.bearer_auth(token.expose());
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| UpstreamError::Transport(e.to_string()))?;
+
+        let status = response.status();
+        if status.is_client_error() {
+            return Err(UpstreamError::Rejected(format!(
+                "responded with {}",
+                status
+            )));
+        }
+        if !status.is_success() {
+            return Err(UpstreamError::Transport(format!(
+                "responded with {}",
+                status
+            )));
+        }
+        response
+            .json()
+            .await
+            .map_err(|e| UpstreamError::InvalidResponse(e.to_string()))
+    }
+}
+
+/// Whether a linked component wants changes of the model, based on the comma separated
+/// `models` in its link config. Without `models` a component receives every change.
+pub fn subscribed(link_config: &HashMap<String, String>, model: &str) -> bool {
+    match link_config.get("models") {
+        Some(models) => models.split(',').any(|m| m.trim() == model),
+        None => true,
+    }
+}
+
+/// The links a change of `model` is delivered over, as component id and link name, for the
+/// links of every linked component by its id
+pub fn targets(linked_to: &HashMap<String, LinkConfigs>, model: &str) -> Vec<(String, String)> {
+    let mut targets: Vec<(String, String)> = linked_to
+        .iter()
+        .flat_map(|(component_id, links)| {
+            links
+                .iter()
+                .filter(|(_, config)| subscribed(config, model))
+                .map(|(link_name, _)| (component_id.clone(), link_name.clone()))
+        })
+        .collect();
+    targets.sort();
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscribed_without_models_receives_everything() {
+        assert!(subscribed(&HashMap::new(), "Order"));
+    }
+
+    #[test]
+    fn test_subscribed_to_listed_models() {
+        let config = HashMap::from([("models".to_string(), "Order, Customer".to_string())]);
+        assert!(subscribed(&config, "Order"));
+        assert!(subscribed(&config, "Customer"));
+        assert!(!subscribed(&config, "Invoice"));
+    }
+
+    fn links(links: &[(&str, Option<&str>)]) -> LinkConfigs {
+        links
+            .iter()
+            .map(|(name, models)| {
+                let config = models
+                    .map(|models| HashMap::from([("models".to_string(), models.to_string())]))
+                    .unwrap_or_default();
+                (name.to_string(), config)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_targets_every_subscribed_link() {
+        let linked_to = HashMap::from([
+            (
+                "action-runner".to_string(),
+                links(&[("default", Some("Order")), ("invoices", Some("Invoice"))]),
+            ),
+            ("audit-log".to_string(), links(&[("default", None)])),
+            (
+                "mailer".to_string(),
+                links(&[("default", Some("Customer"))]),
+            ),
+        ]);
+        assert_eq!(
+            targets(&linked_to, "Order"),
+            vec![
+                ("action-runner".to_string(), "default".to_string()),
+                ("audit-log".to_string(), "default".to_string()),
+            ]
+        );
+        // a component gets the change over the link subscribed to it, not over its others
+        assert_eq!(
+            targets(&linked_to, "Invoice"),
+            vec![
+                ("action-runner".to_string(), "invoices".to_string()),
+                ("audit-log".to_string(), "default".to_string()),
+            ]
+        );
+        assert_eq!(targets(&HashMap::new(), "Order"), vec![]);
+    }
+
+    #[test]
+    fn test_parse_feed_page() {
+        let page: FeedPage = serde_json::from_str(
+            r#"{
+                "events": [
+                    {"model": "Order", "recordId": "12", "kind": "updated", "data": {"id": 12}, "changedAt": "2025-01-10T12:00:00Z"},
+                    {"model": "Order", "recordId": "13", "kind": "deleted", "changedAt": "2025-01-10T12:00:01Z"}
+                ],
+                "cursor": "c-2"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(page.cursor.as_deref(), Some("c-2"));
+        assert_eq!(page.events[0].kind, ChangeKind::Updated);
+        assert_eq!(page.events[1].data, None);
+    }
+}
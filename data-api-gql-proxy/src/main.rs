@@ -4,8 +4,15 @@
 //! for building a capability provider with a custom interface. Take note of the documentation
 //! comments in the code to understand how to build a capability provider.
 
+mod auth;
+mod batch;
+mod breaker;
 mod config;
+mod events;
 mod provider;
+#[cfg(test)]
+mod test_server;
+mod upstream;
 
 use provider::CustomTemplateProvider;
 
@@ -1,16 +1,25 @@
 use std::collections::HashMap;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::Context as _;
 use tokio::sync::RwLock;
-use tracing::{debug, error, info};
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, info, warn};
 use wasmcloud_provider_sdk::initialize_observability;
 use wasmcloud_provider_sdk::{
     run_provider, serve_provider_exports, Context, LinkConfig, LinkDeleteInfo, Provider,
     ProviderInitConfig,
 };
 
+use crate::auth::{AuthMode, Secret, TokenExchange};
+use crate::batch::Batcher;
+use crate::breaker::{Breakers, CircuitState, CircuitStatus};
 use crate::config::ProviderConfig;
+use crate::events::{self, ChangeEvent, ChangeKind, EventFeed, LinkConfigs};
+use crate::upstream::{DataApiClient, GraphqlRequest, UpstreamError};
 
 pub(crate) mod bindings {
     wit_bindgen_wrpc::generate!();
@@ -18,12 +27,18 @@ pub(crate) mod bindings {
 
 // The code generated by `wit-bindgen-wrpc` for your exports follow a pattern:
 // crate::<world_name>::exports::<namespace>::<package>::<interface>::*
-use bindings::exports::bettyblocks::runtime_cloud::system_info::Handler;
-use bindings::exports::bettyblocks::runtime_cloud::system_info::Kind;
+use bindings::exports::bettyblocks::runtime_cloud::data_api::Handler as DataApiHandler;
+use bindings::exports::bettyblocks::runtime_cloud::data_api::{QueryError, QueryRequest};
+use bindings::exports::bettyblocks::runtime_cloud::provider_status::Handler as ProviderStatusHandler;
+use bindings::exports::bettyblocks::runtime_cloud::provider_status::{
+    BatchStats, CircuitState as WitCircuitState, ProviderInfo, TokenCacheStats, UpstreamStatus,
+};
 // The code generated by `wit-bindgen-wrpc` for your imports follow a pattern:
 // crate::<world_name>::<namespace>::<package>::<interface>::*
-use bindings::bettyblocks::runtime_cloud::process_data;
-use bindings::bettyblocks::runtime_cloud::process_data::Data;
+use bindings::bettyblocks::runtime_cloud::record_events;
+use bindings::bettyblocks::runtime_cloud::record_events::{
+    ChangeKind as WitChangeKind, RecordChanged,
+};
 
 #[derive(Default, Clone)]
 /// Your provider struct is where you can store any state or configuration that your provider needs to keep track of.
@@ -31,8 +46,27 @@ pub struct CustomTemplateProvider {
     config: Arc<RwLock<ProviderConfig>>,
     /// All components linked to this provider and their config.
     linked_from: Arc<RwLock<HashMap<String, HashMap<String, String>>>>,
-    /// All components this provider is linked to and their config
-    linked_to: Arc<RwLock<HashMap<String, HashMap<String, String>>>>,
+    /// All components this provider is linked to, with the config of every link by its name
+    linked_to: Arc<RwLock<HashMap<String, LinkConfigs>>>,
+    /// Coalesces Data API requests, only set once a `data_api_url` is configured
+    batcher: Arc<RwLock<Option<Batcher>>>,
+    /// Exchanges caller tokens for service tokens, only set once service credentials are configured
+    token_exchange: Arc<RwLock<Option<TokenExchange>>>,
+    /// Circuit breakers of the Data API and the token endpoint
+    breakers: Arc<RwLock<Breakers>>,
+    started_at: StartedAt,
+    /// Task polling the Data API change feed, only running once an `events_url` is configured
+    event_poller: Arc<RwLock<Option<JoinHandle<()>>>>,
+}
+
+/// When the provider was created, for the uptime in the status export
+#[derive(Clone, Copy)]
+struct StartedAt(Instant);
+
+impl Default for StartedAt {
+    fn default() -> Self {
+        StartedAt(Instant::now())
+    }
 }
 
 /// This `impl` block is where you can implement additional methods for your provider. We've provided two examples
@@ -75,76 +109,289 @@ impl CustomTemplateProvider {
     }
 }
 
-// NOTE: These `use` statements by convention go at the top of the file, but they are included here for clarity.
+impl From<UpstreamError> for QueryError {
+    fn from(err: UpstreamError) -> Self {
+        match err {
+            UpstreamError::Transport(_) | UpstreamError::Rejected(_) => {
+                QueryError::Upstream(err.to_string())
+            }
+            UpstreamError::InvalidResponse(_) => QueryError::InvalidResponse(err.to_string()),
+            UpstreamError::CircuitOpen { retry_after } => {
+                QueryError::CircuitOpen(retry_after.as_millis() as u64)
+            }
+        }
+    }
+}
 
-/// When a provider specifies an `export` in its `wit/world.wit` file, the `wit-bindgen-wrpc` tool generates
-/// a trait that the provider must implement. This trait is used to handle invocations from components that
-/// link to the provider. The `Handler` trait is generated for each export in the WIT world.
-impl Handler<Option<Context>> for CustomTemplateProvider {
-    /// Request information about the system the provider is running on
-    async fn request_info(&self, ctx: Option<Context>, kind: Kind) -> anyhow::Result<String> {
-        // The `ctx` contains information about the component that invoked the request. You can use
-        // this information to look up the configuration of the component that invoked the request.
-        let requesting_component = ctx
-            .and_then(|c| c.component)
-            .unwrap_or_else(|| "UNKNOWN".to_string());
-        let component_config = self
+impl From<CircuitStatus> for UpstreamStatus {
+    fn from(status: CircuitStatus) -> Self {
+        UpstreamStatus {
+            upstream: status.upstream,
+            state: match status.state {
+                CircuitState::Closed => WitCircuitState::Closed,
+                CircuitState::Open => WitCircuitState::Open,
+                CircuitState::HalfOpen => WitCircuitState::HalfOpen,
+            },
+            reachable: status.state != CircuitState::Open,
+            failure_rate: status.failure_rate,
+            recent_calls: status.recent_calls as u32,
+            retry_after_ms: status
+                .retry_after
+                .map(|retry_after| retry_after.as_millis() as u64),
+        }
+    }
+}
+
+impl CustomTemplateProvider {
+    /// Resolve the token to call the Data API with, based on the `auth_mode` of the caller's link
+    async fn upstream_token(
+        &self,
+        requesting_component: &str,
+        caller_token: Option<Secret>,
+    ) -> Result<Option<Secret>, QueryError> {
+        let link_config = self
             .linked_from
             .read()
             .await
-            .get(&requesting_component)
+            .get(requesting_component)
             .cloned()
             .unwrap_or_default();
+        match AuthMode::from_link_config(&link_config).map_err(QueryError::Unauthorized)? {
+            AuthMode::Forward => Ok(caller_token),
+            AuthMode::Service {
+                allow_service_identity,
+            } => {
+                if caller_token.is_none() && !allow_service_identity {
+                    return Err(QueryError::Unauthorized(
+                        "no caller token, and the link doesn't allow calls as the service identity"
+                            .to_string(),
+                    ));
+                }
+                let Some(exchange) = self.token_exchange.read().await.clone() else {
+                    return Err(QueryError::Unauthorized(
+                        "service credentials are not configured".to_string(),
+                    ));
+                };
+                match exchange.service_token(caller_token.as_ref()).await {
+                    Ok(token) => Ok(Some(token)),
+                    Err(e @ UpstreamError::CircuitOpen { .. }) => Err(e.into()),
+                    Err(e) => Err(QueryError::Unauthorized(e.to_string())),
+                }
+            }
+        }
+    }
+}
 
-        info!(
-            requesting_component,
-            ?kind,
-            ?component_config,
-            "received request for system information"
-        );
+impl From<&ChangeEvent> for RecordChanged {
+    fn from(event: &ChangeEvent) -> Self {
+        RecordChanged {
+            model: event.model.clone(),
+            record_id: event.record_id.clone(),
+            kind: match event.kind {
+                ChangeKind::Created => WitChangeKind::Created,
+                ChangeKind::Updated => WitChangeKind::Updated,
+                ChangeKind::Deleted => WitChangeKind::Deleted,
+            },
+            data: event.data.as_ref().map(|data| data.to_string()),
+            changed_at: event.changed_at.clone(),
+        }
+    }
+}
+
+impl CustomTemplateProvider {
+    /// Poll the change feed until the provider shuts down, delivering every change to the
+    /// linked components
+    async fn poll_events(self, mut feed: EventFeed, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            // The feed is read as the provider's service identity, when it has one
+            let exchange = self.token_exchange.read().await.clone();
+            let token = match exchange {
+                Some(exchange) => match exchange.service_token(None).await {
+                    Ok(token) => Some(token),
+                    Err(e) => {
+                        warn!(%e, "no service token to poll the change feed");
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            match feed.poll(token.as_ref()).await {
+                Ok(changes) => {
+                    for change in &changes {
+                        self.deliver_event(change).await;
+                    }
+                }
+                Err(e) => warn!(%e, "failed to poll the change feed"),
+            }
+        }
+    }
+
+    /// Send a change over every link subscribed to its model. Delivery is at most once: a
+    /// change that fails to be delivered is logged and not sent again.
+    async fn deliver_event(&self, change: &ChangeEvent) {
+        let targets = events::targets(&*self.linked_to.read().await, &change.model);
+        let event = RecordChanged::from(change);
+        for (component_id, link_name) in targets {
+            let client = wasmcloud_provider_sdk::get_connection().get_wrpc_client(&component_id);
+            let mut headers = async_nats::HeaderMap::new();
+            headers.insert("link-name", link_name.as_str());
+            match record_events::on_record_changed(&client, Some(headers), &event).await {
+                Ok(Ok(())) => debug!(
+                    component_id,
+                    link_name,
+                    model = %change.model,
+                    record_id = %change.record_id,
+                    "delivered record change"
+                ),
+                Ok(Err(e)) => warn!(
+                    component_id,
+                    link_name,
+                    model = %change.model,
+                    record_id = %change.record_id,
+                    error = %e,
+                    "component failed to handle record change"
+                ),
+                Err(e) => error!(
+                    component_id,
+                    link_name,
+                    model = %change.model,
+                    ?e,
+                    "failed to send record change to component"
+                ),
+            }
+        }
+    }
+}
+
+fn parse_query_request(request: QueryRequest) -> Result<GraphqlRequest, QueryError> {
+    let variables = match request.variables {
+        Some(variables) => Some(serde_json::from_str(&variables).map_err(|e| {
+            QueryError::InvalidRequest(format!("variables are not valid JSON: {}", e))
+        })?),
+        None => None,
+    };
+    Ok(GraphqlRequest {
+        query: request.query,
+        variables,
+        operation_name: request.operation_name,
+    })
+}
 
-        let info = match kind {
-            Kind::Os => std::env::consts::OS,
-            Kind::Arch => std::env::consts::ARCH,
+impl DataApiHandler<Option<Context>> for CustomTemplateProvider {
+    /// Queue the request for the next batch to the Data API and wait for its response
+    async fn query(
+        &self,
+        ctx: Option<Context>,
+        request: QueryRequest,
+    ) -> anyhow::Result<Result<String, QueryError>> {
+        let requesting_component = ctx
+            .and_then(|c| c.component)
+            .unwrap_or_else(|| "UNKNOWN".to_string());
+        debug!(requesting_component, "received data api query");
+
+        // Never log the caller token, it is wrapped in a `Secret` right away
+        let caller_token = request.token.clone().map(Secret::new);
+        let request = match parse_query_request(request) {
+            Ok(request) => request,
+            Err(e) => return Ok(Err(e)),
+        };
+        let token = match self
+            .upstream_token(&requesting_component, caller_token)
+            .await
+        {
+            Ok(token) => token,
+            Err(e) => {
+                error!(
+                    requesting_component,
+                    ?e,
+                    "no credentials for data api query"
+                );
+                return Ok(Err(e));
+            }
         };
-        Ok(info.into())
+        let Some(batcher) = self.batcher.read().await.clone() else {
+            return Ok(Err(QueryError::Upstream(
+                "data_api_url is not configured".to_string(),
+            )));
+        };
+
+        match batcher.query(request, token).await {
+            Ok(response) => Ok(Ok(response.to_string())),
+            Err(e) => {
+                error!(requesting_component, %e, "data api query failed");
+                Ok(Err(e.into()))
+            }
+        }
     }
+}
 
-    /// Request the provider to send some data to all linked components
-    ///
-    /// This function is easy to invoke with `wash call`,
-    async fn call(&self, _ctx: Option<Context>) -> anyhow::Result<String> {
-        info!("received call to send data to linked components");
-        let mut last_response = None;
-        for (component_id, config) in self.linked_to.read().await.iter() {
-            debug!(component_id, ?config, "sending data to component");
-            let sample_data = Data {
-                name: "sup".to_string(),
-                count: 3,
-            };
-            let client = wasmcloud_provider_sdk::get_connection().get_wrpc_client(component_id);
-            match process_data::process(&client, None, &sample_data).await {
-                Ok(response) => {
-                    last_response = Some(response);
-                    info!(
-                        component_id,
-                        ?config,
-                        ?last_response,
-                        "successfully sent data to component"
-                    );
+/// When a provider specifies an `export` in its `wit/world.wit` file, the `wit-bindgen-wrpc` tool generates
+/// a trait that the provider must implement. This trait is used to handle invocations from components that
+/// link to the provider. The `Handler` trait is generated for each export in the WIT world.
+impl ProviderStatusHandler<Option<Context>> for CustomTemplateProvider {
+    /// Report the health of the provider and its upstreams
+    async fn status(&self, ctx: Option<Context>) -> anyhow::Result<ProviderInfo> {
+        let requesting_component = ctx
+            .and_then(|c| c.component)
+            .unwrap_or_else(|| "UNKNOWN".to_string());
+        debug!(requesting_component, "received status request");
+
+        let mut linked_from: Vec<String> = self.linked_from.read().await.keys().cloned().collect();
+        linked_from.sort();
+        let mut linked_to: Vec<String> = self.linked_to.read().await.keys().cloned().collect();
+        linked_to.sort();
+
+        let batching = match self.batcher.read().await.as_ref() {
+            Some(batcher) => {
+                let stats = batcher.stats();
+                BatchStats {
+                    requests: stats.requests.load(Ordering::Relaxed),
+                    batches: stats.batches.load(Ordering::Relaxed),
+                    coalesced: stats.coalesced.load(Ordering::Relaxed),
                 }
-                Err(e) => {
-                    error!(
-                        component_id,
-                        ?config,
-                        ?e,
-                        "failed to send data to component"
-                    );
+            }
+            None => BatchStats {
+                requests: 0,
+                batches: 0,
+                coalesced: 0,
+            },
+        };
+        let exchange = self.token_exchange.read().await.clone();
+        let token_cache = match exchange {
+            Some(exchange) => {
+                let stats = exchange.stats().await;
+                TokenCacheStats {
+                    cached_tokens: stats.cached_tokens as u32,
+                    hits: stats.hits,
+                    misses: stats.misses,
                 }
             }
-        }
+            None => TokenCacheStats {
+                cached_tokens: 0,
+                hits: 0,
+                misses: 0,
+            },
+        };
 
-        Ok(last_response.unwrap_or_else(|| "No components responded to request".to_string()))
+        Ok(ProviderInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            uptime_ms: self.started_at.0.elapsed().as_millis() as u64,
+            linked_from,
+            linked_to,
+            upstreams: self
+                .breakers
+                .read()
+                .await
+                .status()
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            batching,
+            token_cache,
+        })
     }
 }
 
@@ -157,10 +404,53 @@ impl Provider for CustomTemplateProvider {
     async fn init(&self, config: impl ProviderInitConfig) -> anyhow::Result<()> {
         let provider_id = config.get_provider_id();
         let initial_config = config.get_config();
-        info!(provider_id, ?initial_config, "initializing provider");
+        // Only log the keys, the values include credentials
+        let config_keys: Vec<&String> = initial_config.keys().collect();
+        info!(provider_id, ?config_keys, "initializing provider");
 
         // Save configuration to provider state
-        *self.config.write().await = ProviderConfig::from(initial_config);
+        let config = ProviderConfig::from(initial_config);
+        let breakers = Breakers::new(config.breaker);
+        match &config.data_api_url {
+            Some(url) => {
+                let client = DataApiClient::new(
+                    url.clone(),
+                    config.request_timeout,
+                    breakers.for_upstream(url),
+                )
+                .context("failed to build data api client")?;
+                *self.batcher.write().await = Some(Batcher::spawn(
+                    client,
+                    config.batch_window,
+                    config.max_batch_size,
+                    config.batching,
+                ));
+            }
+            None => warn!("no data_api_url configured, data api queries will fail"),
+        }
+        if let Some(credentials) = &config.service_credentials {
+            let exchange = TokenExchange::new(
+                credentials.clone(),
+                config.request_timeout,
+                breakers.for_upstream(&credentials.token_url),
+            )
+            .context("failed to build token exchange client")?;
+            *self.token_exchange.write().await = Some(exchange);
+        }
+        if let Some(url) = &config.events_url {
+            let feed = EventFeed::new(
+                url.clone(),
+                config.request_timeout,
+                breakers.for_upstream(url),
+            )
+            .context("failed to build change feed client")?;
+            let poller = tokio::spawn(self.clone().poll_events(feed, config.events_poll_interval));
+            if let Some(previous) = self.event_poller.write().await.replace(poller) {
+                previous.abort();
+            }
+        }
+        *self.breakers.write().await = breakers;
+        *self.config.write().await = config;
 
         Ok(())
     }
@@ -175,19 +465,23 @@ impl Provider for CustomTemplateProvider {
     async fn receive_link_config_as_source(
         &self,
         LinkConfig {
-            target_id, config, ..
+            target_id,
+            link_name,
+            config,
+            ..
         }: LinkConfig<'_>,
     ) -> anyhow::Result<()> {
-        // We're storing the configuration as an example of how to keep track of linked components, but
-        // the provider SDK does not require you to store this information.
+        // Record changes are delivered over every link, by its name
         self.linked_to
             .write()
             .await
-            .insert(target_id.to_string(), config.to_owned());
+            .entry(target_id.to_string())
+            .or_default()
+            .insert(link_name.to_string(), config.to_owned());
 
         debug!(
-            "finished processing link from provider to component [{}]",
-            target_id
+            "finished processing link [{}] from provider to component [{}]",
+            link_name, target_id
         );
         Ok(())
     }
@@ -223,11 +517,18 @@ impl Provider for CustomTemplateProvider {
     /// associated with the linked component.
     async fn delete_link_as_source(&self, link: impl LinkDeleteInfo) -> anyhow::Result<()> {
         let target = link.get_target_id();
-        self.linked_to.write().await.remove(target);
+        let link_name = link.get_link_name();
+        let mut linked_to = self.linked_to.write().await;
+        if let Some(links) = linked_to.get_mut(target) {
+            links.remove(link_name);
+            if links.is_empty() {
+                linked_to.remove(target);
+            }
+        }
 
         debug!(
-            "finished processing delete link from provider to component [{}]",
-            target
+            "finished processing delete link [{}] from provider to component [{}]",
+            link_name, target
         );
         Ok(())
     }
@@ -249,9 +550,66 @@ impl Provider for CustomTemplateProvider {
     /// Handle shutdown request by cleaning out all linked components. This is a good place to clean up any
     /// resources or connections your provider has established.
     async fn shutdown(&self) -> anyhow::Result<()> {
+        if let Some(poller) = self.event_poller.write().await.take() {
+            poller.abort();
+        }
         self.linked_from.write().await.clear();
         self.linked_to.write().await.clear();
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn provider_linked_from(
+        component: &str,
+        config: &[(&str, &str)],
+    ) -> CustomTemplateProvider {
+        let provider = CustomTemplateProvider::default();
+        provider.linked_from.write().await.insert(
+            component.to_string(),
+            config
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        );
+        provider
+    }
+
+    fn unauthorized(result: Result<Option<Secret>, QueryError>) -> String {
+        match result {
+            Err(QueryError::Unauthorized(message)) => message,
+            other => panic!("expected unauthorized, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forward_mode_passes_caller_token() {
+        let provider = provider_linked_from("action", &[]).await;
+        let token = Secret::new("caller-token".to_string());
+        let upstream = provider.upstream_token("action", Some(token.clone())).await;
+        assert!(matches!(upstream, Ok(Some(upstream)) if upstream == token));
+    }
+
+    #[tokio::test]
+    async fn test_service_mode_rejects_calls_without_caller_token() {
+        let provider = provider_linked_from("action", &[("auth_mode", "service")]).await;
+        let message = unauthorized(provider.upstream_token("action", None).await);
+        assert!(message.contains("no caller token"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn test_service_mode_allows_service_identity_when_configured() {
+        let provider = provider_linked_from(
+            "action",
+            &[("auth_mode", "service"), ("allow_service_identity", "true")],
+        )
+        .await;
+        // gets to the exchange, which needs credentials
+        let message = unauthorized(provider.upstream_token("action", None).await);
+        assert!(message.contains("service credentials"), "{}", message);
+    }
+}
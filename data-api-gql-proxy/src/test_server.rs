@@ -0,0 +1,52 @@
+//! A minimal HTTP server standing in for the upstreams in tests.
+
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Bodies of the requests the server received
+pub type Received = Arc<Mutex<Vec<String>>>;
+
+/// Serve every request with the status and JSON body `respond` returns for its body, returning
+/// the URL of the server.
+pub async fn serve(respond: impl Fn(&str) -> (u16, String) + Send + 'static) -> (String, Received) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let received = Received::default();
+    let requests = received.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            // read the headers, then the body up to its content length
+            let body_start = loop {
+                let read = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..read]);
+                if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break end + 4;
+                }
+            };
+            let head = String::from_utf8_lossy(&request[..body_start]).to_lowercase();
+            let length: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .map_or(0, |length| length.trim().parse().unwrap());
+            while request.len() < body_start + length {
+                let read = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..read]);
+            }
+            let body = String::from_utf8_lossy(&request[body_start..]).to_string();
+            let (status, response) = respond(&body);
+            requests.lock().unwrap().push(body);
+            let response = format!(
+                "HTTP/1.1 {} Status\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                status,
+                response.len(),
+                response
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+    (url, received)
+}
@@ -0,0 +1,191 @@
+//! HTTP client for the Betty Blocks Data API.
+//!
+//! [`UpstreamError`] is shared with the other upstreams of this provider, like the token endpoint.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::Value;
+use tracing::debug;
+
+use crate::auth::Secret;
+use crate::breaker::CircuitBreaker;
+
+/// A single GraphQL request as sent over HTTP.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GraphqlRequest {
+    pub query: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variables: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operation_name: Option<String>,
+}
+
+/// Errors talking to the Data API. These are cloned to every caller that shared a batch.
+#[derive(Debug, Clone, PartialEq)]
+pub enum UpstreamError {
+    /// The upstream could not be reached or responded with a server error status
+    Transport(String),
+    /// The upstream responded with something that is not a valid response
+    InvalidResponse(String),
+    /// The upstream refused the request with a client error status
+    Rejected(String),
+    /// The upstream is considered down, the call was not attempted
+    CircuitOpen { retry_after: Duration },
+}
+
+impl UpstreamError {
+    /// Whether the error says something about the health of the upstream
+    pub fn is_upstream_failure(&self) -> bool {
+        matches!(
+            self,
+            UpstreamError::Transport(_) | UpstreamError::InvalidResponse(_)
+        )
+    }
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamError::Transport(msg) => write!(f, "upstream unavailable: {}", msg),
+            UpstreamError::InvalidResponse(msg) => write!(f, "invalid upstream response: {}", msg),
+            UpstreamError::Rejected(msg) => write!(f, "upstream rejected the request: {}", msg),
+            UpstreamError::CircuitOpen { retry_after } => write!(
+                f,
+                "upstream circuit is open, retry in {}ms",
+                retry_after.as_millis()
+            ),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DataApiClient {
+    http: reqwest::Client,
+    url: String,
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl DataApiClient {
+    pub fn new(
+        url: String,
+        timeout: Duration,
+        breaker: Arc<CircuitBreaker>,
+    ) -> anyhow::Result<Self> {
+        let http = reqwest::Client::builder().timeout(timeout).build()?;
+        Ok(DataApiClient { http, url, breaker })
+    }
+
+    /// Send the requests to the Data API, returning the result of every request in the same
+    /// order.
+    ///
+    /// With `batching` more than one request is sent as an array batch, otherwise every request
+    /// is sent on its own. A Data API that rejects the batch or doesn't answer it with an array
+    /// doesn't support batches, the requests are then sent one by one instead. Any other error
+    /// of the batch is the result of every request.
+    pub async fn execute_batch(
+        &self,
+        requests: &[GraphqlRequest],
+        token: Option<&Secret>,
+        batching: bool,
+    ) -> Vec<Result<Value, UpstreamError>> {
+        if batching && requests.len() > 1 {
+            let shared = match self.post(&requests, token).await {
+                Ok(Value::Array(responses)) if responses.len() == requests.len() => {
+                    return responses.into_iter().map(Ok).collect();
+                }
+                Ok(Value::Array(responses)) => UpstreamError::InvalidResponse(format!(
+                    "expected {} responses in batch, got {}",
+                    requests.len(),
+                    responses.len()
+                )),
+                Ok(_) | Err(UpstreamError::Rejected(_)) => {
+                    debug!("data api doesn't accept the batch, sending its requests one by one");
+                    return self.execute_each(requests, token).await;
+                }
+                Err(e) => e,
+            };
+            return vec![Err(shared); requests.len()];
+        }
+        self.execute_each(requests, token).await
+    }
+
+    /// Send every request on its own, concurrently.
+    async fn execute_each(
+        &self,
+        requests: &[GraphqlRequest],
+        token: Option<&Secret>,
+    ) -> Vec<Result<Value, UpstreamError>> {
+        let calls: Vec<_> = requests
+            .iter()
+            .map(|request| {
+                let client = self.clone();
+                let request = request.clone();
+                let token = token.cloned();
+                tokio::spawn(async move { client.post(&request, token.as_ref()).await })
+            })
+            .collect();
+        let mut results = Vec::with_capacity(calls.len());
+        for call in calls {
+            results.push(call.await.unwrap_or_else(|e| {
+                Err(UpstreamError::Transport(format!(
+                    "request was dropped: {}",
+                    e
+                )))
+            }));
+        }
+        results
+    }
+
+    /// Post to the Data API through its circuit breaker.
+    async fn post(
+        &self,
+        body: &impl Serialize,
+        token: Option<&Secret>,
+    ) -> Result<Value, UpstreamError> {
+        let permit = self
+            .breaker
+            .try_acquire()
+            .map_err(|retry_after| UpstreamError::CircuitOpen { retry_after })?;
+        let result = self.send(body, token).await;
+        permit.record(!matches!(&result, Err(e) if e.is_upstream_failure()));
+        result
+    }
+
+    async fn send(
+        &self,
+        body: &impl Serialize,
+        token: Option<&Secret>,
+    ) -> Result<Value, UpstreamError> {
+        let mut request = self.http.post(&self.url).json(body);
+        if let Some(token) = token {
+            request = request.bearer_auth(token.expose());
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| UpstreamError::Transport(e.to_string()))?;
+
+        let status = response.status();
+        if status.is_client_error() {
+            return Err(UpstreamError::Rejected(format!(
+                "responded with {}",
+                status
+            )));
+        }
+        if !status.is_success() {
+            return Err(UpstreamError::Transport(format!(
+                "responded with {}",
+                status
+            )));
+        }
+
+        response
+            .json()
+            .await
+            .map_err(|e| UpstreamError::InvalidResponse(e.to_string()))
+    }
+}
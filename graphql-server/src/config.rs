@@ -0,0 +1,285 @@
+//! Config of this component.
+//!
+//! | Config                     | Default                       |                                                                     |
+//! | -------------------------- | ----------------------------- | ------------------------------------------------------------------- |
+//! | `introspection`            | `true`                        | Allow introspection queries and serve GraphiQL                      |
+//! | `max_batch_size`           | `10`                          | Most GraphQL requests in one batched request                        |
+//! | `max_body_size`            | `1048576`                     | Largest request body in bytes                                       |
+//! | `max_depth`                | `10`                          | Deepest nesting of fields in a query                                |
+//! | `max_introspection_depth`  | `15`                          | Deepest nesting of fields under an introspection field              |
+//! | `max_aliases`              | `15`                          | Most aliased fields in a query                                      |
+//! | `max_complexity`           | `250`                         | Highest cost of a query, every field costs 1 and a mutation 10 more |
+//! | `cors_origins`             |                               | Origins allowed for every application, `*` allows any origin        |
+//! | `cors_methods`             | `GET, POST`                   | Methods allowed by a preflight                                      |
+//! | `cors_headers`             | `Authorization, Content-Type` | Headers allowed by a preflight                                      |
+//! | `cors_credentials`         | `false`                       | Allow cookies and other credentials                                 |
+//! | `cors_max_age`             | `600`                         | Seconds browsers may cache a preflight                              |
+//! | `persisted_queries`        | `automatic`                   | `automatic` or `allow-list`                                         |
+//! | `persisted_queries_bucket` | `default`                     | Bucket automatic persisted queries are stored in                    |
+
+use crate::cors::Cors;
+use crate::limits::Limits;
+use crate::persisted::Mode;
+
+pub const INTROSPECTION_CONFIG: &str = "introspection";
+pub const MAX_BATCH_SIZE_CONFIG: &str = "max_batch_size";
+pub const MAX_BODY_SIZE_CONFIG: &str = "max_body_size";
+pub const MAX_DEPTH_CONFIG: &str = "max_depth";
+pub const MAX_INTROSPECTION_DEPTH_CONFIG: &str = "max_introspection_depth";
+pub const MAX_ALIASES_CONFIG: &str = "max_aliases";
+pub const MAX_COMPLEXITY_CONFIG: &str = "max_complexity";
+pub const CORS_ORIGINS_CONFIG: &str = "cors_origins";
+pub const CORS_METHODS_CONFIG: &str = "cors_methods";
+pub const CORS_HEADERS_CONFIG: &str = "cors_headers";
+pub const CORS_CREDENTIALS_CONFIG: &str = "cors_credentials";
+pub const CORS_MAX_AGE_CONFIG: &str = "cors_max_age";
+pub const PERSISTED_QUERIES_CONFIG: &str = "persisted_queries";
+pub const PERSISTED_QUERIES_BUCKET_CONFIG: &str = "persisted_queries_bucket";
+
+const DEFAULT_MAX_BATCH_SIZE: usize = 10;
+const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+const DEFAULT_MAX_DEPTH: usize = 10;
+/// Room for the type references GraphiQL nests in its introspection query
+const DEFAULT_MAX_INTROSPECTION_DEPTH: usize = 15;
+const DEFAULT_MAX_ALIASES: usize = 15;
+/// Room for GraphiQL's introspection query, which costs about 230
+const DEFAULT_MAX_COMPLEXITY: usize = 250;
+const DEFAULT_BUCKET: &str = "default";
+const DEFAULT_CORS_METHODS: &str = "GET, POST";
+const DEFAULT_CORS_HEADERS: &str = "Authorization, Content-Type";
+const DEFAULT_CORS_MAX_AGE: usize = 600;
+
+#[derive(Debug, PartialEq)]
+pub struct Config {
+    /// Disable in production, so the schemas of applications can't be explored
+    pub introspection: bool,
+    pub max_batch_size: usize,
+    /// Larger request bodies are rejected with `413 Payload Too Large`
+    pub max_body_size: usize,
+    pub limits: Limits,
+    pub cors: Cors,
+    /// In allow-list mode only the persisted queries of an application execute
+    pub persisted_queries: Mode,
+    pub persisted_queries_bucket: String,
+}
+
+impl Config {
+    /// Read the config with `get`, which looks up a config key.
+    pub fn from_config(
+        get: impl Fn(&str) -> Result<Option<String>, String>,
+    ) -> Result<Config, String> {
+        let config = Config {
+            introspection: flag(&get, INTROSPECTION_CONFIG, true)?,
+            max_batch_size: number(&get, MAX_BATCH_SIZE_CONFIG, DEFAULT_MAX_BATCH_SIZE)?,
+            max_body_size: number(&get, MAX_BODY_SIZE_CONFIG, DEFAULT_MAX_BODY_SIZE)?,
+            limits: Limits {
+                max_depth: number(&get, MAX_DEPTH_CONFIG, DEFAULT_MAX_DEPTH)?,
+                max_introspection_depth: number(
+                    &get,
+                    MAX_INTROSPECTION_DEPTH_CONFIG,
+                    DEFAULT_MAX_INTROSPECTION_DEPTH,
+                )?,
+                max_aliases: number(&get, MAX_ALIASES_CONFIG, DEFAULT_MAX_ALIASES)?,
+                max_complexity: number(&get, MAX_COMPLEXITY_CONFIG, DEFAULT_MAX_COMPLEXITY)?,
+            },
+            cors: Cors {
+                origins: list(&get, CORS_ORIGINS_CONFIG, "")?,
+                methods: list(&get, CORS_METHODS_CONFIG, DEFAULT_CORS_METHODS)?,
+                headers: list(&get, CORS_HEADERS_CONFIG, DEFAULT_CORS_HEADERS)?,
+                credentials: flag(&get, CORS_CREDENTIALS_CONFIG, false)?,
+                max_age: number(&get, CORS_MAX_AGE_CONFIG, DEFAULT_CORS_MAX_AGE)?,
+            },
+            persisted_queries: mode(&get, PERSISTED_QUERIES_CONFIG)?,
+            persisted_queries_bucket: get(PERSISTED_QUERIES_BUCKET_CONFIG)?
+                .filter(|bucket| !bucket.is_empty())
+                .unwrap_or_else(|| DEFAULT_BUCKET.to_string()),
+        };
+        // allowed origins are echoed, so with `*` any website could read responses with the
+        // cookies of its visitors, the CORS spec forbids credentials for any origin
+        if config.cors.credentials && config.cors.origins.iter().any(|origin| origin == "*") {
+            return Err(format!(
+                "Invalid `{}` config, `*` can't be allowed with `{}`",
+                CORS_ORIGINS_CONFIG, CORS_CREDENTIALS_CONFIG
+            ));
+        }
+        Ok(config)
+    }
+
+    /// Read the config of this component.
+    pub fn read() -> Result<Config, String> {
+        Config::from_config(|key| {
+            crate::wasi::config::runtime::get(key)
+                .map_err(|err| format!("Failed to read the `{}` config: {:?}", key, err))
+        })
+    }
+}
+
+fn flag(
+    get: &impl Fn(&str) -> Result<Option<String>, String>,
+    key: &str,
+    default: bool,
+) -> Result<bool, String> {
+    match get(key)?.as_deref() {
+        None | Some("") => Ok(default),
+        Some("true") => Ok(true),
+        Some("false") => Ok(false),
+        Some(value) => Err(format!(
+            "Invalid `{}` config `{}`, expected `true` or `false`",
+            key, value
+        )),
+    }
+}
+
+fn number(
+    get: &impl Fn(&str) -> Result<Option<String>, String>,
+    key: &str,
+    default: usize,
+) -> Result<usize, String> {
+    match get(key)?.as_deref() {
+        None | Some("") => Ok(default),
+        Some(value) => value
+            .parse()
+            .map_err(|_| format!("Invalid `{}` config `{}`, expected a number", key, value)),
+    }
+}
+
+/// A comma separated list
+fn list(
+    get: &impl Fn(&str) -> Result<Option<String>, String>,
+    key: &str,
+    default: &str,
+) -> Result<Vec<String>, String> {
+    let value = get(key)?.unwrap_or_else(|| default.to_string());
+    Ok(value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+fn mode(get: &impl Fn(&str) -> Result<Option<String>, String>, key: &str) -> Result<Mode, String> {
+    match get(key)?.as_deref() {
+        None | Some("") | Some("automatic") => Ok(Mode::Automatic),
+        Some("allow-list") => Ok(Mode::AllowList),
+        Some(value) => Err(format!(
+            "Invalid `{}` config `{}`, expected `automatic` or `allow-list`",
+            key, value
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config(values: &[(&str, &str)]) -> Result<Config, String> {
+        let values: HashMap<_, _> = values.iter().copied().collect();
+        Config::from_config(|key| Ok(values.get(key).map(|value| value.to_string())))
+    }
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(
+            config(&[]),
+            Ok(Config {
+                introspection: true,
+                max_batch_size: 10,
+                max_body_size: 1048576,
+                limits: Limits {
+                    max_depth: 10,
+                    max_introspection_depth: 15,
+                    max_aliases: 15,
+                    max_complexity: 250,
+                },
+                cors: Cors {
+                    origins: vec![],
+                    methods: vec!["GET".to_string(), "POST".to_string()],
+                    headers: vec!["Authorization".to_string(), "Content-Type".to_string()],
+                    credentials: false,
+                    max_age: 600,
+                },
+                persisted_queries: Mode::Automatic,
+                persisted_queries_bucket: "default".to_string(),
+            })
+        );
+    }
+
+    /// The introspection query of GraphiQL with every option enabled
+    const GRAPHIQL_INTROSPECTION: &str = "query IntrospectionQuery { __schema { description queryType { name } mutationType { name } subscriptionType { name } types { ...FullType } directives { name description isRepeatable locations args(includeDeprecated: true) { ...InputValue } } } } fragment FullType on __Type { kind name description specifiedByURL fields(includeDeprecated: true) { name description args(includeDeprecated: true) { ...InputValue } type { ...TypeRef } isDeprecated deprecationReason } inputFields(includeDeprecated: true) { ...InputValue } interfaces { ...TypeRef } enumValues(includeDeprecated: true) { name description isDeprecated deprecationReason } possibleTypes { ...TypeRef } } fragment InputValue on __InputValue { name description type { ...TypeRef } defaultValue isDeprecated deprecationReason } fragment TypeRef on __Type { kind name ofType { kind name ofType { kind name ofType { kind name ofType { kind name ofType { kind name ofType { kind name ofType { kind name ofType { kind name ofType { kind name } } } } } } } } } }";
+
+    struct Query;
+
+    #[juniper::graphql_object]
+    impl Query {
+        fn ping() -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_default_limits_allow_graphiql_introspection() {
+        let root = juniper::RootNode::new(
+            Query,
+            juniper::EmptyMutation::<()>::new(),
+            juniper::EmptySubscription::<()>::new(),
+        );
+        let mut limits = config(&[]).unwrap().limits;
+        assert!(crate::limits::check(GRAPHIQL_INTROSPECTION, &root.schema, &limits).is_ok());
+        // introspection is counted, not exempt
+        limits.max_complexity = 200;
+        assert!(crate::limits::check(GRAPHIQL_INTROSPECTION, &root.schema, &limits).is_err());
+    }
+
+    #[test]
+    fn test_disable_introspection() {
+        assert!(!config(&[("introspection", "false")]).unwrap().introspection);
+    }
+
+    #[test]
+    fn test_cors() {
+        let config = config(&[
+            (
+                "cors_origins",
+                "http://localhost:3000, https://admin.example.com",
+            ),
+            ("cors_credentials", "true"),
+        ])
+        .unwrap();
+        assert_eq!(
+            config.cors.origins,
+            vec!["http://localhost:3000", "https://admin.example.com"]
+        );
+        assert!(config.cors.credentials);
+    }
+
+    #[test]
+    fn test_rejects_any_origin_with_credentials() {
+        assert!(config(&[
+            ("cors_origins", "https://admin.example.com, *"),
+            ("cors_credentials", "true")
+        ])
+        .is_err());
+        assert!(config(&[("cors_origins", "*")]).is_ok());
+    }
+
+    #[test]
+    fn test_allow_list() {
+        let config = config(&[
+            ("persisted_queries", "allow-list"),
+            ("persisted_queries_bucket", "PERSISTEDQUERIES"),
+        ])
+        .unwrap();
+        assert_eq!(config.persisted_queries, Mode::AllowList);
+        assert_eq!(config.persisted_queries_bucket, "PERSISTEDQUERIES");
+    }
+
+    #[test]
+    fn test_rejects_invalid_flag() {
+        assert!(config(&[("introspection", "no")]).is_err());
+        assert!(config(&[("max_batch_size", "-1")]).is_err());
+        assert!(config(&[("persisted_queries", "all")]).is_err());
+        assert!(config(&[("max_depth", "deep")]).is_err());
+    }
+}
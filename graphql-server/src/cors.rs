@@ -0,0 +1,180 @@
+//! CORS, so the front-ends of applications can call `/graphql` from the browser.
+//!
+//! A preflight doesn't carry the JWT, so it can't tell which application is called. It is allowed
+//! for the configured origins and the origins of any application in the artefact. A request is
+//! only executed when its origin is allowed for the application of its JWT, or is the origin of
+//! the server itself. Browsers send an `Origin` with same-origin POSTs too, e.g. from GraphiQL.
+
+use serde_json::Value;
+
+use crate::http::{header, HeaderMap, HeaderValue};
+
+#[derive(Debug, PartialEq)]
+pub struct Cors {
+    /// Origins allowed for every application, `*` allows any origin
+    pub origins: Vec<String>,
+    pub methods: Vec<String>,
+    pub headers: Vec<String>,
+    /// Allow cookies and other credentials
+    pub credentials: bool,
+    /// Seconds browsers may cache a preflight
+    pub max_age: usize,
+}
+
+impl Cors {
+    /// Whether `origin` is allowed for every application
+    pub fn allows_everywhere(&self, origin: &str) -> bool {
+        self.origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+    }
+
+    /// Whether `origin` is allowed for an application with `app_origins`
+    pub fn allows(&self, origin: &str, app_origins: &[String]) -> bool {
+        self.allows_everywhere(origin)
+            || app_origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+    }
+
+    pub fn allows_method(&self, method: &str) -> bool {
+        self.methods
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(method))
+    }
+
+    /// Add the headers allowing `origin` to read a response.
+    pub fn allow_origin(
+        &self,
+        origin: &str,
+        headers: &mut HeaderMap,
+    ) -> Result<(), header::InvalidHeaderValue> {
+        // the origin is echoed, the config can't allow credentials for `*`
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            HeaderValue::from_str(origin)?,
+        );
+        if self.credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        Ok(())
+    }
+
+    /// Add the headers of a response to an allowed preflight.
+    pub fn allow_preflight(
+        &self,
+        origin: &str,
+        headers: &mut HeaderMap,
+    ) -> Result<(), header::InvalidHeaderValue> {
+        self.allow_origin(origin, headers)?;
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_str(&self.methods.join(", "))?,
+        );
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            HeaderValue::from_str(&self.headers.join(", "))?,
+        );
+        headers.insert(header::ACCESS_CONTROL_MAX_AGE, self.max_age.into());
+        Ok(())
+    }
+}
+
+/// Whether `origin` is the origin the request was sent to, `scheme://host` of its URI. Requests
+/// without a scheme are taken as `http`.
+pub fn same_origin(origin: &str, scheme: Option<&str>, host: Option<&str>) -> bool {
+    let Some(host) = host else {
+        return false;
+    };
+    let scheme = scheme.unwrap_or("http");
+    origin
+        .strip_prefix(scheme)
+        .and_then(|rest| rest.strip_prefix("://"))
+        .is_some_and(|origin_host| origin_host.eq_ignore_ascii_case(host))
+}
+
+/// The origins of an application in the artefact format
+pub fn from_application(application: &Value) -> Vec<String> {
+    application["origins"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|origin| origin.as_str().map(str::to_string))
+        .collect()
+}
+
+/// Every response depends on the `Origin` of the request, so caches must tell them apart.
+pub fn vary(headers: &mut HeaderMap) {
+    headers.append(header::VARY, HeaderValue::from_static("Origin"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors(origins: &[&str]) -> Cors {
+        Cors {
+            origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            methods: vec!["GET".to_string(), "POST".to_string()],
+            headers: vec!["Authorization".to_string(), "Content-Type".to_string()],
+            credentials: true,
+            max_age: 600,
+        }
+    }
+
+    #[test]
+    fn test_allows() {
+        let app_origins = vec!["https://myapp.example.com".to_string()];
+        let localhost = cors(&["http://localhost:3000"]);
+        assert!(localhost.allows("http://localhost:3000", &[]));
+        assert!(localhost.allows("https://MyApp.example.com", &app_origins));
+        assert!(!localhost.allows("https://myapp.example.com", &[]));
+        assert!(!localhost.allows("https://other.example.com", &app_origins));
+        assert!(cors(&["*"]).allows("https://other.example.com", &[]));
+    }
+
+    #[test]
+    fn test_same_origin() {
+        let host = Some("localhost:8000");
+        assert!(same_origin("http://localhost:8000", Some("http"), host));
+        assert!(same_origin("http://LocalHost:8000", None, host));
+        assert!(!same_origin("https://localhost:8000", Some("http"), host));
+        assert!(!same_origin("http://localhost:3000", Some("http"), host));
+        assert!(!same_origin(
+            "http://localhost:8000.example.com",
+            None,
+            host
+        ));
+        assert!(!same_origin("http://localhost:8000", None, None));
+    }
+
+    #[test]
+    fn test_allows_method() {
+        let cors = cors(&[]);
+        assert!(cors.allows_method("POST"));
+        assert!(!cors.allows_method("DELETE"));
+    }
+
+    #[test]
+    fn test_preflight_headers() {
+        let mut headers = HeaderMap::new();
+        cors(&[])
+            .allow_preflight("https://myapp.example.com", &mut headers)
+            .unwrap();
+        let header = |name| headers.get(name).unwrap().to_str().unwrap();
+        assert_eq!(
+            header(header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            "https://myapp.example.com"
+        );
+        assert_eq!(header(header::ACCESS_CONTROL_ALLOW_CREDENTIALS), "true");
+        assert_eq!(header(header::ACCESS_CONTROL_ALLOW_METHODS), "GET, POST");
+        assert_eq!(
+            header(header::ACCESS_CONTROL_ALLOW_HEADERS),
+            "Authorization, Content-Type"
+        );
+        assert_eq!(header(header::ACCESS_CONTROL_MAX_AGE), "600");
+    }
+}
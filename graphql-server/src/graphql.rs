@@ -0,0 +1,1372 @@
+//! The GraphQL schema of an application, generated from its actions in the cloud artefact.
+//!
+//! Every action is a mutation named after the action. The properties of its `input` schema are
+//! the arguments of the mutation and its `output` schema is the type of the result, a `String`
+//! when the action has none. Every action is a subscription as well, with the same arguments, which
+//! executes the action step by step and has an event with the progress of every step, and one
+//! with the result. Actions can also be queued with `executeAsync`, which returns a job to follow
+//! with the `job` query. Schemas are built at runtime, so they are cached per application
+//! and only rebuilt when the etag of the application changes.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use juniper::http::{GraphQLRequest, GraphQLResponse};
+use juniper::meta::{Field, MetaType};
+use juniper::{
+    Arguments, EmptyMutation, EmptySubscription, ExecutionResult, Executor, FieldError,
+    FromInputValue, GraphQLEnum, GraphQLObject, GraphQLType, GraphQLValue, InputValue, Registry,
+    RootNode, ScalarValue, ID,
+};
+use serde_json::Value;
+
+use crate::bettyblocks::runtime_cloud::action_runner::{
+    self, ActionJob, ActionRequest, ActionStep,
+};
+use crate::bettyblocks::runtime_cloud::meta_artefact::Application as StoredApplication;
+
+/// Names of the types every schema defines, generated types don't use them
+const RESERVED_NAMES: [&str; 14] = [
+    "Query",
+    "Mutation",
+    "Subscription",
+    "ActionProgress",
+    "AsyncMutation",
+    "Job",
+    "JobStatus",
+    "JobProgress",
+    "Application",
+    "String",
+    "Int",
+    "Float",
+    "Boolean",
+    "ID",
+];
+
+/// The mutation queueing actions, no action can have its name
+const EXECUTE_ASYNC: &str = "executeAsync";
+
+pub type Schema = RootNode<'static, Query, Mutation, Subscription>;
+
+/// Executes the events of subscriptions. Juniper only executes subscriptions asynchronously, so a
+/// subscription is executed as a query of this schema, once for every event.
+pub type EventSchema =
+    RootNode<'static, Subscription, EmptyMutation<Context>, EmptySubscription<Context>>;
+
+pub struct Context {
+    pub app_uuid: String,
+    /// JWT of the caller, passed on to actions so they call the Data API on their behalf
+    pub token: Option<String>,
+    /// Executes an action, the action runner outside of tests
+    pub runner: fn(&ActionRequest) -> Result<String, String>,
+    /// Executes the next step of an action, the action runner outside of tests
+    pub stepper: fn(&ActionRequest, Option<&str>) -> Result<ActionStep, String>,
+    /// Queues an action, returning the id of its job
+    pub enqueuer: fn(&ActionRequest) -> Result<String, String>,
+    /// Reads a job by its id
+    pub jobs: fn(&str) -> Result<Option<ActionJob>, String>,
+    /// How far the subscription executed with this context is, juniper requires a `Sync` context
+    steps: Mutex<Steps>,
+}
+
+#[derive(Debug, Default)]
+struct Steps {
+    /// State of the action after the last step
+    state: Option<String>,
+    /// Whether an event executed a step
+    stepped: bool,
+    /// Whether the action finished or failed
+    done: bool,
+}
+
+impl Context {
+    pub fn new(
+        app_uuid: String,
+        token: Option<String>,
+        runner: fn(&ActionRequest) -> Result<String, String>,
+        stepper: fn(&ActionRequest, Option<&str>) -> Result<ActionStep, String>,
+        enqueuer: fn(&ActionRequest) -> Result<String, String>,
+        jobs: fn(&str) -> Result<Option<ActionJob>, String>,
+    ) -> Context {
+        Context {
+            app_uuid,
+            token,
+            runner,
+            stepper,
+            enqueuer,
+            jobs,
+            steps: Mutex::default(),
+        }
+    }
+
+    fn steps(&self) -> MutexGuard<'_, Steps> {
+        self.steps.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl juniper::Context for Context {}
+
+/// The GraphQL type of a value, as described by the JSON Schema of an action
+#[derive(Debug, Clone)]
+pub enum Kind {
+    String,
+    Int,
+    Float,
+    Boolean,
+    Object(Rc<ObjectInfo>),
+    List(Box<Kind>),
+    Nullable(Box<Kind>),
+}
+
+#[derive(Debug)]
+pub struct ObjectInfo {
+    pub name: String,
+    pub description: Option<String>,
+    pub fields: Vec<FieldInfo>,
+}
+
+/// A field of an object, or an argument of a mutation
+#[derive(Debug)]
+pub struct FieldInfo {
+    pub name: String,
+    pub description: Option<String>,
+    pub kind: Kind,
+}
+
+#[derive(Debug)]
+pub struct ActionInfo {
+    pub uuid: String,
+    pub name: String,
+    pub component: Option<String>,
+    pub arguments: Vec<FieldInfo>,
+    pub output: Kind,
+    /// Type of the events of the subscription to the action
+    pub event: Kind,
+}
+
+/// Everything the schema of an application is generated from
+#[derive(Debug)]
+pub struct AppInfo {
+    pub uuid: String,
+    pub name: String,
+    pub actions: Vec<ActionInfo>,
+}
+
+impl AppInfo {
+    /// Read an application in the artefact format.
+    pub fn from_application(application: &Value) -> Result<AppInfo, String> {
+        let text = |value: &Value, name: &str| {
+            value[name]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| format!("Application has no {}", name))
+        };
+        let mut names: HashSet<String> = RESERVED_NAMES.iter().map(|n| n.to_string()).collect();
+        let progress = Kind::Nullable(Box::new(Kind::Object(Rc::new(ObjectInfo {
+            name: "ActionProgress".to_string(),
+            description: Some("Progress of an action after a step".to_string()),
+            fields: vec![
+                FieldInfo {
+                    name: "message".to_string(),
+                    description: None,
+                    kind: Kind::String,
+                },
+                FieldInfo {
+                    name: "percent".to_string(),
+                    description: Some("Between 0 and 100, when the action knows".to_string()),
+                    kind: Kind::Nullable(Box::new(Kind::Int)),
+                },
+            ],
+        }))));
+        let mut actions = vec![];
+        for action in application["actions"].as_array().into_iter().flatten() {
+            let name = text(action, "name")?;
+            if name == EXECUTE_ASYNC {
+                return Err(format!("Action name {} is reserved", EXECUTE_ASYNC));
+            }
+            let type_name = pascal_case(&name);
+            let arguments = match action.get("input") {
+                Some(input) => fields(input, &format!("{}Input", type_name), &mut names)?,
+                None => vec![],
+            };
+            let output = match action.get("output") {
+                Some(output) => kind(output, format!("{}Result", type_name), &mut names)?,
+                None => Kind::String,
+            };
+            // a failed action resolves to null, next to the error
+            let output = Kind::Nullable(Box::new(output));
+            let event = Kind::Object(Rc::new(ObjectInfo {
+                name: unique_name(format!("{}Event", type_name), &mut names),
+                description: Some(format!(
+                    "Progress of {}, or its result once it is done",
+                    name
+                )),
+                fields: vec![
+                    FieldInfo {
+                        name: "progress".to_string(),
+                        description: None,
+                        kind: progress.clone(),
+                    },
+                    FieldInfo {
+                        name: "result".to_string(),
+                        description: None,
+                        kind: output.clone(),
+                    },
+                ],
+            }));
+            actions.push(ActionInfo {
+                uuid: text(action, "uuid")?,
+                name,
+                component: action["component"].as_str().map(str::to_string),
+                arguments,
+                output,
+                event: Kind::Nullable(Box::new(event)),
+            });
+        }
+        Ok(AppInfo {
+            uuid: text(application, "uuid")?,
+            name: text(application, "name")?,
+            actions,
+        })
+    }
+}
+
+/// The kind of a value from its schema, objects are named `type_name`.
+fn kind(schema: &Value, type_name: String, names: &mut HashSet<String>) -> Result<Kind, String> {
+    match schema["type"].as_str() {
+        Some("string") => Ok(Kind::String),
+        Some("integer") => Ok(Kind::Int),
+        Some("number") => Ok(Kind::Float),
+        Some("boolean") => Ok(Kind::Boolean),
+        Some("array") => Ok(Kind::List(Box::new(kind(
+            &schema["items"],
+            type_name,
+            names,
+        )?))),
+        Some("object") => {
+            let name = unique_name(type_name, names);
+            let fields = fields(schema, &name, names)?;
+            Ok(Kind::Object(Rc::new(ObjectInfo {
+                name,
+                description: schema["description"].as_str().map(str::to_string),
+                fields,
+            })))
+        }
+        _ => Err(format!("Unsupported schema: {}", schema)),
+    }
+}
+
+/// The properties of an object schema, properties that aren't required are nullable.
+fn fields(
+    schema: &Value,
+    type_name: &str,
+    names: &mut HashSet<String>,
+) -> Result<Vec<FieldInfo>, String> {
+    let required: Vec<&str> = schema["required"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .collect();
+    let properties = schema["properties"]
+        .as_object()
+        .ok_or_else(|| format!("Object {} has no properties", type_name))?;
+    properties
+        .iter()
+        .map(|(name, property)| {
+            let kind = kind(
+                property,
+                format!("{}{}", type_name, pascal_case(name)),
+                names,
+            )?;
+            Ok(FieldInfo {
+                name: name.clone(),
+                description: property["description"].as_str().map(str::to_string),
+                kind: if required.contains(&name.as_str()) {
+                    kind
+                } else {
+                    Kind::Nullable(Box::new(kind))
+                },
+            })
+        })
+        .collect()
+}
+
+/// `name`, or `name` with a number when another type already has that name
+fn unique_name(name: String, names: &mut HashSet<String>) -> String {
+    let mut candidate = name.clone();
+    let mut number = 2;
+    while !names.insert(candidate.clone()) {
+        candidate = format!("{}{}", name, number);
+        number += 1;
+    }
+    candidate
+}
+
+fn pascal_case(name: &str) -> String {
+    name.split('_')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            chars
+                .next()
+                .map(|first| first.to_ascii_uppercase().to_string() + chars.as_str())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+fn object_field<'r, S: ScalarValue>(
+    registry: &mut Registry<'r, S>,
+    field: &FieldInfo,
+) -> Field<'r, S> {
+    let meta = registry.field::<Output>(&field.name, &field.kind);
+    match &field.description {
+        Some(description) => meta.description(description),
+        None => meta,
+    }
+}
+
+/// The root query, describing the application
+pub struct Query;
+
+/// The application the schema belongs to
+#[derive(GraphQLObject)]
+#[graphql(context = Context)]
+struct Application {
+    uuid: String,
+    name: String,
+}
+
+impl<S: ScalarValue> GraphQLType<S> for Query {
+    fn name(_info: &Rc<AppInfo>) -> Option<&str> {
+        Some("Query")
+    }
+
+    fn meta<'r>(info: &Rc<AppInfo>, registry: &mut Registry<'r, S>) -> MetaType<'r, S>
+    where
+        S: 'r,
+    {
+        let fields = [
+            registry.field::<Application>("application", &()),
+            registry
+                .field::<Option<Job>>("job", &())
+                .argument(registry.arg::<ID>("id", &()))
+                .description("An action queued with `executeAsync`, null when it isn't found"),
+        ];
+        registry
+            .build_object_type::<Query>(info, &fields)
+            .into_meta()
+    }
+}
+
+impl<S: ScalarValue> GraphQLValue<S> for Query {
+    type Context = Context;
+    type TypeInfo = Rc<AppInfo>;
+
+    fn type_name<'i>(&self, info: &'i Rc<AppInfo>) -> Option<&'i str> {
+        <Query as GraphQLType<S>>::name(info)
+    }
+
+    fn resolve_field(
+        &self,
+        info: &Rc<AppInfo>,
+        field_name: &str,
+        arguments: &Arguments<S>,
+        executor: &Executor<Context, S>,
+    ) -> ExecutionResult<S> {
+        match field_name {
+            "application" => executor.resolve_with_ctx(
+                &(),
+                &Application {
+                    uuid: info.uuid.clone(),
+                    name: info.name.clone(),
+                },
+            ),
+            "job" => {
+                let id: ID = arguments
+                    .get("id")?
+                    .ok_or_else(|| FieldError::from("A job id is required"))?;
+                let context = executor.context();
+                let job = (context.jobs)(&id)
+                    .map_err(FieldError::from)?
+                    // the jobs of other applications are not found
+                    .filter(|job| job.app_uuid.eq_ignore_ascii_case(&context.app_uuid))
+                    .map(|job| Job::new(info, job));
+                executor.resolve_with_ctx(&(), &job)
+            }
+            _ => Err(FieldError::from(format!("Unknown field {}", field_name))),
+        }
+    }
+}
+
+/// The root mutation, with a field for every action of the application
+pub struct Mutation;
+
+impl<S: ScalarValue> GraphQLType<S> for Mutation {
+    fn name(_info: &Rc<AppInfo>) -> Option<&str> {
+        Some("Mutation")
+    }
+
+    fn meta<'r>(info: &Rc<AppInfo>, registry: &mut Registry<'r, S>) -> MetaType<'r, S>
+    where
+        S: 'r,
+    {
+        let mut fields: Vec<_> = info
+            .actions
+            .iter()
+            .map(|action| action_field(registry, action, &action.output))
+            .collect();
+        fields.push(
+            registry
+                .field::<AsyncMutation>(EXECUTE_ASYNC, info)
+                .description("Queue an action to execute in the background"),
+        );
+        registry
+            .build_object_type::<Mutation>(info, &fields)
+            .into_meta()
+    }
+}
+
+impl<S: ScalarValue> GraphQLValue<S> for Mutation {
+    type Context = Context;
+    type TypeInfo = Rc<AppInfo>;
+
+    fn type_name<'i>(&self, info: &'i Rc<AppInfo>) -> Option<&'i str> {
+        <Mutation as GraphQLType<S>>::name(info)
+    }
+
+    fn resolve_field(
+        &self,
+        info: &Rc<AppInfo>,
+        field_name: &str,
+        arguments: &Arguments<S>,
+        executor: &Executor<Context, S>,
+    ) -> ExecutionResult<S> {
+        if field_name == EXECUTE_ASYNC {
+            return executor.resolve(info, &AsyncMutation);
+        }
+        let context = executor.context();
+        let (action, request) = action_request(info, field_name, arguments, context)?;
+        let result = (context.runner)(&request).map_err(FieldError::from)?;
+        let result = parse_result(action, &result)?;
+        executor.resolve(&action.output, &Output(result))
+    }
+}
+
+/// The field of an action with the arguments of the action, resolving to `kind`
+fn action_field<'r, S: ScalarValue>(
+    registry: &mut Registry<'r, S>,
+    action: &ActionInfo,
+    kind: &Kind,
+) -> Field<'r, S> {
+    let field = registry.field::<Output>(&action.name, kind);
+    with_arguments(registry, field, action)
+}
+
+fn with_arguments<'r, S: ScalarValue>(
+    registry: &mut Registry<'r, S>,
+    mut field: Field<'r, S>,
+    action: &ActionInfo,
+) -> Field<'r, S> {
+    for argument in &action.arguments {
+        let meta = registry.arg::<Input>(&argument.name, &argument.kind);
+        field = field.argument(match &argument.description {
+            Some(description) => meta.description(description),
+            None => meta,
+        });
+    }
+    field
+}
+
+/// The action a field executes, with the arguments of the field as its input
+fn action_request<'i, S: ScalarValue>(
+    info: &'i AppInfo,
+    field_name: &str,
+    arguments: &Arguments<S>,
+    context: &Context,
+) -> Result<(&'i ActionInfo, ActionRequest), FieldError<S>> {
+    let action = info
+        .actions
+        .iter()
+        .find(|action| action.name == field_name)
+        .ok_or_else(|| FieldError::from(format!("Unknown action {}", field_name)))?;
+
+    let mut input = serde_json::Map::new();
+    for argument in &action.arguments {
+        if let Some(value) = arguments.get_input_value(&argument.name) {
+            let value = serde_json::to_value(&value.item).map_err(FieldError::from)?;
+            input.insert(argument.name.clone(), value);
+        }
+    }
+    let request = ActionRequest {
+        app_uuid: context.app_uuid.clone(),
+        action_uuid: action.uuid.clone(),
+        component: action.component.clone(),
+        input: Value::Object(input).to_string(),
+        token: context.token.clone(),
+    };
+    Ok((action, request))
+}
+
+fn parse_result<S: ScalarValue>(action: &ActionInfo, result: &str) -> Result<Value, FieldError<S>> {
+    serde_json::from_str(result).map_err(|err| {
+        FieldError::from(format!(
+            "Action {} returned invalid JSON: {}",
+            action.name, err
+        ))
+    })
+}
+
+/// The actions of the application queued to execute in the background, with the same arguments
+/// as their mutations
+pub struct AsyncMutation;
+
+impl<S: ScalarValue> GraphQLType<S> for AsyncMutation {
+    fn name(_info: &Rc<AppInfo>) -> Option<&str> {
+        Some("AsyncMutation")
+    }
+
+    fn meta<'r>(info: &Rc<AppInfo>, registry: &mut Registry<'r, S>) -> MetaType<'r, S>
+    where
+        S: 'r,
+    {
+        let fields: Vec<_> = info
+            .actions
+            .iter()
+            .map(|action| {
+                let field = registry.field::<Option<Job>>(&action.name, &());
+                with_arguments(registry, field, action)
+            })
+            .collect();
+        registry
+            .build_object_type::<AsyncMutation>(info, &fields)
+            .into_meta()
+    }
+}
+
+impl<S: ScalarValue> GraphQLValue<S> for AsyncMutation {
+    type Context = Context;
+    type TypeInfo = Rc<AppInfo>;
+
+    fn type_name<'i>(&self, info: &'i Rc<AppInfo>) -> Option<&'i str> {
+        <AsyncMutation as GraphQLType<S>>::name(info)
+    }
+
+    fn resolve_field(
+        &self,
+        info: &Rc<AppInfo>,
+        field_name: &str,
+        arguments: &Arguments<S>,
+        executor: &Executor<Context, S>,
+    ) -> ExecutionResult<S> {
+        let context = executor.context();
+        let (action, request) = action_request(info, field_name, arguments, context)?;
+        let id = (context.enqueuer)(&request).map_err(FieldError::from)?;
+        let job = Job {
+            id: ID::new(id),
+            action: Some(action.name.clone()),
+            status: JobStatus::Queued,
+            progress: None,
+            result: None,
+            error: None,
+        };
+        executor.resolve_with_ctx(&(), &job)
+    }
+}
+
+#[derive(GraphQLEnum, Debug, Clone, Copy, PartialEq)]
+enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(context = Context)]
+struct JobProgress {
+    message: String,
+    /// Between 0 and 100, when the action knows
+    percent: Option<i32>,
+}
+
+/// An action executed in the background
+#[derive(GraphQLObject)]
+#[graphql(context = Context)]
+struct Job {
+    id: ID,
+    /// Name of the action, null when it was removed from the application
+    action: Option<String>,
+    status: JobStatus,
+    /// Progress reported by the last step
+    progress: Option<JobProgress>,
+    /// Result of the action as JSON, once it succeeded
+    result: Option<String>,
+    /// Why the action failed
+    error: Option<String>,
+}
+
+impl Job {
+    fn new(info: &AppInfo, job: ActionJob) -> Job {
+        Job {
+            id: ID::new(job.id),
+            action: info
+                .actions
+                .iter()
+                .find(|action| action.uuid == job.action_uuid)
+                .map(|action| action.name.clone()),
+            status: match job.status {
+                action_runner::JobStatus::Queued => JobStatus::Queued,
+                action_runner::JobStatus::Running => JobStatus::Running,
+                action_runner::JobStatus::Succeeded => JobStatus::Succeeded,
+                action_runner::JobStatus::Failed => JobStatus::Failed,
+            },
+            progress: job.message.map(|message| JobProgress {
+                message,
+                percent: job.percent.map(i32::from),
+            }),
+            result: job.result,
+            error: job.error,
+        }
+    }
+}
+
+/// The root subscription, with a field for every action of the application. Every execution of
+/// a field executes the next step of its action, see [`AppSchema::next_event`].
+pub struct Subscription;
+
+impl<S: ScalarValue> GraphQLType<S> for Subscription {
+    fn name(_info: &Rc<AppInfo>) -> Option<&str> {
+        Some("Subscription")
+    }
+
+    fn meta<'r>(info: &Rc<AppInfo>, registry: &mut Registry<'r, S>) -> MetaType<'r, S>
+    where
+        S: 'r,
+    {
+        let fields: Vec<_> = info
+            .actions
+            .iter()
+            .map(|action| action_field(registry, action, &action.event))
+            .collect();
+        registry
+            .build_object_type::<Subscription>(info, &fields)
+            .into_meta()
+    }
+}
+
+impl<S: ScalarValue> GraphQLValue<S> for Subscription {
+    type Context = Context;
+    type TypeInfo = Rc<AppInfo>;
+
+    fn type_name<'i>(&self, info: &'i Rc<AppInfo>) -> Option<&'i str> {
+        <Subscription as GraphQLType<S>>::name(info)
+    }
+
+    fn resolve_field(
+        &self,
+        info: &Rc<AppInfo>,
+        field_name: &str,
+        arguments: &Arguments<S>,
+        executor: &Executor<Context, S>,
+    ) -> ExecutionResult<S> {
+        let context = executor.context();
+        let (action, request) = action_request(info, field_name, arguments, context)?;
+        let state = {
+            let mut steps = context.steps();
+            steps.stepped = true;
+            // a failed step ends the subscription
+            steps.done = true;
+            steps.state.clone()
+        };
+        let event = match (context.stepper)(&request, state.as_deref())? {
+            ActionStep::Progress(progress) => {
+                let mut steps = context.steps();
+                steps.state = Some(progress.state);
+                steps.done = false;
+                serde_json::json!({
+                    "progress": { "message": progress.message, "percent": progress.percent }
+                })
+            }
+            ActionStep::Done(result) => {
+                serde_json::json!({ "result": parse_result(action, &result)? })
+            }
+        };
+        executor.resolve(&action.event, &Output(event))
+    }
+}
+
+/// A result of an action, resolved as the [`Kind`] of its schema
+pub struct Output(Value);
+
+impl<S: ScalarValue> GraphQLType<S> for Output {
+    fn name(info: &Kind) -> Option<&str> {
+        match info {
+            Kind::String => Some("String"),
+            Kind::Int => Some("Int"),
+            Kind::Float => Some("Float"),
+            Kind::Boolean => Some("Boolean"),
+            Kind::Object(object) => Some(&object.name),
+            Kind::List(_) | Kind::Nullable(_) => None,
+        }
+    }
+
+    fn meta<'r>(info: &Kind, registry: &mut Registry<'r, S>) -> MetaType<'r, S>
+    where
+        S: 'r,
+    {
+        match info {
+            Kind::String => <String as GraphQLType<S>>::meta(&(), registry),
+            Kind::Int => <i32 as GraphQLType<S>>::meta(&(), registry),
+            Kind::Float => <f64 as GraphQLType<S>>::meta(&(), registry),
+            Kind::Boolean => <bool as GraphQLType<S>>::meta(&(), registry),
+            Kind::Object(object) => {
+                let fields: Vec<_> = object
+                    .fields
+                    .iter()
+                    .map(|field| object_field(registry, field))
+                    .collect();
+                let meta = registry.build_object_type::<Output>(info, &fields);
+                match &object.description {
+                    Some(description) => meta.description(description).into_meta(),
+                    None => meta.into_meta(),
+                }
+            }
+            Kind::List(item) => registry.build_list_type::<Output>(item, None).into_meta(),
+            Kind::Nullable(kind) => registry.build_nullable_type::<Output>(kind).into_meta(),
+        }
+    }
+}
+
+impl<S: ScalarValue> GraphQLValue<S> for Output {
+    type Context = Context;
+    type TypeInfo = Kind;
+
+    fn type_name<'i>(&self, info: &'i Kind) -> Option<&'i str> {
+        <Output as GraphQLType<S>>::name(info)
+    }
+
+    fn concrete_type_name(&self, _context: &Context, info: &Kind) -> String {
+        <Output as GraphQLType<S>>::name(info)
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    fn resolve(
+        &self,
+        info: &Kind,
+        _selection_set: Option<&[juniper::Selection<S>]>,
+        executor: &Executor<Context, S>,
+    ) -> ExecutionResult<S> {
+        let scalar = match (info, &self.0) {
+            (Kind::Nullable(_), Value::Null) => return Ok(juniper::Value::null()),
+            (Kind::Nullable(kind), _) => return executor.resolve(kind.as_ref(), self),
+            (Kind::List(item), Value::Array(values)) => {
+                return values
+                    .iter()
+                    .map(|value| executor.resolve(item.as_ref(), &Output(value.clone())))
+                    .collect::<Result<_, _>>()
+                    .map(juniper::Value::list);
+            }
+            (Kind::Object(object), Value::Object(_)) => {
+                return executor.resolve(object, &OutputObject(&self.0));
+            }
+            (Kind::String, Value::String(value)) => Some(S::from(value.clone())),
+            (Kind::Int, Value::Number(number)) => number
+                .as_i64()
+                .and_then(|number| i32::try_from(number).ok())
+                .map(S::from),
+            (Kind::Float, Value::Number(number)) => number.as_f64().map(S::from),
+            (Kind::Boolean, Value::Bool(value)) => Some(S::from(*value)),
+            _ => None,
+        };
+        scalar.map(juniper::Value::Scalar).ok_or_else(|| {
+            FieldError::from(format!(
+                "Action returned `{}`, which is not a valid {}",
+                self.0,
+                describe(info)
+            ))
+        })
+    }
+}
+
+/// An object in the result of an action, its fields resolve as the object's [`Kind`]
+struct OutputObject<'a>(&'a Value);
+
+impl<S: ScalarValue> GraphQLValue<S> for OutputObject<'_> {
+    type Context = Context;
+    type TypeInfo = Rc<ObjectInfo>;
+
+    fn type_name<'i>(&self, info: &'i Rc<ObjectInfo>) -> Option<&'i str> {
+        Some(&info.name)
+    }
+
+    fn concrete_type_name(&self, _context: &Context, info: &Rc<ObjectInfo>) -> String {
+        info.name.clone()
+    }
+
+    fn resolve_field(
+        &self,
+        info: &Rc<ObjectInfo>,
+        field_name: &str,
+        _arguments: &Arguments<S>,
+        executor: &Executor<Context, S>,
+    ) -> ExecutionResult<S> {
+        let field = info
+            .fields
+            .iter()
+            .find(|field| field.name == field_name)
+            .ok_or_else(|| FieldError::from(format!("Unknown field {}", field_name)))?;
+        let value = self.0.get(field_name).cloned().unwrap_or(Value::Null);
+        executor.resolve(&field.kind, &Output(value))
+    }
+}
+
+fn describe(kind: &Kind) -> String {
+    match kind {
+        Kind::String => "String".to_string(),
+        Kind::Int => "Int".to_string(),
+        Kind::Float => "Float".to_string(),
+        Kind::Boolean => "Boolean".to_string(),
+        Kind::Object(object) => object.name.clone(),
+        Kind::List(item) => format!("[{}]", describe(item)),
+        Kind::Nullable(kind) => describe(kind),
+    }
+}
+
+/// An argument of a mutation. Arguments are checked against the schema before the mutation is
+/// executed and read as JSON, so the value itself is never converted.
+pub struct Input;
+
+impl<S: ScalarValue> FromInputValue<S> for Input {
+    type Error = String;
+
+    fn from_input_value(_value: &InputValue<S>) -> Result<Input, String> {
+        Ok(Input)
+    }
+}
+
+impl<S: ScalarValue> GraphQLType<S> for Input {
+    fn name(info: &Kind) -> Option<&str> {
+        <Output as GraphQLType<S>>::name(info)
+    }
+
+    fn meta<'r>(info: &Kind, registry: &mut Registry<'r, S>) -> MetaType<'r, S>
+    where
+        S: 'r,
+    {
+        match info {
+            Kind::Object(object) => {
+                let arguments: Vec<_> = object
+                    .fields
+                    .iter()
+                    .map(|field| {
+                        let meta = registry.arg::<Input>(&field.name, &field.kind);
+                        match &field.description {
+                            Some(description) => meta.description(description),
+                            None => meta,
+                        }
+                    })
+                    .collect();
+                let meta = registry.build_input_object_type::<Input>(info, &arguments);
+                match &object.description {
+                    Some(description) => meta.description(description).into_meta(),
+                    None => meta.into_meta(),
+                }
+            }
+            Kind::List(item) => registry.build_list_type::<Input>(item, None).into_meta(),
+            Kind::Nullable(kind) => registry.build_nullable_type::<Input>(kind).into_meta(),
+            scalar => <Output as GraphQLType<S>>::meta(scalar, registry),
+        }
+    }
+}
+
+impl<S: ScalarValue> GraphQLValue<S> for Input {
+    type Context = Context;
+    type TypeInfo = Kind;
+
+    fn type_name<'i>(&self, info: &'i Kind) -> Option<&'i str> {
+        <Input as GraphQLType<S>>::name(info)
+    }
+}
+
+/// The schema of an application, with the etag of the application it was generated from
+pub struct AppSchema {
+    pub etag: String,
+    /// Whether the schema can be introspected, it is generated again when the config changes
+    pub introspection: bool,
+    pub schema: Schema,
+    pub events: EventSchema,
+    /// Persisted queries of the application by their hash
+    pub queries: HashMap<String, String>,
+    /// Origins browsers may call the endpoint of the application from
+    pub origins: Vec<String>,
+}
+
+impl AppSchema {
+    pub fn new(etag: String, application: &str, introspection: bool) -> Result<AppSchema, String> {
+        let application: Value = serde_json::from_str(application)
+            .map_err(|err| format!("Application is not valid JSON: {}", err))?;
+        let info = Rc::new(AppInfo::from_application(&application)?);
+        let queries = crate::persisted::from_application(&application);
+        let origins = crate::cors::from_application(&application);
+        let schema = Schema::new_with_info(
+            Query,
+            Mutation,
+            Subscription,
+            info.clone(),
+            info.clone(),
+            info.clone(),
+        );
+        // subscriptions can't be introspected, the schema above describes them
+        let events = EventSchema::new_with_info(
+            Subscription,
+            EmptyMutation::new(),
+            EmptySubscription::new(),
+            info,
+            (),
+            (),
+        )
+        .disable_introspection();
+        let schema = if introspection {
+            schema
+        } else {
+            schema.disable_introspection()
+        };
+        Ok(AppSchema {
+            etag,
+            introspection,
+            schema,
+            events,
+            queries,
+            origins,
+        })
+    }
+
+    /// Execute the next event of a subscription, `None` once the action is done. `request` is
+    /// the subscription as a query of the [`EventSchema`], see
+    /// [`crate::request::subscription_as_query`].
+    pub fn next_event(
+        &self,
+        request: &GraphQLRequest,
+        context: &Context,
+    ) -> Option<GraphQLResponse> {
+        if context.steps().done {
+            return None;
+        }
+        context.steps().stepped = false;
+        let response = request.execute_sync(&self.events, context);
+        let mut steps = context.steps();
+        // an invalid subscription executes nothing, and has a single event with its errors
+        if !steps.stepped || !response.is_ok() {
+            steps.done = true;
+        }
+        Some(response)
+    }
+}
+
+thread_local! {
+    static SCHEMAS: RefCell<HashMap<String, Rc<AppSchema>>> = RefCell::new(HashMap::new());
+}
+
+/// The cached schema of an application, its etag tells whether it is still up to date.
+pub fn cached_schema(app_uuid: &str) -> Option<Rc<AppSchema>> {
+    SCHEMAS.with(|schemas| schemas.borrow().get(&app_uuid.to_lowercase()).cloned())
+}
+
+/// Generate the schema of a changed application and cache it.
+pub fn cache_schema(
+    app_uuid: &str,
+    application: StoredApplication,
+    introspection: bool,
+) -> Result<Rc<AppSchema>, String> {
+    let schema = Rc::new(AppSchema::new(
+        application.etag,
+        &application.json,
+        introspection,
+    )?);
+    SCHEMAS.with(|schemas| {
+        schemas
+            .borrow_mut()
+            .insert(app_uuid.to_lowercase(), schema.clone())
+    });
+    Ok(schema)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bettyblocks::runtime_cloud::action_runner::ActionProgress;
+    use serde_json::json;
+
+    /// JWT of the caller in the tests, actions fail without it
+    const TOKEN: &str = "eyJhbGciOiJIUzI1NiJ9.e30.c2ln";
+
+    fn application() -> Value {
+        json!({
+            "uuid": "693b22e983fb46afa4eb353d82ece4bb",
+            "name": "MyApp",
+            "actions": [
+                {
+                    "uuid": "5c2e8f1a9b3d4c7e8a6f0d2b4e1c3a57",
+                    "name": "greet",
+                    "auth": "None",
+                    "scope": "Public",
+                    "etag": "1",
+                    "component": "action-example-a",
+                    "input": {
+                        "type": "object",
+                        "properties": {
+                            "name": { "type": "string", "description": "Who to greet" },
+                            "times": { "type": "integer" },
+                            "tags": { "type": "array", "items": { "type": "string" } }
+                        },
+                        "required": ["name"]
+                    }
+                },
+                {
+                    "uuid": "b1d7e3a0c6f94b0e9d5f2a4c8e7b6d51",
+                    "name": "provider_status",
+                    "auth": "None",
+                    "scope": "Public",
+                    "etag": "1",
+                    "component": "action-example-b",
+                    "output": {
+                        "type": "object",
+                        "properties": {
+                            "version": { "type": "string" },
+                            "reachable": { "type": "boolean" },
+                            "upstreams": {
+                                "type": "array",
+                                "items": {
+                                    "type": "object",
+                                    "properties": { "failureRate": { "type": "number" } }
+                                }
+                            }
+                        },
+                        "required": ["version", "reachable"]
+                    }
+                }
+            ]
+        })
+    }
+
+    fn runner(request: &ActionRequest) -> Result<String, String> {
+        if request.token.as_deref() != Some(TOKEN) {
+            return Err("no caller token".to_string());
+        }
+        match request.component.as_deref() {
+            Some("action-example-a") => Ok(json!(request.input).to_string()),
+            Some("action-example-b") => Ok(json!({
+                "version": "0.1.0",
+                "reachable": true,
+                "upstreams": [{ "failureRate": 0.5 }]
+            })
+            .to_string()),
+            _ => Err("no component".to_string()),
+        }
+    }
+
+    /// Provider status takes two steps, greet only one
+    fn stepper(request: &ActionRequest, state: Option<&str>) -> Result<ActionStep, String> {
+        match (request.component.as_deref(), state) {
+            (Some("action-example-b"), None) => Ok(ActionStep::Progress(ActionProgress {
+                message: "Checking upstreams".to_string(),
+                percent: Some(50),
+                state: "checked".to_string(),
+            })),
+            (Some("action-example-b"), Some("checked")) => runner(request).map(ActionStep::Done),
+            (Some("action-example-a"), None) => Err("greet failed".to_string()),
+            _ => Err(format!("unexpected state {:?}", state)),
+        }
+    }
+
+    fn enqueuer(request: &ActionRequest) -> Result<String, String> {
+        match request.component.as_deref() {
+            Some("action-example-b") => Ok("0f4c6e3a9d1b4f7e8a2c5b6d7e8f9a0b".to_string()),
+            _ => Err("queue unavailable".to_string()),
+        }
+    }
+
+    /// A running provider status job of this application, and a job of another application
+    fn jobs(id: &str) -> Result<Option<ActionJob>, String> {
+        let job = |app_uuid: &str| ActionJob {
+            id: id.to_string(),
+            app_uuid: app_uuid.to_string(),
+            action_uuid: "b1d7e3a0c6f94b0e9d5f2a4c8e7b6d51".to_string(),
+            status: action_runner::JobStatus::Running,
+            message: Some("Checking upstreams".to_string()),
+            percent: Some(50),
+            result: None,
+            error: None,
+        };
+        match id {
+            "0f4c6e3a9d1b4f7e8a2c5b6d7e8f9a0b" => Ok(Some(job("693B22E983FB46AFA4EB353D82ECE4BB"))),
+            "a6f1c0d2e3b44f5a9c8d7e6f5a4b3c2d" => Ok(Some(job("0d4a8f3e2b1c4e5f9a7b6c5d4e3f2a1b"))),
+            _ => Ok(None),
+        }
+    }
+
+    fn execute(query: &str, variables: Value) -> Value {
+        let schema = AppSchema::new("1".to_string(), &application().to_string(), true).unwrap();
+        let request: GraphQLRequest =
+            serde_json::from_value(json!({ "query": query, "variables": variables })).unwrap();
+        let context = Context::new(
+            "693b22e983fb46afa4eb353d82ece4bb".to_string(),
+            Some(TOKEN.to_string()),
+            runner,
+            stepper,
+            enqueuer,
+            jobs,
+        );
+        serde_json::to_value(request.execute_sync(&schema.schema, &context)).unwrap()
+    }
+
+    #[test]
+    fn test_mutation_per_action_with_typed_arguments() {
+        let response = execute(
+            r#"{ __type(name: "Mutation") { fields { name args { name type { kind name ofType { name } } } type { name } } } }"#,
+            json!({}),
+        );
+        let fields = &response["data"]["__type"]["fields"];
+        assert_eq!(fields[0]["name"], "greet");
+        assert_eq!(fields[0]["type"]["name"], "String");
+        assert_eq!(
+            fields[0]["args"],
+            json!([
+                { "name": "name", "type": { "kind": "NON_NULL", "name": null, "ofType": { "name": "String" } } },
+                { "name": "tags", "type": { "kind": "LIST", "name": null, "ofType": { "name": null } } },
+                { "name": "times", "type": { "kind": "SCALAR", "name": "Int", "ofType": null } }
+            ])
+        );
+        assert_eq!(fields[1]["name"], "provider_status");
+        assert_eq!(fields[1]["type"]["name"], "ProviderStatusResult");
+    }
+
+    #[test]
+    fn test_executes_action_with_arguments() {
+        let response = execute(
+            r#"mutation($tags: [String!]) { greet(name: "Betty", tags: $tags) }"#,
+            json!({ "tags": ["a"] }),
+        );
+        let input: Value =
+            serde_json::from_str(response["data"]["greet"].as_str().unwrap()).unwrap();
+        assert_eq!(input, json!({ "name": "Betty", "tags": ["a"] }));
+    }
+
+    #[test]
+    fn test_passes_caller_token_to_actions() {
+        let schema = AppSchema::new("1".to_string(), &application().to_string(), true).unwrap();
+        let request: GraphQLRequest =
+            serde_json::from_value(json!({ "query": r#"mutation { greet(name: "Betty") }"# }))
+                .unwrap();
+        let context = Context::new(
+            "693b22e983fb46afa4eb353d82ece4bb".to_string(),
+            None,
+            runner,
+            stepper,
+            enqueuer,
+            jobs,
+        );
+        let response =
+            serde_json::to_value(request.execute_sync(&schema.schema, &context)).unwrap();
+        assert_eq!(response["errors"][0]["message"], "no caller token");
+    }
+
+    #[test]
+    fn test_resolves_typed_result() {
+        let response = execute(
+            "mutation { provider_status { version reachable upstreams { failureRate } } }",
+            json!({}),
+        );
+        assert_eq!(
+            response,
+            json!({ "data": { "provider_status": {
+                "version": "0.1.0",
+                "reachable": true,
+                "upstreams": [{ "failureRate": 0.5 }]
+            } } })
+        );
+    }
+
+    #[test]
+    fn test_rejects_invalid_arguments() {
+        let response = execute("mutation { greet(times: 2) }", json!({}));
+        assert!(response["errors"][0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("name"));
+        assert_eq!(response.get("data"), None);
+    }
+
+    #[test]
+    fn test_introspection_can_be_disabled() {
+        let schema = AppSchema::new("1".to_string(), &application().to_string(), false).unwrap();
+        let request: GraphQLRequest =
+            serde_json::from_value(json!({ "query": "{ __schema { queryType { name } } }" }))
+                .unwrap();
+        let context = Context::new(
+            "693b22e983fb46afa4eb353d82ece4bb".to_string(),
+            Some(TOKEN.to_string()),
+            runner,
+            stepper,
+            enqueuer,
+            jobs,
+        );
+        let response =
+            serde_json::to_value(request.execute_sync(&schema.schema, &context)).unwrap();
+        assert_eq!(response.get("data"), None);
+        assert!(response["errors"][0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("introspection"));
+    }
+
+    /// The events of a subscription, executed as the server does
+    fn subscribe(subscription: &str) -> Vec<Value> {
+        let schema = AppSchema::new("1".to_string(), &application().to_string(), true).unwrap();
+        let query =
+            crate::request::subscription_as_query(subscription, None, &schema.schema.schema)
+                .unwrap();
+        let request = GraphQLRequest::new(query, None, None);
+        let context = Context::new(
+            "693b22e983fb46afa4eb353d82ece4bb".to_string(),
+            Some(TOKEN.to_string()),
+            runner,
+            stepper,
+            enqueuer,
+            jobs,
+        );
+        std::iter::from_fn(|| schema.next_event(&request, &context))
+            .map(|response| serde_json::to_value(response).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_subscription_streams_progress_and_result() {
+        let events = subscribe(
+            "subscription { provider_status { progress { message percent } result { version } } }",
+        );
+        assert_eq!(
+            events,
+            vec![
+                json!({ "data": { "provider_status": {
+                    "progress": { "message": "Checking upstreams", "percent": 50 },
+                    "result": null
+                } } }),
+                json!({ "data": { "provider_status": {
+                    "progress": null,
+                    "result": { "version": "0.1.0" }
+                } } }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_failed_step_ends_subscription() {
+        let events = subscribe(r#"subscription { greet(name: "Betty") { result } }"#);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["data"]["greet"], Value::Null);
+        assert_eq!(events[0]["errors"][0]["message"], "greet failed");
+    }
+
+    #[test]
+    fn test_invalid_subscription_has_single_event() {
+        let events = subscribe("subscription { provider_status { unknown } }");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].get("data"), None);
+    }
+
+    #[test]
+    fn test_execute_async_returns_queued_job() {
+        let response = execute(
+            "mutation { executeAsync { provider_status { id action status } } }",
+            json!({}),
+        );
+        assert_eq!(
+            response["data"]["executeAsync"]["provider_status"],
+            json!({ "id": "0f4c6e3a9d1b4f7e8a2c5b6d7e8f9a0b", "action": "provider_status", "status": "QUEUED" })
+        );
+        let response = execute(
+            r#"mutation { executeAsync { greet(name: "Betty") { id } } }"#,
+            json!({}),
+        );
+        assert_eq!(response["data"]["executeAsync"]["greet"], Value::Null);
+        assert_eq!(response["errors"][0]["message"], "queue unavailable");
+    }
+
+    #[test]
+    fn test_query_job() {
+        let query = r#"query($id: ID!) { job(id: $id) { id action status progress { message percent } result } }"#;
+        let response = execute(query, json!({ "id": "0f4c6e3a9d1b4f7e8a2c5b6d7e8f9a0b" }));
+        assert_eq!(
+            response["data"]["job"],
+            json!({
+                "id": "0f4c6e3a9d1b4f7e8a2c5b6d7e8f9a0b",
+                "action": "provider_status",
+                "status": "RUNNING",
+                "progress": { "message": "Checking upstreams", "percent": 50 },
+                "result": null
+            })
+        );
+        // jobs of other applications are not found
+        let response = execute(query, json!({ "id": "a6f1c0d2e3b44f5a9c8d7e6f5a4b3c2d" }));
+        assert_eq!(response["data"]["job"], Value::Null);
+        let response = execute(query, json!({ "id": "unknown" }));
+        assert_eq!(response["data"]["job"], Value::Null);
+    }
+
+    #[test]
+    fn test_execute_async_is_reserved() {
+        let mut application = application();
+        application["actions"][0]["name"] = json!("executeAsync");
+        assert!(AppInfo::from_application(&application).is_err());
+    }
+
+    #[test]
+    fn test_query_application() {
+        let response = execute("{ application { uuid name } }", json!({}));
+        assert_eq!(
+            response["data"]["application"],
+            json!({ "uuid": "693b22e983fb46afa4eb353d82ece4bb", "name": "MyApp" })
+        );
+    }
+
+    #[test]
+    fn test_type_names_are_unique() {
+        let mut names: HashSet<String> = RESERVED_NAMES.iter().map(|n| n.to_string()).collect();
+        assert_eq!(unique_name("Query".to_string(), &mut names), "Query2");
+        assert_eq!(
+            unique_name("GreetInput".to_string(), &mut names),
+            "GreetInput"
+        );
+        assert_eq!(
+            unique_name("GreetInput".to_string(), &mut names),
+            "GreetInput2"
+        );
+        assert_eq!(pascal_case("provider_status"), "ProviderStatus");
+        assert_eq!(pascal_case("greet"), "Greet");
+    }
+
+    #[test]
+    fn test_schema_is_cached_per_application() {
+        let stored = |etag: &str| StoredApplication {
+            etag: etag.to_string(),
+            json: application().to_string(),
+        };
+        assert!(cached_schema("693b22e983fb46afa4eb353d82ece4bb").is_none());
+        let schema = cache_schema("693B22E983FB46AFA4EB353D82ECE4BB", stored("1"), true).unwrap();
+        let cached = cached_schema("693b22e983fb46afa4eb353d82ece4bb").unwrap();
+        assert!(Rc::ptr_eq(&schema, &cached));
+        cache_schema("693b22e983fb46afa4eb353d82ece4bb", stored("2"), true).unwrap();
+        assert_eq!(
+            cached_schema("693b22e983fb46afa4eb353d82ece4bb")
+                .unwrap()
+                .etag,
+            "2"
+        );
+    }
+}
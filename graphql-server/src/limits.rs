@@ -0,0 +1,255 @@
+//! Limits on GraphQL documents, checked before execution so abusive queries are rejected before
+//! they run. Fragments are expanded where they are spread, and the walk stops at the first limit
+//! exceeded, so fragments spreading each other many times can't make the check itself expensive.
+
+use std::collections::HashMap;
+
+use juniper::{
+    graphql_value, DefaultScalarValue, Definition, FieldError, OperationType, SchemaType, Selection,
+};
+
+/// Cost of a mutation or subscription on top of the field itself, it executes an action in
+/// another component
+const ACTION_COST: usize = 10;
+
+#[derive(Debug, PartialEq)]
+pub struct Limits {
+    /// Deepest nesting of fields, `{ application { name } }` is 2 deep
+    pub max_depth: usize,
+    /// Deepest nesting of fields under an introspection field like `__schema`, introspection
+    /// queries nest type references deeper than queries usually are
+    pub max_introspection_depth: usize,
+    pub max_aliases: usize,
+    /// Every field costs 1, a mutation or subscription costs 10 more
+    pub max_complexity: usize,
+}
+
+struct Walker<'a, 'd> {
+    limits: &'a Limits,
+    fragments: HashMap<&'d str, &'a [Selection<'d, DefaultScalarValue>]>,
+    /// Fragments being expanded, a fragment spreading itself is reported by validation
+    spreading: Vec<&'d str>,
+    aliases: usize,
+    complexity: usize,
+}
+
+impl<'a, 'd> Walker<'a, 'd> {
+    fn selections(
+        &mut self,
+        selections: &'a [Selection<'d, DefaultScalarValue>],
+        depth: usize,
+        action: bool,
+        introspection: bool,
+    ) -> Result<(), FieldError> {
+        for selection in selections {
+            match selection {
+                Selection::Field(field) => {
+                    let depth = depth + 1;
+                    let introspection = introspection || field.item.name.item.starts_with("__");
+                    let max_depth = if introspection {
+                        self.limits.max_introspection_depth
+                    } else {
+                        self.limits.max_depth
+                    };
+                    if depth > max_depth {
+                        return Err(error(
+                            &format!("Query is nested more than {} levels deep", max_depth),
+                            "QUERY_TOO_DEEP",
+                        ));
+                    }
+                    if field.item.alias.is_some() {
+                        self.aliases += 1;
+                        if self.aliases > self.limits.max_aliases {
+                            return Err(error(
+                                &format!("Query has more than {} aliases", self.limits.max_aliases),
+                                "TOO_MANY_ALIASES",
+                            ));
+                        }
+                    }
+                    self.complexity += if action { 1 + ACTION_COST } else { 1 };
+                    if self.complexity > self.limits.max_complexity {
+                        return Err(error(
+                            &format!(
+                                "Query complexity is more than {}",
+                                self.limits.max_complexity
+                            ),
+                            "QUERY_TOO_COMPLEX",
+                        ));
+                    }
+                    if let Some(selections) = &field.item.selection_set {
+                        self.selections(selections, depth, false, introspection)?;
+                    }
+                }
+                Selection::FragmentSpread(spread) => {
+                    let name = spread.item.name.item;
+                    let Some(selections) = self.fragments.get(name).copied() else {
+                        continue;
+                    };
+                    if self.spreading.contains(&name) {
+                        continue;
+                    }
+                    self.spreading.push(name);
+                    self.selections(selections, depth, action, introspection)?;
+                    self.spreading.pop();
+                }
+                Selection::InlineFragment(fragment) => {
+                    self.selections(&fragment.item.selection_set, depth, action, introspection)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn error(message: &str, code: &str) -> FieldError {
+    FieldError::new(message, graphql_value!({ "code": code }))
+}
+
+/// Check every operation in a query against the limits. A query that can't be parsed passes,
+/// execution reports why it is invalid.
+pub fn check(
+    query: &str,
+    schema: &SchemaType<DefaultScalarValue>,
+    limits: &Limits,
+) -> Result<(), FieldError> {
+    let Ok(document) = juniper::parser::parse_document_source(query, schema) else {
+        return Ok(());
+    };
+    let fragments = document
+        .iter()
+        .filter_map(|definition| match definition {
+            Definition::Fragment(fragment) => Some((
+                fragment.item.name.item,
+                fragment.item.selection_set.as_slice(),
+            )),
+            Definition::Operation(_) => None,
+        })
+        .collect();
+    let mut walker = Walker {
+        limits,
+        fragments,
+        spreading: vec![],
+        aliases: 0,
+        complexity: 0,
+    };
+    for definition in &document {
+        if let Definition::Operation(operation) = definition {
+            let action = operation.item.operation_type != OperationType::Query;
+            walker.selections(&operation.item.selection_set, 0, action, false)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use juniper::{EmptySubscription, RootNode};
+
+    struct Query;
+
+    #[juniper::graphql_object]
+    impl Query {
+        fn ping() -> bool {
+            true
+        }
+
+        fn nested() -> Query {
+            Query
+        }
+    }
+
+    struct Mutation;
+
+    #[juniper::graphql_object]
+    impl Mutation {
+        fn ping() -> bool {
+            true
+        }
+    }
+
+    const LIMITS: Limits = Limits {
+        max_depth: 3,
+        max_introspection_depth: 5,
+        max_aliases: 2,
+        max_complexity: 30,
+    };
+
+    fn code(query: &str) -> Option<String> {
+        let root = RootNode::new(Query, Mutation, EmptySubscription::<()>::new());
+        let err = check(query, &root.schema, &LIMITS).err()?;
+        err.extensions()
+            .as_object_value()?
+            .get_field_value("code")?
+            .as_string_value()
+            .map(str::to_string)
+    }
+
+    #[test]
+    fn test_within_limits() {
+        assert_eq!(code("{ nested { nested { ping } } }"), None);
+        assert_eq!(code("{ a: ping b: ping }"), None);
+        assert_eq!(code("mutation { a: ping b: ping }"), None);
+        assert_eq!(code("{ ping"), None);
+    }
+
+    #[test]
+    fn test_depth() {
+        assert_eq!(
+            code("{ nested { nested { nested { ping } } } }").as_deref(),
+            Some("QUERY_TOO_DEEP")
+        );
+        let fragments = "{ nested { ...A } } fragment A on Query { nested { ...B } } fragment B on Query { nested { ping } }";
+        assert_eq!(code(fragments).as_deref(), Some("QUERY_TOO_DEEP"));
+    }
+
+    #[test]
+    fn test_aliases() {
+        assert_eq!(
+            code("{ a: ping b: ping c: ping }").as_deref(),
+            Some("TOO_MANY_ALIASES")
+        );
+    }
+
+    #[test]
+    fn test_complexity() {
+        assert_eq!(
+            code("mutation { ping ping ping }").as_deref(),
+            Some("QUERY_TOO_COMPLEX")
+        );
+        assert_eq!(
+            code("subscription { ping ping ping }").as_deref(),
+            Some("QUERY_TOO_COMPLEX")
+        );
+        // every spread of a fragment counts, so spreading fragments in each other adds up
+        let fragments = "{ ...A ...A } fragment A on Query { ...B ...B } fragment B on Query { ...C ...C } fragment C on Query { ...D ...D } fragment D on Query { ping ping }";
+        assert_eq!(code(fragments).as_deref(), Some("QUERY_TOO_COMPLEX"));
+    }
+
+    #[test]
+    fn test_introspection_is_limited() {
+        assert_eq!(
+            code("{ __schema { types { fields { type { name } } } } }"),
+            None
+        );
+        assert_eq!(
+            code("{ __schema { types { fields { type { ofType { ofType { name } } } } } } }")
+                .as_deref(),
+            Some("QUERY_TOO_DEEP")
+        );
+        assert_eq!(
+            code("{ a: __typename b: __typename c: __typename }").as_deref(),
+            Some("TOO_MANY_ALIASES")
+        );
+        // fields under introspection don't get the introspection depth
+        assert_eq!(
+            code("{ nested { __typename nested { nested { ping } } } }").as_deref(),
+            Some("QUERY_TOO_DEEP")
+        );
+    }
+
+    #[test]
+    fn test_fragment_cycle() {
+        assert_eq!(code("{ ...A } fragment A on Query { ping ...A }"), None);
+    }
+}
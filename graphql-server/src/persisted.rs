@@ -0,0 +1,356 @@
+//! Persisted queries, sent by the SHA-256 of the query instead of the query itself.
+//!
+//! With automatic persisted queries (APQ) a client first sends only the hash, in
+//! `extensions.persistedQuery.sha256Hash`. When the query is unknown the response is a
+//! `PersistedQueryNotFound` error, and the client sends the query along with its hash, which
+//! stores the query for the next time. The `queries` of the application in the artefact are
+//! known from the start. In allow-list mode only those queries execute, by their hash or in full.
+
+use std::cell::OnceCell;
+use std::collections::HashMap;
+
+use data_encoding::HEXLOWER;
+use juniper::http::GraphQLRequest;
+use juniper::{graphql_value, FieldError};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::request::Request;
+use crate::wasi::keyvalue::store;
+use crate::wasi::logging::logging::{log, Level};
+
+/// Version of the persisted query extension, as sent by the client
+const VERSION: u32 = 1;
+/// Prefix of the keys queries are stored under
+const KEY_PREFIX: &str = "apq.";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Execute any query, and store queries sent with their hash
+    Automatic,
+    /// Only execute the persisted queries of the application in the artefact
+    AllowList,
+}
+
+/// Where queries sent with their hash are stored
+pub trait QueryStore {
+    fn get(&self, sha256: &str) -> Result<Option<String>, String>;
+    /// Store a query, which may fail, the client sends it again when it isn't found
+    fn set(&self, sha256: &str, query: &str);
+}
+
+/// Queries stored in a keyvalue bucket, shared by every instance of this component. The bucket
+/// is only opened when a persisted query is sent.
+pub struct KeyValueStore {
+    name: String,
+    bucket: OnceCell<Result<store::Bucket, String>>,
+}
+
+impl KeyValueStore {
+    pub fn new(name: &str) -> KeyValueStore {
+        KeyValueStore {
+            name: name.to_string(),
+            bucket: OnceCell::new(),
+        }
+    }
+
+    fn bucket(&self) -> Result<&store::Bucket, String> {
+        self.bucket
+            .get_or_init(|| {
+                store::open(&self.name)
+                    .map_err(|err| format!("Failed to open bucket {}: {:?}", self.name, err))
+            })
+            .as_ref()
+            .map_err(Clone::clone)
+    }
+}
+
+impl QueryStore for KeyValueStore {
+    fn get(&self, sha256: &str) -> Result<Option<String>, String> {
+        let value = self
+            .bucket()?
+            .get(&format!("{}{}", KEY_PREFIX, sha256))
+            .map_err(|err| format!("Failed to read persisted query {}: {:?}", sha256, err))?;
+        value
+            .map(|value| String::from_utf8(value).map_err(|err| err.to_string()))
+            .transpose()
+    }
+
+    fn set(&self, sha256: &str, query: &str) {
+        let stored = self.bucket().and_then(|bucket| {
+            bucket
+                .set(&format!("{}{}", KEY_PREFIX, sha256), query.as_bytes())
+                .map_err(|err| format!("{:?}", err))
+        });
+        if let Err(err) = stored {
+            log(
+                Level::Warn,
+                "",
+                &format!("Failed to store persisted query {}: {}", sha256, err),
+            );
+        }
+    }
+}
+
+/// The persisted queries of an application in the artefact format, by their hash
+pub fn from_application(application: &Value) -> HashMap<String, String> {
+    application["queries"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|query| {
+            Some((
+                query["sha256"].as_str()?.to_string(),
+                query["query"].as_str()?.to_string(),
+            ))
+        })
+        .collect()
+}
+
+/// The SHA-256 of a query as lowercase hex
+pub fn sha256(query: &str) -> String {
+    HEXLOWER.encode(&Sha256::digest(query))
+}
+
+fn error(message: &str, code: &str) -> FieldError {
+    FieldError::new(message, graphql_value!({ "code": code }))
+}
+
+/// The GraphQL request to execute for a request, which may send a persisted query by its hash.
+/// `registered` are the persisted queries of the application. The query has to pass `check`,
+/// the limits of the server, to execute, and before it is stored.
+pub fn resolve(
+    request: Request,
+    mode: Mode,
+    registered: &HashMap<String, String>,
+    store: &dyn QueryStore,
+    check: &dyn Fn(&str) -> Result<(), FieldError>,
+) -> Result<GraphQLRequest, FieldError> {
+    let hash = match request.extensions.persisted_query {
+        Some(persisted) if persisted.version != VERSION => {
+            return Err(error(
+                &format!(
+                    "Unsupported persisted query version {}, expected {}",
+                    persisted.version, VERSION
+                ),
+                "PERSISTED_QUERY_NOT_SUPPORTED",
+            ))
+        }
+        Some(persisted) => Some(persisted.sha256_hash.to_ascii_lowercase()),
+        None => None,
+    };
+    if let (Some(hash), Some(query)) = (&hash, &request.query) {
+        if *hash != sha256(query) {
+            return Err(error(
+                "provided sha does not match query",
+                "PERSISTED_QUERY_HASH_MISMATCH",
+            ));
+        }
+    }
+    let mut persist = None;
+    let query = match (mode, hash, request.query) {
+        (Mode::AllowList, hash, query) => {
+            let Some(hash) = hash.or_else(|| query.as_deref().map(sha256)) else {
+                return Err(FieldError::from("A persisted query is required"));
+            };
+            registered.get(&hash).cloned().ok_or_else(|| {
+                error(
+                    "Only persisted queries are allowed",
+                    "PERSISTED_QUERY_NOT_ALLOWED",
+                )
+            })?
+        }
+        (Mode::Automatic, Some(hash), Some(query)) => {
+            if !registered.contains_key(&hash) {
+                persist = Some(hash);
+            }
+            query
+        }
+        (Mode::Automatic, Some(hash), None) => match registered.get(&hash) {
+            Some(query) => query.clone(),
+            None => store
+                .get(&hash)
+                .map_err(|err| {
+                    log(Level::Error, "", &err);
+                    FieldError::from("Persisted queries are unavailable")
+                })?
+                .ok_or_else(|| error("PersistedQueryNotFound", "PERSISTED_QUERY_NOT_FOUND"))?,
+        },
+        (Mode::Automatic, None, Some(query)) => query,
+        (Mode::Automatic, None, None) => return Err(FieldError::from("A query is required")),
+    };
+    check(&query)?;
+    if let Some(hash) = persist {
+        store.set(&hash, &query);
+    }
+    Ok(GraphQLRequest::new(
+        query,
+        request.operation_name,
+        request.variables,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::{Extensions, PersistedQueryExtension};
+    use std::cell::RefCell;
+
+    const QUERY: &str = "{ application { name } }";
+
+    fn accept(_query: &str) -> Result<(), FieldError> {
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct MemoryStore(RefCell<HashMap<String, String>>);
+
+    impl QueryStore for MemoryStore {
+        fn get(&self, sha256: &str) -> Result<Option<String>, String> {
+            Ok(self.0.borrow().get(sha256).cloned())
+        }
+
+        fn set(&self, sha256: &str, query: &str) {
+            self.0
+                .borrow_mut()
+                .insert(sha256.to_string(), query.to_string());
+        }
+    }
+
+    fn request(query: Option<&str>, hash: Option<&str>) -> Request {
+        Request {
+            query: query.map(str::to_string),
+            operation_name: None,
+            variables: None,
+            extensions: Extensions {
+                persisted_query: hash.map(|hash| PersistedQueryExtension {
+                    version: 1,
+                    sha256_hash: hash.to_string(),
+                }),
+            },
+        }
+    }
+
+    fn code(result: Result<GraphQLRequest, FieldError>) -> Option<String> {
+        let err = result.err()?;
+        err.extensions()
+            .as_object_value()?
+            .get_field_value("code")?
+            .as_string_value()
+            .map(str::to_string)
+    }
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(
+            sha256(QUERY),
+            "0b90fb52bd5b1a85ce75d69cc2a260b55d8e5b37137ddd5f04f64cfb83f5c5ac"
+        );
+    }
+
+    #[test]
+    fn test_automatic_persisted_query() {
+        let store = MemoryStore::default();
+        let registered = HashMap::new();
+        let hash = sha256(QUERY);
+        let resolve = |request| resolve(request, Mode::Automatic, &registered, &store, &accept);
+
+        assert_eq!(
+            code(resolve(request(None, Some(&hash)))).as_deref(),
+            Some("PERSISTED_QUERY_NOT_FOUND")
+        );
+        let resolved = resolve(request(Some(QUERY), Some(&hash))).unwrap();
+        assert_eq!(resolved.query, QUERY);
+        let resolved = resolve(request(None, Some(&hash.to_uppercase()))).unwrap();
+        assert_eq!(resolved.query, QUERY);
+    }
+
+    #[test]
+    fn test_query_over_the_limits_is_not_stored() {
+        let store = MemoryStore::default();
+        let hash = sha256(QUERY);
+        let reject = |_: &str| Err(FieldError::from("Query is too complex"));
+        let result = resolve(
+            request(Some(QUERY), Some(&hash)),
+            Mode::Automatic,
+            &HashMap::new(),
+            &store,
+            &reject,
+        );
+        assert!(result.is_err());
+        assert!(store.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_hash_mismatch() {
+        let store = MemoryStore::default();
+        let result = resolve(
+            request(Some(QUERY), Some(&sha256("{ other }"))),
+            Mode::Automatic,
+            &HashMap::new(),
+            &store,
+            &accept,
+        );
+        assert_eq!(
+            code(result).as_deref(),
+            Some("PERSISTED_QUERY_HASH_MISMATCH")
+        );
+        assert!(store.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut request = request(None, Some(&sha256(QUERY)));
+        if let Some(persisted) = &mut request.extensions.persisted_query {
+            persisted.version = 2;
+        }
+        let result = resolve(
+            request,
+            Mode::Automatic,
+            &HashMap::new(),
+            &MemoryStore::default(),
+            &accept,
+        );
+        assert_eq!(
+            code(result).as_deref(),
+            Some("PERSISTED_QUERY_NOT_SUPPORTED")
+        );
+    }
+
+    #[test]
+    fn test_allow_list() {
+        let store = MemoryStore::default();
+        let hash = sha256(QUERY);
+        let registered = HashMap::from([(hash.clone(), QUERY.to_string())]);
+        let resolve = |request| resolve(request, Mode::AllowList, &registered, &store, &accept);
+
+        assert_eq!(resolve(request(None, Some(&hash))).unwrap().query, QUERY);
+        assert_eq!(resolve(request(Some(QUERY), None)).unwrap().query, QUERY);
+        let other = "{ application { uuid } }";
+        assert_eq!(
+            code(resolve(request(Some(other), Some(&sha256(other))))).as_deref(),
+            Some("PERSISTED_QUERY_NOT_ALLOWED")
+        );
+        assert_eq!(
+            code(resolve(request(Some(other), None))).as_deref(),
+            Some("PERSISTED_QUERY_NOT_ALLOWED")
+        );
+        assert!(resolve(request(None, None)).is_err());
+        assert!(store.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_from_application() {
+        let application = serde_json::json!({
+            "uuid": "693b22e983fb46afa4eb353d82ece4bb",
+            "name": "MyApp",
+            "actions": [],
+            "queries": [{ "sha256": sha256(QUERY), "query": QUERY }]
+        });
+        assert_eq!(
+            from_application(&application),
+            HashMap::from([(sha256(QUERY), QUERY.to_string())])
+        );
+        assert!(from_application(&serde_json::json!({ "actions": [] })).is_empty());
+    }
+}
@@ -0,0 +1,594 @@
+//! GraphQL requests as sent over HTTP, see the GraphQL-over-HTTP spec.
+
+use std::io::Read;
+
+use juniper::http::GraphQLBatchResponse;
+use juniper::{
+    DefaultScalarValue, Definition, InputValue, Operation, OperationType, SchemaType, Selection,
+};
+use serde_derive::Deserialize;
+
+pub const JSON: &str = "application/json";
+/// A bare GraphQL document as the body of a POST request
+pub const GRAPHQL: &str = "application/graphql";
+pub const GRAPHQL_RESPONSE_JSON: &str = "application/graphql-response+json";
+/// Responses streamed as server-sent events, see [`crate::sse`]
+pub const EVENT_STREAM: &str = "text/event-stream";
+
+/// A GraphQL request as sent over HTTP. Unlike a [`juniper::http::GraphQLRequest`] the query is
+/// optional, a persisted query is sent by its hash, see [`crate::persisted`].
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Request {
+    pub query: Option<String>,
+    pub operation_name: Option<String>,
+    pub variables: Option<InputValue>,
+    #[serde(default)]
+    pub extensions: Extensions,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Extensions {
+    pub persisted_query: Option<PersistedQueryExtension>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PersistedQueryExtension {
+    pub version: u32,
+    pub sha256_hash: String,
+}
+
+/// The GraphQL requests in the body of a POST request
+#[derive(Debug)]
+pub enum Requests {
+    Single(Request),
+    /// Executed one after the other, each has its own response
+    Batch(Vec<Request>),
+}
+
+/// Media type of the response, as negotiated with the `Accept` header of the request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResponseType {
+    /// Status codes tell whether the request was executed
+    GraphQLResponseJson,
+    /// The legacy media type, every GraphQL response is `200 OK`
+    Json,
+}
+
+impl ResponseType {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ResponseType::GraphQLResponseJson => "application/graphql-response+json; charset=utf-8",
+            ResponseType::Json => "application/json; charset=utf-8",
+        }
+    }
+
+    /// The status of a GraphQL response. A request that couldn't be parsed or validated is a bad
+    /// request, a request that was executed is `200 OK`, even when fields failed. Every response
+    /// in a batch has its own errors, so a batch is always `200 OK`.
+    pub fn status(self, response: &GraphQLBatchResponse) -> u16 {
+        match (self, response) {
+            (ResponseType::GraphQLResponseJson, GraphQLBatchResponse::Single(response))
+                if !response.is_ok() =>
+            {
+                400
+            }
+            _ => 200,
+        }
+    }
+}
+
+/// The response type for the `Accept` header of a request, `None` when neither is accepted.
+/// Requests without an `Accept` header get `application/json`, older clients don't send one.
+pub fn negotiate(accept: Option<&str>) -> Option<ResponseType> {
+    let Some(accept) = accept.filter(|accept| !accept.trim().is_empty()) else {
+        return Some(ResponseType::Json);
+    };
+    let mut best: Option<(f32, ResponseType)> = None;
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let media_type = params
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let quality = params
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .and_then(|quality| quality.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        let candidate = match media_type.as_str() {
+            GRAPHQL_RESPONSE_JSON | "application/*" | "*/*" => ResponseType::GraphQLResponseJson,
+            JSON => ResponseType::Json,
+            _ => continue,
+        };
+        if quality <= 0.0 {
+            continue;
+        }
+        // on equal quality the spec prefers application/graphql-response+json
+        let better = best.is_none_or(|(best_quality, _)| {
+            quality > best_quality
+                || (quality == best_quality && candidate == ResponseType::GraphQLResponseJson)
+        });
+        if better {
+            best = Some((quality, candidate));
+        }
+    }
+    best.map(|(_, response_type)| response_type)
+}
+
+/// Whether the `Accept` header of a request accepts a stream of server-sent events. Streaming is
+/// opted into, so `*/*` doesn't accept it.
+pub fn accepts_event_stream(accept: Option<&str>) -> bool {
+    accept.unwrap_or_default().split(',').any(|range| {
+        let mut params = range.split(';');
+        let media_type = params.next().unwrap_or_default().trim();
+        let quality = params
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .and_then(|quality| quality.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        media_type.eq_ignore_ascii_case(EVENT_STREAM) && quality > 0.0
+    })
+}
+
+/// Why the body of a POST request can't be read as a GraphQL request
+#[derive(Debug, PartialEq)]
+pub enum BodyError {
+    /// `415 Unsupported Media Type`
+    UnsupportedMediaType(String),
+    /// `400 Bad Request`
+    Invalid(String),
+    /// `413 Payload Too Large`
+    TooLarge(String),
+}
+
+/// Read the body of a request as it streams in, up to `max_size` bytes. A larger body is
+/// rejected as soon as the limit is crossed, or right away when its `Content-Length` says so.
+pub fn read_body(
+    body: impl Read,
+    content_length: Option<&str>,
+    max_size: usize,
+) -> Result<String, BodyError> {
+    let too_large =
+        || BodyError::TooLarge(format!("Request body is larger than {} bytes", max_size));
+    let length = content_length.and_then(|length| length.trim().parse::<u64>().ok());
+    if length.is_some_and(|length| length > max_size as u64) {
+        return Err(too_large());
+    }
+    // one byte over the limit tells a body of exactly `max_size` bytes apart from a larger one
+    let mut buf = Vec::with_capacity(length.unwrap_or_default() as usize);
+    body.take(max_size as u64 + 1)
+        .read_to_end(&mut buf)
+        .map_err(|err| BodyError::Invalid(format!("Failed to read the request body: {}", err)))?;
+    if buf.len() > max_size {
+        return Err(too_large());
+    }
+    String::from_utf8(buf)
+        .map_err(|err| BodyError::Invalid(format!("Request body is not valid UTF-8: {}", err)))
+}
+
+/// The GraphQL request in the body of a POST request, as JSON or a bare GraphQL document. A JSON
+/// array is a batch of at most `max_batch_size` requests.
+pub fn from_body(
+    content_type: Option<&str>,
+    body: &str,
+    max_batch_size: usize,
+) -> Result<Requests, BodyError> {
+    let content_type = content_type.unwrap_or_default();
+    let mut params = content_type.split(';');
+    let media_type = params
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let charset = params.find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches('"').to_ascii_lowercase())
+    });
+    if charset.is_some_and(|charset| charset != "utf-8") {
+        return Err(BodyError::UnsupportedMediaType(
+            "Only utf-8 encoded requests are supported".to_string(),
+        ));
+    }
+    match media_type.as_str() {
+        JSON => from_json(body, max_batch_size).map_err(BodyError::Invalid),
+        GRAPHQL => Ok(Requests::Single(Request {
+            query: Some(body.to_string()),
+            operation_name: None,
+            variables: None,
+            extensions: Extensions::default(),
+        })),
+        "" => Err(BodyError::UnsupportedMediaType(format!(
+            "A Content-Type header is required, either {} or {}",
+            JSON, GRAPHQL
+        ))),
+        other => Err(BodyError::UnsupportedMediaType(format!(
+            "Unsupported Content-Type {}, expected {} or {}",
+            other, JSON, GRAPHQL
+        ))),
+    }
+}
+
+fn from_json(body: &str, max_batch_size: usize) -> Result<Requests, String> {
+    let invalid = |err: serde_json::Error| format!("Invalid GraphQL request: {}", err);
+    match serde_json::from_str(body).map_err(invalid)? {
+        serde_json::Value::Array(requests) => {
+            if requests.is_empty() {
+                return Err("A batch needs at least one GraphQL request".to_string());
+            }
+            if requests.len() > max_batch_size {
+                return Err(format!(
+                    "A batch has at most {} GraphQL requests, not {}",
+                    max_batch_size,
+                    requests.len()
+                ));
+            }
+            requests
+                .into_iter()
+                .enumerate()
+                .map(|(index, request)| {
+                    serde_json::from_value(request).map_err(|err| {
+                        format!("Invalid GraphQL request at index {}: {}", index, err)
+                    })
+                })
+                .collect::<Result<_, _>>()
+                .map(Requests::Batch)
+        }
+        request => serde_json::from_value(request)
+            .map(Requests::Single)
+            .map_err(invalid),
+    }
+}
+
+/// A GraphQL request encoded in the query string of a GET request, `None` when it has neither a
+/// `query` nor an `extensions` parameter.
+pub fn from_query_string(query_string: &str) -> Result<Option<Request>, String> {
+    let mut query = None;
+    let mut operation_name = None;
+    let mut variables = None;
+    let mut extensions = None;
+    for (key, value) in form_urlencoded::parse(query_string.as_bytes()) {
+        match key.as_ref() {
+            "query" => query = Some(value.into_owned()),
+            "operationName" if !value.is_empty() => operation_name = Some(value.into_owned()),
+            "variables" if !value.is_empty() => {
+                let parsed: InputValue = serde_json::from_str(&value)
+                    .map_err(|err| format!("Invalid variables: {}", err))?;
+                variables = Some(parsed);
+            }
+            "extensions" if !value.is_empty() => {
+                let parsed: Extensions = serde_json::from_str(&value)
+                    .map_err(|err| format!("Invalid extensions: {}", err))?;
+                extensions = Some(parsed);
+            }
+            _ => {}
+        }
+    }
+    if query.is_none() && extensions.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(Request {
+        query,
+        operation_name,
+        variables,
+        extensions: extensions.unwrap_or_default(),
+    }))
+}
+
+/// The operation a request executes, `None` when the document doesn't select a single operation
+fn operation<'a, 'd>(
+    document: &'a [Definition<'d, DefaultScalarValue>],
+    operation_name: Option<&str>,
+) -> Option<&'a juniper::Spanning<Operation<'d, DefaultScalarValue>>> {
+    let mut operations = document.iter().filter_map(|definition| match definition {
+        Definition::Operation(operation) => Some(operation),
+        Definition::Fragment(_) => None,
+    });
+    match operation_name {
+        Some(name) => operations
+            .find(|operation| operation.item.name.as_ref().map(|name| name.item) == Some(name)),
+        None => {
+            let operation = operations.next()?;
+            if operations.next().is_some() {
+                return None;
+            }
+            Some(operation)
+        }
+    }
+}
+
+/// The type of the operation a request executes, `None` when the document can't be parsed or
+/// doesn't select a single operation. Execution reports those errors.
+pub fn operation_type(
+    query: &str,
+    operation_name: Option<&str>,
+    schema: &SchemaType<DefaultScalarValue>,
+) -> Option<OperationType> {
+    let document = juniper::parser::parse_document_source(query, schema).ok()?;
+    Some(operation(&document, operation_name)?.item.operation_type)
+}
+
+/// A subscription as a query of the [`crate::graphql::EventSchema`], which is executed for every
+/// event. A subscription selects a single action.
+pub fn subscription_as_query(
+    query: &str,
+    operation_name: Option<&str>,
+    schema: &SchemaType<DefaultScalarValue>,
+) -> Result<String, String> {
+    let document = juniper::parser::parse_document_source(query, schema)
+        .map_err(|err| format!("Invalid subscription: {}", err))?;
+    let subscription = operation(&document, operation_name)
+        .filter(|operation| operation.item.operation_type == OperationType::Subscription)
+        .ok_or_else(|| "The request doesn't select a subscription".to_string())?;
+    if !matches!(
+        subscription.item.selection_set.as_slice(),
+        [Selection::Field(field)] if !field.item.name.item.starts_with("__")
+    ) {
+        return Err("A subscription selects a single action".to_string());
+    }
+    // the position is counted in characters, the operation starts with its keyword
+    let start = query
+        .char_indices()
+        .nth(subscription.span.start.index())
+        .map(|(start, _)| start)
+        .filter(|start| query[*start..].starts_with("subscription"))
+        .ok_or_else(|| "A subscription starts with `subscription`".to_string())?;
+    Ok(format!(
+        "{}query{}",
+        &query[..start],
+        &query[start + "subscription".len()..]
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use juniper::{EmptyMutation, EmptySubscription, RootNode};
+
+    struct Query;
+
+    #[juniper::graphql_object]
+    impl Query {
+        fn ping() -> bool {
+            true
+        }
+    }
+
+    fn operation(query: &str, operation_name: Option<&str>) -> Option<OperationType> {
+        let root = RootNode::new(
+            Query,
+            EmptyMutation::<()>::new(),
+            EmptySubscription::<()>::new(),
+        );
+        operation_type(query, operation_name, &root.schema)
+    }
+
+    #[test]
+    fn test_from_query_string() {
+        let request = from_query_string(
+            "query=query%20Ping(%24id%3A%20Int)%20%7B%20ping%20%7D&operationName=Ping&variables=%7B%22id%22%3A1%7D",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            request.query.as_deref(),
+            Some("query Ping($id: Int) { ping }")
+        );
+        assert_eq!(request.operation_name.as_deref(), Some("Ping"));
+        assert_eq!(
+            serde_json::to_value(request.variables).unwrap(),
+            serde_json::json!({ "id": 1 })
+        );
+    }
+
+    #[test]
+    fn test_from_query_string_persisted_query() {
+        let request = from_query_string(
+            "extensions=%7B%22persistedQuery%22%3A%7B%22version%22%3A1%2C%22sha256Hash%22%3A%22abc%22%7D%7D",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(request.query, None);
+        let persisted = request.extensions.persisted_query.unwrap();
+        assert_eq!(persisted.version, 1);
+        assert_eq!(persisted.sha256_hash, "abc");
+    }
+
+    #[test]
+    fn test_from_query_string_without_query() {
+        assert!(from_query_string("").unwrap().is_none());
+        assert!(from_query_string("operationName=Ping").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_from_query_string_invalid_variables() {
+        assert!(from_query_string("query=%7Bping%7D&variables=%7B").is_err());
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(None), Some(ResponseType::Json));
+        assert_eq!(
+            negotiate(Some(
+                "application/graphql-response+json, application/json;q=0.9"
+            )),
+            Some(ResponseType::GraphQLResponseJson)
+        );
+        assert_eq!(
+            negotiate(Some(
+                "application/graphql-response+json;q=0.5, application/json"
+            )),
+            Some(ResponseType::Json)
+        );
+        assert_eq!(
+            negotiate(Some("*/*")),
+            Some(ResponseType::GraphQLResponseJson)
+        );
+        assert_eq!(
+            negotiate(Some(
+                "application/json, application/graphql-response+json;q=0"
+            )),
+            Some(ResponseType::Json)
+        );
+        assert_eq!(negotiate(Some("text/html")), None);
+    }
+
+    #[test]
+    fn test_accepts_event_stream() {
+        assert!(accepts_event_stream(Some("text/event-stream")));
+        assert!(accepts_event_stream(Some(
+            "application/json, Text/Event-Stream;q=0.5"
+        )));
+        assert!(!accepts_event_stream(Some("text/event-stream;q=0")));
+        assert!(!accepts_event_stream(Some("*/*")));
+        assert!(!accepts_event_stream(None));
+        assert_eq!(negotiate(Some("text/event-stream")), None);
+    }
+
+    #[test]
+    fn test_from_json_body() {
+        let request = from_body(
+            Some("application/json; charset=UTF-8"),
+            r#"{"query": "{ ping }", "operationName": null}"#,
+            10,
+        );
+        assert!(
+            matches!(request, Ok(Requests::Single(request)) if request.query.as_deref() == Some("{ ping }"))
+        );
+        assert!(matches!(
+            from_body(Some("application/json"), "{ ping }", 10),
+            Err(BodyError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_from_batch_body() {
+        let body = r#"[{"query": "{ ping }"}, {"query": "mutation { ping }"}]"#;
+        match from_body(Some("application/json"), body, 2) {
+            Ok(Requests::Batch(requests)) => {
+                assert_eq!(requests[1].query.as_deref(), Some("mutation { ping }"))
+            }
+            _ => panic!("expected a batch"),
+        }
+        assert_eq!(
+            from_body(Some("application/json"), body, 1).err(),
+            Some(BodyError::Invalid(
+                "A batch has at most 1 GraphQL requests, not 2".to_string()
+            ))
+        );
+        assert!(from_body(Some("application/json"), "[]", 2).is_err());
+        assert_eq!(
+            from_body(
+                Some("application/json"),
+                r#"[{"query": "{ ping }"}, {"query": 1}]"#,
+                2
+            )
+            .err(),
+            Some(BodyError::Invalid(
+                "Invalid GraphQL request at index 1: invalid type: integer `1`, expected a string"
+                    .to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_from_graphql_body() {
+        match from_body(Some("application/graphql"), "{ ping }", 10) {
+            Ok(Requests::Single(request)) => {
+                assert_eq!(request.query.as_deref(), Some("{ ping }"));
+                assert_eq!(request.operation_name, None);
+            }
+            _ => panic!("expected a single request"),
+        }
+    }
+
+    #[test]
+    fn test_from_body_unsupported_media_type() {
+        for content_type in [
+            None,
+            Some("text/plain"),
+            Some("application/json; charset=latin1"),
+        ] {
+            assert!(matches!(
+                from_body(content_type, "{}", 10),
+                Err(BodyError::UnsupportedMediaType(_))
+            ));
+        }
+    }
+
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("connection reset"))
+        }
+    }
+
+    #[test]
+    fn test_read_body() {
+        assert_eq!(
+            read_body("{ ping }".as_bytes(), Some("8"), 8),
+            Ok("{ ping }".to_string())
+        );
+        assert!(matches!(
+            read_body("{ ping }".as_bytes(), None, 7),
+            Err(BodyError::TooLarge(_))
+        ));
+        // the Content-Length is checked before reading
+        assert!(matches!(
+            read_body(FailingReader, Some("9"), 8),
+            Err(BodyError::TooLarge(_))
+        ));
+        assert!(matches!(
+            read_body(FailingReader, None, 8),
+            Err(BodyError::Invalid(_))
+        ));
+        assert!(matches!(
+            read_body(&[0xff, 0xfe][..], None, 8),
+            Err(BodyError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_operation_type() {
+        assert_eq!(operation("{ ping }", None), Some(OperationType::Query));
+        assert_eq!(
+            operation("mutation { ping }", None),
+            Some(OperationType::Mutation)
+        );
+        let document = "query A { ping } mutation B { ping }";
+        assert_eq!(
+            operation(document, Some("B")),
+            Some(OperationType::Mutation)
+        );
+        assert_eq!(operation(document, None), None);
+        assert_eq!(operation("{ ping", None), None);
+    }
+
+    #[test]
+    fn test_subscription_as_query() {
+        let root = RootNode::new(
+            Query,
+            EmptyMutation::<()>::new(),
+            EmptySubscription::<()>::new(),
+        );
+        let rewrite =
+            |query, operation_name| subscription_as_query(query, operation_name, &root.schema);
+        assert_eq!(
+            rewrite("subscription { ping }", None).as_deref(),
+            Ok("query { ping }")
+        );
+        // positions are counted in characters
+        let document = "# é\nquery A { ping }\nsubscription B($a: Int) { ping }";
+        assert_eq!(
+            rewrite(document, Some("B")).as_deref(),
+            Ok("# é\nquery A { ping }\nquery B($a: Int) { ping }")
+        );
+        assert!(rewrite(document, Some("A")).is_err());
+        assert!(rewrite("subscription { ping ping }", None).is_err());
+        assert!(rewrite("subscription { __typename }", None).is_err());
+    }
+}
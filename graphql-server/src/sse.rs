@@ -0,0 +1,65 @@
+//! Responses streamed as server-sent events, as in the GraphQL-over-SSE protocol. Every GraphQL
+//! response is a `next` event, written as soon as it is executed, and a `complete` event ends the
+//! stream.
+
+use std::io::{self, Write};
+
+use wasmcloud_component::wasi::http::types::OutgoingBody as WasiOutgoingBody;
+use wasmcloud_component::wasi::io::streams::OutputStream;
+
+use crate::http;
+
+/// The body of a response
+pub enum Body {
+    Full(String),
+    /// The data of the `next` events of a stream
+    Events(Box<dyn Iterator<Item = String>>),
+}
+
+/// An event as sent on the stream, data spanning lines has a `data` field per line
+pub fn event(name: &str, data: &str) -> String {
+    let mut event = format!("event: {}\n", name);
+    for line in data.split('\n') {
+        if line.is_empty() {
+            event.push_str("data:\n");
+        } else {
+            event.push_str(&format!("data: {}\n", line));
+        }
+    }
+    event.push('\n');
+    event
+}
+
+impl http::OutgoingBody for Body {
+    fn write(self, body: WasiOutgoingBody, mut stream: OutputStream) -> io::Result<()> {
+        match self {
+            Body::Full(text) => stream.write_all(text.as_bytes())?,
+            Body::Events(events) => {
+                for data in events {
+                    stream.write_all(event("next", &data).as_bytes())?;
+                    // the client gets every event as it happens, not when the stream ends
+                    Write::flush(&mut stream)?;
+                }
+                stream.write_all(event("complete", "").as_bytes())?;
+            }
+        }
+        Write::flush(&mut stream)?;
+        drop(stream);
+        WasiOutgoingBody::finish(body, None).map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event() {
+        assert_eq!(
+            event("next", r#"{"data":{"ping":true}}"#),
+            "event: next\ndata: {\"data\":{\"ping\":true}}\n\n"
+        );
+        assert_eq!(event("complete", ""), "event: complete\ndata:\n\n");
+        assert_eq!(event("next", "a\nb"), "event: next\ndata: a\ndata: b\n\n");
+    }
+}